@@ -3,7 +3,7 @@ import { Contract } from '@ethersproject/contracts';
 import { AbiCoder, FormatTypes, FunctionFragment, Fragment, Interface, ParamType } from '@ethersproject/abi';
 import { keccak256 } from '@ethersproject/keccak256';
 import { getContractAddress } from '@ethersproject/address';
-import { parse } from '../parser/pkg/parser';
+import { Session, decode_multicall } from '../parser/pkg/parser';
 
 interface Opts {
   network?: string,
@@ -20,6 +20,8 @@ const sleuthDeployer = process.env['SLEUTH_DEPLOYER'] ?? '0x84C3e20985d9E7aEc46F
 
 interface Query<T, A extends any[] = []> {
   bytecode: string,
+  // Init code which runs the query and returns its result, with no Sleuth contract needed.
+  initcode?: string,
   callargs?: string,
   fn: FunctionFragment
 }
@@ -85,6 +87,7 @@ export class Sleuth {
   version: number;
   sleuthAddr: string;
   sources: Source[];
+  session: Session;
   coder: AbiCoder;
 
   constructor(provider: Provider, opts: Opts = {}) {
@@ -93,51 +96,29 @@ export class Sleuth {
     this.version = opts.version ?? defaultOpts.version;
     this.sleuthAddr = opts.contractAddress ?? getContractAddress({ from: sleuthDeployer, nonce: this.version - 1 });
     this.sources = [];
+    this.session = new Session();
     this.coder = new AbiCoder();
   }
 
-  query<T>(q: string): Query<T, []> {
-    let registrations = this.sources.map((source) => {
-      let iface = JSON.stringify(source.iface.format(FormatTypes.full));
-      return `REGISTER CONTRACT ${source.name} AT ${source.address} WITH INTERFACE ${iface};`
-    }).join("\n");
-    let fullQuery = `${registrations}${q}`;
-    console.log("Full Query", fullQuery);
-    let [tuple, yul] = parse(fullQuery).split(';', 2);
-    console.log("Tuple", tuple, "Yul", yul);
-    const input = {
-      language: 'Yul',
-      sources: {
-        'query.yul': {
-          content: yul
-        }
-      },
-      settings: {
-        outputSelection: {
-          '*': {
-            '*': ['*']
-          }
-        }
-      }
-    };
-
-    let result = solcCompile(input);
-    console.log(result.contracts['query.yul']);
-    if (result.errors && result.errors.length > 0) {
-      throw new Error("Compilation Error: " + JSON.stringify(result.errors));
+  query<T, A extends any[] = []>(q: string, name: string = 'query'): Query<T, A> {
+    console.log("Query", q);
+    let { queries, bytecode, initcode, estimate } = JSON.parse(this.session.compile(q));
+    for (let warning of estimate.warnings) {
+      console.warn(warning);
     }
-    
-    let bytecode = result?.contracts['query.yul']?.Query?.evm?.bytecode?.object;
-
-    if (!bytecode) {
-      throw new Error(`Missing bytecode from compilation result: ${JSON.stringify(result)}`);
+    let compiled = queries.find((query: { name: string }) => query.name === name);
+    if (!compiled) {
+      throw new Error(`Query does not define \`${name}\``);
     }
+    let { tuple, signature } = compiled;
+    console.log("Tuple", tuple, "Bytecode", bytecode);
 
     return {
       bytecode: bytecode,
+      initcode: name === 'query' ? initcode : undefined,
       fn: FunctionFragment.from({
-        name: 'query',
-        inputs: [],
+        name,
+        inputs: FunctionFragment.from(signature).inputs,
         outputs: ParamType.from(tuple).components,
         stateMutability: 'pure',
         type: 'function'
@@ -226,6 +207,9 @@ export class Sleuth {
       iface = new Interface(iface);
     }
     this.sources.push({name, address, iface});
+    // Sources are parsed once here, rather than with every query.
+    let abi = JSON.stringify(iface.format(FormatTypes.full));
+    this.session.register(`REGISTER CONTRACT ${name} AT ${address} WITH INTERFACE ${abi};`);
   }
 
   async fetch<T, A extends any[] = []>(q: Query<T, A>, args?: A): Promise<T> {
@@ -245,8 +229,38 @@ export class Sleuth {
     }
   }
 
+  // Runs a query as an `eth_call` creating its init code, which works on chains where the
+  // Sleuth contract isn't deployed.
+  async fetchCounterfactual<T>(q: Query<T, []>): Promise<T> {
+    if (!q.initcode) {
+      throw new Error(`Query has no init code to run`);
+    }
+    let queryResult = await this.provider.call({ data: q.initcode });
+    let r = this.coder.decode(q.fn.outputs ?? [], queryResult) as unknown;
+    if (Array.isArray(r) && r.length === 1) {
+      return r[0] as T;
+    } else {
+      return r as T;
+    }
+  }
+
   async fetchSql<T>(q: string): Promise<T> {
     let query = this.query<T>(q);
     return this.fetch<T, []>(query, []);
   }
+
+  // Runs a query through Multicall3 rather than the Sleuth contract. Only queries of
+  // contract calls, literals, block numbers and balances can be run this way.
+  async fetchMulticall<T>(q: string): Promise<T> {
+    let { tuple } = JSON.parse(this.session.compile(q));
+    let multicall = this.session.compile_multicall(q);
+    let { to, calldata } = JSON.parse(multicall);
+    let returnData = await this.provider.call({ to, data: calldata });
+    let r = this.coder.decode(ParamType.from(tuple).components, decode_multicall(multicall, returnData)) as unknown;
+    if (Array.isArray(r) && r.length === 1) {
+      return r[0] as T;
+    } else {
+      return r as T;
+    }
+  }
 }
@@ -0,0 +1,95 @@
+//! Times compiling generated queries with many columns and registered contracts, in the
+//! spirit of "all markets, all accounts" queries. Run with `cargo bench`.
+
+use parser::compile::{check, compile};
+use parser::session::Session;
+use std::time::{Duration, Instant};
+
+const CONTRACTS: usize = 200;
+// Ten columns per contract, two of which share a call.
+const COLUMNS: [&str; 10] = [
+    "c{}.symbol",
+    "TRY(c{}.symbol)",
+    "COALESCE(c{}.decimals, 18)",
+    "c{}.totalSupply GAS 50000",
+    "BALANCE({})",
+    "CODESIZE({})",
+    "CODEHASH({})",
+    "IS_CONTRACT({})",
+    "5",
+    "\"cat\"",
+];
+
+fn registrations() -> String {
+    (0..CONTRACTS)
+        .map(|i| format!("REGISTER CONTRACT c{} AT {} AS ERC20;\n", i, address(i)))
+        .collect()
+}
+
+fn address(i: usize) -> String {
+    format!("0x{:040x}", i + 1)
+}
+
+// One `SELECT` of ten columns for each of the first `contracts` contracts.
+fn selects(contracts: usize) -> String {
+    (0..contracts)
+        .map(|i| {
+            let columns: Vec<String> = COLUMNS
+                .iter()
+                .map(|column| {
+                    if column.contains("c{}") {
+                        column.replace("{}", &i.to_string())
+                    } else {
+                        column.replace("{}", &address(i))
+                    }
+                })
+                .collect();
+            format!("SELECT {} FROM c{};\n", columns.join(", "), i)
+        })
+        .collect()
+}
+
+fn bench<T>(name: &str, iterations: u32, mut f: impl FnMut() -> T) {
+    f();
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let each: Duration = start.elapsed() / iterations;
+    println!("{:<40} {:>12.3?}", name, each);
+}
+
+fn main() {
+    let registrations = registrations();
+    // Deployed code is limited to 64KB, which 2,000 columns of calls don't fit in.
+    let compiled_selects = selects(40);
+    let selects = selects(CONTRACTS);
+    let query = format!("{}{}", registrations, selects);
+    let compiled_query = format!("{}{}", registrations, compiled_selects);
+    let one = format!("{}SELECT c7.totalSupply FROM c7", registrations);
+    let mut session = Session::new();
+    session.register(&registrations).unwrap();
+
+    bench("register 200 contracts", 10, || {
+        Session::new().register(&registrations).unwrap()
+    });
+    bench("check 1 column, 200 contracts", 10, || check(&one).unwrap());
+    bench("check 2,000 columns, 200 contracts", 10, || {
+        check(&query).unwrap()
+    });
+    bench("compile 400 columns, 200 contracts", 3, || {
+        compile(&compiled_query).unwrap()
+    });
+    bench("session: check 1 column", 100, || {
+        session.check("SELECT c7.totalSupply FROM c7").unwrap()
+    });
+    bench("session: check 2,000 columns", 10, || {
+        session.check(&selects).unwrap()
+    });
+    bench("session: compile 1 column", 10, || {
+        session.compile("SELECT c7.totalSupply FROM c7").unwrap()
+    });
+    bench("session: compile 400 columns", 3, || {
+        session.compile(&compiled_selects).unwrap()
+    });
+}
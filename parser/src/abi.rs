@@ -1,8 +1,10 @@
 use crate::resolve::Resolution;
-use ethers::abi::param_type::ParamType;
+use ethers::abi::param_type::{ParamType, Reader};
 use ethers::abi::struct_def::FieldType;
+use ethers::abi::{self, Token};
+use ethers::types::{Address, U256};
 
-fn param_type(p: &ParamType) -> String {
+pub fn param_type(p: &ParamType) -> String {
     match p {
         ParamType::Address => String::from("address"),
         ParamType::Bytes => String::from("bytes"),
@@ -10,9 +12,9 @@ fn param_type(p: &ParamType) -> String {
         ParamType::Uint(sz) => format!("uint{}", sz),
         ParamType::Bool => String::from("bool"),
         ParamType::String => String::from("string"),
-        ParamType::Array(pp) => format!("{}[]", param_type(&*pp)),
+        ParamType::Array(pp) => format!("{}[]", param_type(pp)),
         ParamType::FixedBytes(sz) => format!("bytes{}", sz),
-        ParamType::FixedArray(pp, sz) => format!("{}[{}]", param_type(&*pp), sz),
+        ParamType::FixedArray(pp, sz) => format!("{}[{}]", param_type(pp), sz),
         ParamType::Tuple(els) => {
             let inner = els
                 .iter()
@@ -24,14 +26,89 @@ fn param_type(p: &ParamType) -> String {
     }
 }
 
-fn field_type(ty: &FieldType) -> String {
+pub fn field_type(ty: &FieldType) -> String {
+    param_type(field_param(ty))
+}
+
+/// Reads a type written by `param_type`, e.g. `tuple(uint256,string)`.
+pub fn parse_type(ty: &str) -> Result<ParamType, String> {
+    Reader::read(&ty.replace("tuple(", "(")).map_err(|e| format!("Invalid type {}: {}", ty, e))
+}
+
+/// Human-readable signature of an error, e.g. `error Unauthorized(address caller)`.
+pub fn error_signature(error: &abi::ethabi::AbiError) -> String {
+    let inputs = error
+        .inputs
+        .iter()
+        .map(|input| {
+            if input.name.is_empty() {
+                param_type(&input.kind)
+            } else {
+                format!("{} {}", param_type(&input.kind), input.name)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+    format!("error {}({})", error.name, inputs)
+}
+
+pub fn field_param(ty: &FieldType) -> &ParamType {
     match ty {
-        FieldType::Elementary(p) => param_type(p),
+        FieldType::Elementary(p) => p,
         _ => unreachable!(),
     }
 }
 
-pub fn get_tuple_abi(resolutions: &Vec<Resolution>) -> String {
+/// Size in bytes of a value in the head of an ABI encoding: static values are
+/// inlined, dynamic values take a single word holding an offset into the tail.
+pub fn head_size(p: &ParamType) -> usize {
+    if p.is_dynamic() {
+        return 32;
+    }
+    match p {
+        ParamType::Tuple(els) => els.iter().map(head_size).sum(),
+        ParamType::FixedArray(pp, sz) => head_size(pp) * sz,
+        _ => 32,
+    }
+}
+
+/// Smallest valid encoding of `p` as returned by a call, e.g. the heads of
+/// each return value for a function's output tuple.
+pub fn min_encoded_size(p: &ParamType) -> usize {
+    match p {
+        ParamType::Tuple(els) => els.iter().map(head_size).sum(),
+        _ => head_size(p),
+    }
+}
+
+pub fn default_token(p: &ParamType) -> Token {
+    match p {
+        ParamType::Address => Token::Address(Address::zero()),
+        ParamType::Bytes => Token::Bytes(vec![]),
+        ParamType::Int(_) => Token::Int(U256::zero()),
+        ParamType::Uint(_) => Token::Uint(U256::zero()),
+        ParamType::Bool => Token::Bool(false),
+        ParamType::String => Token::String(String::new()),
+        ParamType::Array(_) => Token::Array(vec![]),
+        ParamType::FixedBytes(sz) => Token::FixedBytes(vec![0; *sz]),
+        ParamType::FixedArray(pp, sz) => Token::FixedArray(vec![default_token(pp); *sz]),
+        ParamType::Tuple(els) => Token::Tuple(els.iter().map(default_token).collect()),
+    }
+}
+
+/// Encodes `token` the way it's laid out at its position in a larger encoding:
+/// inline for static values, or the tail its offset points to for dynamic ones.
+/// For a function's output tuple this matches the call's returndata.
+pub fn encode_value(token: &Token) -> Vec<u8> {
+    let encoded = abi::encode(std::slice::from_ref(token));
+    if token.is_dynamic() {
+        encoded[32..].to_vec()
+    } else {
+        encoded
+    }
+}
+
+pub fn get_tuple_abi(resolutions: &[Resolution]) -> String {
     let fields = resolutions
         .iter()
         .map(|r: &Resolution| {
@@ -51,8 +128,12 @@ pub fn get_tuple_abi(resolutions: &Vec<Resolution>) -> String {
 
 #[cfg(test)]
 mod tests {
-    use crate::abi::{get_tuple_abi, Resolution};
-    use crate::source::DataSource;
+    use crate::abi::{
+        default_token, encode_value, error_signature, get_tuple_abi, head_size, min_encoded_size,
+        Resolution,
+    };
+    use ethers::abi::Token;
+    use crate::source::{Account, DataSource};
     use ethers::abi::param_type::ParamType;
     use ethers::abi::struct_def::FieldType;
 
@@ -61,24 +142,98 @@ mod tests {
         let resolutions = vec![
             Resolution {
                 name: Some(String::from("name")),
+                source: None,
                 abi: FieldType::Elementary(ParamType::String),
                 data_source: DataSource::String(String::from("Hello")),
+                gas: None,
+                span: None,
             },
             Resolution {
                 name: Some(String::from("age")),
+                source: None,
                 abi: FieldType::Elementary(ParamType::Uint(256)),
                 data_source: DataSource::Number(22),
+                gas: None,
+                span: None,
             },
             Resolution {
                 name: None,
+                source: None,
                 abi: FieldType::Elementary(ParamType::Uint(256)),
                 data_source: DataSource::BlockNumber,
+                gas: None,
+                span: None,
+            },
+            Resolution {
+                name: None,
+                source: None,
+                abi: FieldType::Elementary(ParamType::FixedBytes(32)),
+                data_source: DataSource::CodeHash(Account::Address(ethers::types::H160::zero())),
+                gas: None,
+                span: None,
+            },
+            Resolution {
+                name: None,
+                source: None,
+                abi: FieldType::Elementary(ParamType::Bool),
+                data_source: DataSource::IsContract(Account::Address(ethers::types::H160::zero())),
+                gas: None,
+                span: None,
             },
         ];
 
         assert_eq!(
             get_tuple_abi(&resolutions),
-            String::from("tuple(string name,uint256 age,uint256)")
+            String::from("tuple(string name,uint256 age,uint256,bytes32,bool)")
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        let uint_tuple = ParamType::Tuple(vec![ParamType::Uint(256), ParamType::Bool]);
+        let string_tuple = ParamType::Tuple(vec![ParamType::Uint(256), ParamType::String]);
+        assert_eq!(head_size(&ParamType::Uint(8)), 32);
+        assert_eq!(head_size(&uint_tuple), 64);
+        assert_eq!(head_size(&string_tuple), 32);
+        assert_eq!(
+            head_size(&ParamType::FixedArray(Box::new(uint_tuple.clone()), 3)),
+            192
+        );
+        assert_eq!(min_encoded_size(&uint_tuple), 64);
+        assert_eq!(min_encoded_size(&string_tuple), 64);
+    }
+
+    #[test]
+    fn encode_default_values() {
+        let string_tuple = ParamType::Tuple(vec![ParamType::String]);
+        assert_eq!(
+            default_token(&string_tuple),
+            Token::Tuple(vec![Token::String(String::new())])
+        );
+        let mut expected = vec![0u8; 64];
+        expected[31] = 0x20;
+        assert_eq!(encode_value(&default_token(&string_tuple)), expected);
+        assert_eq!(
+            encode_value(&default_token(&ParamType::Tuple(vec![ParamType::Uint(256)]))),
+            vec![0u8; 32]
+        );
+    }
+
+    #[test]
+    fn error_signatures() {
+        let contract = ethers::abi::parse_abi(&[
+            "error Unauthorized(address caller, uint256)",
+            "error Paused()",
+        ])
+        .unwrap();
+        let mut signatures: Vec<String> = contract.errors().map(error_signature).collect();
+        signatures.sort();
+        assert_eq!(
+            signatures,
+            vec![
+                String::from("error Paused()"),
+                String::from("error Unauthorized(address caller, uint256)")
+            ]
         );
     }
 }
@@ -0,0 +1,96 @@
+use parser::compile::{check, compile};
+use parser::explain::explain;
+use parser::format::format_query;
+use std::fs;
+use std::process;
+
+const USAGE: &str = "Usage: sleuth <command> <file>...
+
+Commands:
+  compile        Prints the Yul, ABI and metadata of each query as JSON
+  check          Checks that each query parses and resolves
+  fmt [--check]  Prints each query formatted, or with --check, lists the files which aren't
+  explain        Describes each query's functions, columns and costs";
+
+enum Command {
+    Compile,
+    Check,
+    Fmt { check: bool },
+    Explain,
+}
+
+fn parse_args(args: &[String]) -> Result<(Command, Vec<&str>), String> {
+    let (command, rest) = args.split_first().ok_or_else(|| String::from(USAGE))?;
+    let check = rest.iter().any(|arg| arg == "--check");
+    let command = match command.as_str() {
+        "compile" => Command::Compile,
+        "check" => Command::Check,
+        "fmt" => Command::Fmt { check },
+        "explain" => Command::Explain,
+        _ => return Err(format!("Unknown command {}\n\n{}", command, USAGE)),
+    };
+    let files: Vec<&str> = rest
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| !(matches!(command, Command::Fmt { .. }) && *arg == "--check"))
+        .collect();
+    if let Some(flag) = files.iter().find(|arg| arg.starts_with("--")) {
+        return Err(format!("Unknown option {}\n\n{}", flag, USAGE));
+    }
+    if files.is_empty() {
+        return Err(String::from(USAGE));
+    }
+    Ok((command, files))
+}
+
+// Runs the command on one query, returning what to print, if anything.
+fn run(command: &Command, query: &str) -> Result<Option<String>, String> {
+    match command {
+        Command::Compile => {
+            let compiled = compile(query)?;
+            let json = serde_json::to_string_pretty(&compiled)
+                .map_err(|e| format!("Error serializing compiled query: {}", e))?;
+            Ok(Some(json))
+        }
+        Command::Check => check(query).map(|_| None),
+        Command::Fmt { check: false } => format_query(query).map(Some),
+        Command::Fmt { check: true } => {
+            if format_query(query)? == query {
+                Ok(None)
+            } else {
+                Err(String::from("not formatted"))
+            }
+        }
+        Command::Explain => compile(query).map(|compiled| Some(explain(&compiled))),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (command, files) = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    let mut failed = 0;
+    for file in &files {
+        let result = fs::read_to_string(file)
+            .map_err(|e| format!("Error reading file: {}", e))
+            .and_then(|query| run(&command, &query));
+        match result {
+            Ok(Some(output)) if files.len() > 1 => println!("// {}\n{}", file, output),
+            Ok(Some(output)) => println!("{}", output.trim_end()),
+            Ok(None) => (),
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        eprintln!("{} of {} file(s) failed", failed, files.len());
+        process::exit(1);
+    }
+}
@@ -0,0 +1,463 @@
+use crate::abi::{error_signature, field_type, get_tuple_abi};
+use crate::estimate::{estimate, Estimate};
+use crate::evm::assemble;
+use crate::ir::{lower, Plan};
+use crate::multicall::{derive_multicall, Multicall};
+use crate::parse::parse_query_cls;
+use crate::passes::PassManager;
+use crate::query::Query;
+use crate::resolve::{resolve_entries, resolve_with_sources, settings, Resolution};
+use crate::source::{get_all_sources, SourceIndex};
+use crate::yul::{
+    derive_dispatcher_object, derive_initcode_object, QUERY_SELECTOR, RUNTIME_OBJECT,
+};
+use ethers::utils::{hex, id};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Column {
+    pub name: Option<String>,
+    pub source: Option<String>,
+    #[serde(rename = "type")]
+    pub ty: String,
+    /// The `SELECT` item the column came from, as written in the query.
+    pub text: Option<String>,
+    /// Where `text` appears in the query, as a range of byte offsets.
+    pub span: Option<(usize, usize)>,
+}
+
+impl Column {
+    /// How the column was selected, e.g. ``column 3 (`comet.getAssetInfo(7)`)``, for use in
+    /// messages.
+    pub fn label(&self, index: usize) -> String {
+        if let Some(text) = &self.text {
+            return format!("column {} (`{}`)", index, text);
+        }
+        match (&self.source, &self.name) {
+            (Some(source), Some(name)) => format!("{}.{}", source, name),
+            (None, Some(name)) => name.clone(),
+            _ => format!("column {}", index),
+        }
+    }
+}
+
+/// Runtime bytecode generated for one `SELECT` item: `pc` is a range of program counters in
+/// the deployed query and `span` a range of the query text, both with exclusive ends.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SourceMapping {
+    pub pc: (usize, usize),
+    pub span: (usize, usize),
+}
+
+/// A function of the compiled contract, which runs one query.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompiledQuery {
+    pub name: String,
+    /// E.g. `account(address)`, with one `address` per parameter.
+    pub signature: String,
+    /// Names of the query's parameters, each of which is an `address`.
+    pub params: Vec<String>,
+    /// The function's selector, as `0x`-prefixed hex.
+    pub selector: String,
+    pub tuple: String,
+    pub columns: Vec<Column>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Compiled {
+    /// The result of `query()`, which runs the query's plain `SELECT`s. Empty when the query
+    /// only defines named queries.
+    pub tuple: String,
+    pub yul: String,
+    /// Init code deploying the query, as `0x`-prefixed hex, which is ready to send.
+    pub bytecode: String,
+    /// Init code which runs `query()` itself and returns its result, for chains where the
+    /// Sleuth contract isn't deployed: send it as an `eth_call` with no `to` address.
+    pub initcode: Option<String>,
+    pub strict: bool,
+    /// True when every call is made with `staticcall`, so running the query cannot modify
+    /// state.
+    pub read_only: bool,
+    pub columns: Vec<Column>,
+    /// Every function the contract has, `query()` included.
+    pub queries: Vec<CompiledQuery>,
+    pub errors: Vec<String>,
+    /// Where the code of the deployed query came from, for errors raised while it runs.
+    pub source_map: Vec<SourceMapping>,
+    pub estimate: Estimate,
+}
+
+fn columns(query: &str, resolutions: &[Resolution]) -> Vec<Column> {
+    resolutions
+        .iter()
+        .map(|r| Column {
+            name: r.name.clone(),
+            source: r.source.clone(),
+            ty: field_type(&r.abi),
+            text: r
+                .span
+                .map(|span| String::from(&query[span.start..span.end])),
+            span: r.span.map(|span| (span.start, span.end)),
+        })
+        .collect()
+}
+
+pub fn compile(query: &str) -> Result<Compiled, String> {
+    let query_set = parse_query_cls(query)?;
+    let all_sources = get_all_sources(&query_set)?;
+    compile_with_sources(query, &query_set, &SourceIndex::new(&all_sources))
+}
+
+/// Compiles a parsed query, resolving it against `all_sources` rather than only the
+/// contracts it registers itself.
+pub fn compile_with_sources(
+    query: &str,
+    query_set: &[Query],
+    all_sources: &SourceIndex,
+) -> Result<Compiled, String> {
+    let settings = settings(query_set);
+    let entries = resolve_entries(query_set, all_sources)?;
+
+    let read_only = entries
+        .iter()
+        .flat_map(|e| e.resolutions.iter())
+        .all(|r| r.data_source.is_read_only());
+    let mut errors: Vec<String> = vec![];
+    for error in all_sources.iter().flat_map(|s| s.errors().iter()) {
+        let signature = error_signature(error);
+        if !errors.contains(&signature) {
+            errors.push(signature);
+        }
+    }
+    let mut queries = vec![];
+    let mut plans = vec![];
+    for entry in entries {
+        let signature = entry.signature();
+        let selector = id(&signature);
+        queries.push(CompiledQuery {
+            name: entry.name,
+            signature,
+            params: entry.params,
+            selector: format!("0x{}", hex::encode(selector)),
+            tuple: get_tuple_abi(&entry.resolutions),
+            columns: columns(query, &entry.resolutions),
+        });
+        let plan = PassManager::default().run(lower(entry.resolutions, &settings)?)?;
+        plans.push((u32::from_be_bytes(selector), plan));
+    }
+    let dispatch: Vec<(u32, &Plan)> = plans.iter().map(|(s, plan)| (*s, plan)).collect();
+    let object = derive_dispatcher_object(&dispatch)?;
+    let assembly = assemble(&object)?;
+    let plans: Vec<&Plan> = dispatch.iter().map(|(_, plan)| *plan).collect();
+    let estimate = estimate(&plans, &object, &assembly)?;
+    let initcode = match dispatch
+        .iter()
+        .find(|(selector, _)| *selector == QUERY_SELECTOR)
+    {
+        Some((_, plan)) => {
+            let initcode = assemble(&derive_initcode_object(plan)?)?;
+            Some(format!("0x{}", hex::encode(&initcode.bytecode)))
+        }
+        None => None,
+    };
+    let bytecode = format!("0x{}", hex::encode(&assembly.bytecode));
+    let source_map = assembly
+        .sources
+        .into_iter()
+        .filter(|source| source.object == RUNTIME_OBJECT)
+        .map(|source| SourceMapping {
+            pc: (source.pc.start, source.pc.end),
+            span: (source.span.start, source.span.end),
+        })
+        .collect();
+    let (tuple, columns) = match queries.iter().find(|q| q.signature == "query()") {
+        Some(q) => (q.tuple.clone(), q.columns.clone()),
+        None => (String::new(), vec![]),
+    };
+
+    Ok(Compiled {
+        tuple,
+        yul: object.to_string(),
+        bytecode,
+        initcode,
+        strict: settings.strict,
+        read_only,
+        columns,
+        queries,
+        errors,
+        source_map,
+        estimate,
+    })
+}
+
+/// Checks that a query parses and that everything it selects resolves, without compiling it.
+pub fn check(query: &str) -> Result<(), String> {
+    let query_set = parse_query_cls(query)?;
+    let all_sources = get_all_sources(&query_set)?;
+    resolve_entries(&query_set, &SourceIndex::new(&all_sources))?;
+    Ok(())
+}
+
+/// Compiles a query's plain `SELECT`s to run through Multicall3 rather than be deployed.
+pub fn compile_multicall(query: &str) -> Result<Multicall, String> {
+    let query_set = parse_query_cls(query)?;
+    let all_sources = get_all_sources(&query_set)?;
+    compile_multicall_with_sources(&query_set, &SourceIndex::new(&all_sources))
+}
+
+/// As `compile_multicall`, resolving the parsed query against `all_sources`.
+pub fn compile_multicall_with_sources(
+    query_set: &[Query],
+    all_sources: &SourceIndex,
+) -> Result<Multicall, String> {
+    let settings = settings(query_set);
+    let resolutions = resolve_with_sources(query_set, all_sources)?;
+    let plan = PassManager::default().run(lower(resolutions, &settings)?)?;
+    derive_multicall(&plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::{check, compile, Column, CompiledQuery};
+
+    #[test]
+    fn compile_strict_query() {
+        let query = r###"
+            SET STRICT;
+            REGISTER CONTRACT comet AT 0xc3d688B66703497DAA19211EEdff47f25384cdc3 WITH INTERFACE [
+                "function totalSupply() view returns (uint256)",
+                "function accrue()",
+                "error Paused()",
+                "error Unauthorized(address caller)"
+            ];
+            SELECT comet.totalSupply, 5 FROM comet;
+            "###;
+        let compiled = compile(query).unwrap();
+        let start = query.find("comet.totalSupply").unwrap();
+        assert_eq!(compiled.tuple, "tuple(tuple(uint256) totalSupply,uint256)");
+        assert!(compiled.strict);
+        assert!(compiled.read_only);
+        assert!(compiled.yul.contains("revertCallFailed(0)"));
+        assert_eq!(compiled.estimate.calls, 1);
+        assert!(compiled.estimate.min_gas < compiled.estimate.max_gas.unwrap());
+        assert_eq!(
+            compiled.columns,
+            vec![
+                Column {
+                    name: Some(String::from("totalSupply")),
+                    source: Some(String::from("comet")),
+                    ty: String::from("tuple(uint256)"),
+                    text: Some(String::from("comet.totalSupply")),
+                    span: Some((start, start + 17)),
+                },
+                Column {
+                    name: None,
+                    source: None,
+                    ty: String::from("uint256"),
+                    text: Some(String::from("5")),
+                    span: Some((start + 19, start + 20)),
+                },
+            ]
+        );
+        assert_eq!(
+            compiled.errors,
+            vec![
+                String::from("error Paused()"),
+                String::from("error Unauthorized(address caller)")
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_mutating_query() {
+        let query = r###"
+            REGISTER CONTRACT comet AT 0xc3d688B66703497DAA19211EEdff47f25384cdc3 WITH INTERFACE [
+                "function totalSupply() view returns (uint256)",
+                "function accrue() returns (uint256)"
+            ];
+            SELECT comet.totalSupply, TRY(comet.accrue) FROM comet;
+            "###;
+        assert!(compile(query).is_err());
+
+        let compiled = compile(&format!("SET ALLOW_MUTATIONS;{}", query)).unwrap();
+        assert!(!compiled.read_only);
+        assert!(compiled
+            .yul
+            .contains("ok := tryCall(gas(), 0xc3d688b66703497daa19211eedff47f25384cdc3"));
+        assert!(compiled
+            .yul
+            .contains("ok := tryMutatingCall(gas(), 0xc3d688b66703497daa19211eedff47f25384cdc3"));
+    }
+
+    #[test]
+    fn compile_shares_repeated_calls() {
+        let compiled = compile(
+            r###"
+            REGISTER CONTRACT comet AT 0xc3d688B66703497DAA19211EEdff47f25384cdc3 WITH INTERFACE [
+                "function totalSupply() view returns (uint256)",
+                "function totalBorrow() view returns (uint256)"
+            ];
+            SELECT comet.totalSupply, comet.totalBorrow, comet.totalSupply, TRY(comet.totalBorrow) FROM comet;
+            "###,
+        )
+        .unwrap();
+        assert_eq!(compiled.yul.matches("ok := tryCall(").count(), 2);
+        assert!(compiled
+            .yul
+            .contains("mstore(0xa0, or(shl(128, returndatasize()), res))"));
+
+        let estimate = &compiled.estimate;
+        assert_eq!(estimate.calls, 2);
+        assert_eq!(estimate.init_size, compiled.bytecode.len() / 2 - 1);
+        assert!(estimate.runtime_size < estimate.init_size);
+        // Two cache entries and a head of five words, with the calldata for each call past it.
+        assert_eq!(estimate.memory, 0x80 + 0x40 + 0xa0 + 0x20);
+        // Sharing copies the cached result in a loop.
+        assert!(estimate.min_gas > 0);
+        assert_eq!(estimate.max_gas, None);
+        assert!(estimate.warnings.is_empty());
+    }
+
+    #[test]
+    fn column_labels() {
+        let column = Column {
+            name: Some(String::from("totalSupply")),
+            source: Some(String::from("comet")),
+            ty: String::from("uint256"),
+            text: None,
+            span: None,
+        };
+        assert_eq!(column.label(0), "comet.totalSupply");
+        let column = Column {
+            name: None,
+            source: None,
+            ty: String::from("uint256"),
+            text: None,
+            span: None,
+        };
+        assert_eq!(column.label(3), "column 3");
+        let column = Column {
+            name: None,
+            source: Some(String::from("comet")),
+            ty: String::from("tuple(uint8,address)"),
+            text: Some(String::from("comet.getAssetInfo(7)")),
+            span: Some((7, 28)),
+        };
+        assert_eq!(column.label(3), "column 3 (`comet.getAssetInfo(7)`)");
+    }
+
+    #[test]
+    fn compile_source_map() {
+        let query = r###"
+            REGISTER CONTRACT comet AT 0xc3d688B66703497DAA19211EEdff47f25384cdc3 WITH INTERFACE [
+                "function totalSupply() view returns (uint256)",
+                "function name() view returns (string)"
+            ];
+            SELECT comet.totalSupply GAS 50000, comet.name, "cat" FROM comet;
+            "###;
+        let compiled = compile(query).unwrap();
+        let spans: Vec<(usize, usize)> = compiled.columns.iter().map(|c| c.span.unwrap()).collect();
+        let texts: Vec<&str> = spans
+            .iter()
+            .map(|(start, end)| &query[*start..*end])
+            .collect();
+        assert_eq!(
+            texts,
+            vec!["comet.totalSupply GAS 50000", "comet.name", "\"cat\""]
+        );
+        for (start, end) in &spans {
+            assert!(compiled
+                .yul
+                .contains(&format!("/// @src 0:{}:{}", start, end)));
+        }
+        assert!(compiled.yul.contains("/// @src -1:-1:-1"));
+
+        // Each column's code is one range of the runtime code, in order.
+        let mapped: Vec<(usize, usize)> = compiled.source_map.iter().map(|m| m.span).collect();
+        assert_eq!(mapped, spans);
+        for pair in compiled.source_map.windows(2) {
+            assert!(pair[0].pc.0 < pair[0].pc.1 && pair[0].pc.1 <= pair[1].pc.0);
+        }
+    }
+
+    #[test]
+    fn compile_named_queries() {
+        let query = r###"
+            SELECT block.number FROM block;
+            QUERY balances(:owner, :spender) AS SELECT BALANCE(:owner), BALANCE(:spender);
+            QUERY code(:account) AS SELECT :account, CODEHASH(:account)
+            "###;
+        let compiled = compile(query).unwrap();
+        assert_eq!(compiled.tuple, "tuple(uint256 number)");
+        let start = query.find(":account, CODEHASH").unwrap();
+        assert_eq!(
+            compiled.queries[2],
+            CompiledQuery {
+                name: String::from("code"),
+                signature: String::from("code(address)"),
+                params: vec![String::from("account")],
+                selector: String::from("0x5b90b752"),
+                tuple: String::from("tuple(address account,bytes32)"),
+                columns: vec![
+                    Column {
+                        name: Some(String::from("account")),
+                        source: None,
+                        ty: String::from("address"),
+                        text: Some(String::from(":account")),
+                        span: Some((start, start + 8)),
+                    },
+                    Column {
+                        name: None,
+                        source: None,
+                        ty: String::from("bytes32"),
+                        text: Some(String::from("CODEHASH(:account)")),
+                        span: Some((start + 10, start + 28)),
+                    },
+                ],
+            }
+        );
+        let signatures: Vec<(&str, &str)> = compiled
+            .queries
+            .iter()
+            .map(|q| (q.signature.as_str(), q.selector.as_str()))
+            .collect();
+        assert_eq!(
+            signatures,
+            vec![
+                ("query()", "0x2c46b205"),
+                ("balances(address,address)", "0xc23f001f"),
+                ("code(address)", "0x5b90b752"),
+            ]
+        );
+        assert!(compiled
+            .yul
+            .contains("balance(and(calldataload(0x24), sub(shl(160, 1), 1)))"));
+        assert_eq!(compiled.estimate.calls, 0);
+
+        let only_named = compile("QUERY code(:a) AS SELECT CODESIZE(:a)").unwrap();
+        assert_eq!(only_named.tuple, "");
+        assert!(only_named.columns.is_empty());
+        assert_eq!(only_named.queries.len(), 1);
+
+        assert_eq!(
+            compile("QUERY a AS SELECT 1; QUERY a AS SELECT 2").map(|_| ()),
+            Err(String::from("Query a is defined more than once"))
+        );
+        assert_eq!(
+            compile("QUERY a(:x) AS SELECT BALANCE(:y)").map(|_| ()),
+            Err(String::from("Unknown parameter :y"))
+        );
+    }
+
+    #[test]
+    fn check_queries() {
+        assert_eq!(check("SELECT block.number FROM block"), Ok(()));
+        assert_eq!(
+            check("SELECT comet.totalSupply FROM comet"),
+            Err(String::from(
+                "No such relation \"comet\" referenced in FROM clause"
+            ))
+        );
+        assert!(check("SELECT").is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use crate::abi::{default_token, encode_value, head_size, min_encoded_size};
+use crate::evm::{static_gas, Assembly};
+use crate::ir::{Expr, Failure, Plan, Value, ValueId};
+use crate::yul::{result_offset, RUNTIME_OBJECT};
+use crate::yul_ast::Object;
+use ethers::abi::ParamType;
+use serde::{Deserialize, Serialize};
+
+/// EIP-170's limit on the size of deployed code.
+pub const MAX_CODE_SIZE: usize = 0x6000;
+
+/// EIP-3860's limit on the size of init code.
+pub const MAX_INIT_CODE_SIZE: usize = 2 * MAX_CODE_SIZE;
+
+/// What running a compiled query should cost, as far as can be told without running it.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Estimate {
+    /// Size of the init code which deploys the query, in bytes.
+    pub init_size: usize,
+    /// Size of the deployed query, in bytes.
+    pub runtime_size: usize,
+    /// External calls the query makes when none of them fail.
+    pub calls: usize,
+    /// The most memory the query uses, in bytes, with each dynamic result at its smallest.
+    pub memory: usize,
+    /// Gas a successful run spends, besides what its calls spend: each call is an unknown
+    /// on top of this, as is copying results larger than their smallest encoding.
+    pub min_gas: u64,
+    /// As `min_gas`, but with every account cold. `None` when the query loops, e.g. to
+    /// decode a `SAFE_STRING`.
+    pub max_gas: Option<u64>,
+    pub warnings: Vec<String>,
+}
+
+// Bytes rounded up to whole words.
+fn padded(len: usize) -> usize {
+    len.div_ceil(32) * 32
+}
+
+/// The gas it costs to expand memory to `size` bytes.
+pub fn memory_gas(size: usize) -> u64 {
+    let words = (size as u64).div_ceil(32);
+    3 * words + words * words / 512
+}
+
+// The smallest tail a call's dynamic result can take: its returndata, or what's written on
+// failure.
+fn min_result(param: &ParamType, failure: &Failure) -> usize {
+    let returned = min_encoded_size(param);
+    match failure {
+        Failure::Revert => returned,
+        Failure::Zero => returned.min(encode_value(&default_token(param)).len()),
+        Failure::Default(token) => returned.min(encode_value(token).len()),
+    }
+}
+
+/// The most memory a plan's query uses, following the layout `yul::derive_yul_plan` gives it:
+/// the cache entries of shared calls from 0x80, the result's head after them, then each
+/// dynamic value appended to the tail, with calldata written past the end of the tail before
+/// each call.
+pub fn plan_memory(plan: &Plan) -> usize {
+    let head: usize = plan.columns.iter().map(|c| head_size(&c.ty)).sum();
+    let mut free = result_offset(plan) + head;
+    let mut memory = free;
+    let mut made = vec![];
+    for column in &plan.columns {
+        for value in column.expr.values() {
+            if let Value::Call(call) | Value::SafeString(call) = &plan.values[value] {
+                if !made.contains(&value) {
+                    memory = memory.max(free + padded(call.calldata.len()));
+                    made.push(value);
+                }
+            }
+        }
+        let returns = |value: ValueId| match &plan.values[value] {
+            Value::Call(call) if call.returns.is_dynamic() => Some(&call.returns),
+            _ => None,
+        };
+        free += match &column.expr {
+            Expr::Const(token) if token.is_dynamic() => encode_value(token).len(),
+            // An empty string, on failure.
+            Expr::Value(value) if matches!(plan.values[*value], Value::SafeString(_)) => 32,
+            // The status and offset of the value, then the value.
+            Expr::Try(value) => match returns(*value) {
+                Some(returns) => 0x40 + min_result(returns, &Failure::Zero),
+                None => 0,
+            },
+            expr => match expr.call_result() {
+                Some((value, failure)) => returns(value)
+                    .map(|returns| min_result(returns, &failure))
+                    .unwrap_or_default(),
+                None => 0,
+            },
+        };
+        memory = memory.max(free);
+    }
+    memory
+}
+
+/// Warnings for code too large to deploy.
+pub fn size_warnings(init_size: usize, runtime_size: usize) -> Vec<String> {
+    let mut warnings = vec![];
+    if runtime_size > MAX_CODE_SIZE {
+        warnings.push(format!(
+            "Query is {} bytes, over the EIP-170 limit of {} bytes for deployed code, so creating it will fail",
+            runtime_size, MAX_CODE_SIZE
+        ));
+    }
+    if init_size > MAX_INIT_CODE_SIZE {
+        warnings.push(format!(
+            "Query init code is {} bytes, over the EIP-3860 limit of {} bytes, so creating it will fail",
+            init_size, MAX_INIT_CODE_SIZE
+        ));
+    }
+    warnings
+}
+
+/// Estimates the cost of a query from the plans of its functions, the object generated for
+/// them, and that object's assembly. Calls are counted across every function, memory is for
+/// whichever function needs the most, and gas bounds cover running any of them.
+pub fn estimate(plans: &[&Plan], object: &Object, assembly: &Assembly) -> Result<Estimate, String> {
+    let runtime = object
+        .objects
+        .iter()
+        .find(|o| o.name == RUNTIME_OBJECT)
+        .ok_or_else(|| format!("Missing {} object", RUNTIME_OBJECT))?;
+    let runtime_size = assembly
+        .objects
+        .iter()
+        .find(|(name, _)| name == RUNTIME_OBJECT)
+        .map(|(_, size)| *size)
+        .unwrap_or_default();
+    let init_size = assembly.bytecode.len();
+
+    let calls = plans
+        .iter()
+        .map(|plan| {
+            plan.values
+                .iter()
+                .zip(plan.uses())
+                .filter(|(value, uses)| {
+                    *uses > 0 && matches!(value, Value::Call(_) | Value::SafeString(_))
+                })
+                .count()
+        })
+        .sum();
+    let memory = plans
+        .iter()
+        .map(|plan| plan_memory(plan))
+        .max()
+        .unwrap_or(0x80);
+    let gas = static_gas(runtime)?.ok_or_else(|| String::from("Query always reverts"))?;
+    let memory_gas = memory_gas(memory);
+
+    Ok(Estimate {
+        init_size,
+        runtime_size,
+        calls,
+        memory,
+        min_gas: gas.min + memory_gas,
+        max_gas: gas.max.map(|max| max + memory_gas),
+        warnings: size_warnings(init_size, runtime_size),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::estimate::{memory_gas, plan_memory, size_warnings};
+    use crate::ir::{Call, Column, Expr, Plan, Value};
+    use ethers::abi::{ParamType, StateMutability, Token};
+    use ethers::types::{Bytes, H160};
+
+    fn column(ty: ParamType, expr: Expr) -> Column {
+        Column {
+            name: None,
+            source: None,
+            ty,
+            expr,
+            span: None,
+        }
+    }
+
+    fn name_call() -> Value {
+        Value::Call(Call {
+            address: H160::repeat_byte(0x11),
+            calldata: Bytes::from([0x06, 0xfd, 0xde, 0x03]),
+            returns: ParamType::Tuple(vec![ParamType::String]),
+            mutability: StateMutability::View,
+            gas: None,
+        })
+    }
+
+    #[test]
+    fn memory_high_water_mark() {
+        let plan = Plan {
+            values: vec![name_call(), Value::BlockNumber],
+            columns: vec![
+                column(
+                    ParamType::String,
+                    Expr::Const(Token::String(String::from("cat"))),
+                ),
+                column(ParamType::Tuple(vec![ParamType::String]), Expr::Result(0)),
+                column(ParamType::Uint(256), Expr::Value(1)),
+            ],
+            gas_budget: None,
+        };
+        // Three words of head, the string's length and contents, then the call's calldata,
+        // which its result overwrites with at least a word.
+        assert_eq!(plan_memory(&plan), 0x80 + 0x60 + 0x40 + 0x20);
+
+        let plan = Plan {
+            columns: vec![column(
+                ParamType::Tuple(vec![ParamType::String]),
+                Expr::if_ok(
+                    0,
+                    Expr::Const(Token::Tuple(vec![Token::String(String::from(
+                        "a string longer than a single word",
+                    ))])),
+                ),
+            )],
+            values: vec![name_call()],
+            gas_budget: None,
+        };
+        // The default is larger than the smallest result the call could return.
+        assert_eq!(plan_memory(&plan), 0x80 + 0x20 + 0x20);
+    }
+
+    #[test]
+    fn memory_gas_is_quadratic() {
+        assert_eq!(memory_gas(0), 0);
+        assert_eq!(memory_gas(1), 3);
+        assert_eq!(memory_gas(0x400), 98);
+    }
+
+    #[test]
+    fn warns_about_size_limits() {
+        assert!(size_warnings(0x6000, 0x5fff).is_empty());
+        assert_eq!(
+            size_warnings(0xc001, 0x6001),
+            vec![
+                String::from("Query is 24577 bytes, over the EIP-170 limit of 24576 bytes for deployed code, so creating it will fail"),
+                String::from("Query init code is 49153 bytes, over the EIP-3860 limit of 49152 bytes, so creating it will fail"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1380 @@
+use crate::query::Span;
+use crate::yul_ast::{Block, Expression, Function, Ident, Literal, Object, Statement};
+use ethers::types::U256;
+use std::collections::HashMap;
+use std::ops::Range;
+
+const STOP: u8 = 0x00;
+const EQ: u8 = 0x14;
+const ISZERO: u8 = 0x15;
+const CODECOPY: u8 = 0x39;
+const POP: u8 = 0x50;
+const JUMP: u8 = 0x56;
+const JUMPI: u8 = 0x57;
+const JUMPDEST: u8 = 0x5b;
+const PUSH1: u8 = 0x60;
+const PUSH2: u8 = 0x61;
+const DUP1: u8 = 0x80;
+const SWAP1: u8 = 0x90;
+
+/// EVM builtins which compile to a single opcode: its name, opcode, arguments and results.
+#[rustfmt::skip]
+const OPCODES: &[(&str, u8, usize, usize)] = &[
+    ("stop", 0x00, 0, 0), ("add", 0x01, 2, 1), ("mul", 0x02, 2, 1), ("sub", 0x03, 2, 1),
+    ("div", 0x04, 2, 1), ("sdiv", 0x05, 2, 1), ("mod", 0x06, 2, 1), ("smod", 0x07, 2, 1),
+    ("addmod", 0x08, 3, 1), ("mulmod", 0x09, 3, 1), ("exp", 0x0a, 2, 1),
+    ("signextend", 0x0b, 2, 1), ("lt", 0x10, 2, 1), ("gt", 0x11, 2, 1), ("slt", 0x12, 2, 1),
+    ("sgt", 0x13, 2, 1), ("eq", 0x14, 2, 1), ("iszero", 0x15, 1, 1), ("and", 0x16, 2, 1),
+    ("or", 0x17, 2, 1), ("xor", 0x18, 2, 1), ("not", 0x19, 1, 1), ("byte", 0x1a, 2, 1),
+    ("shl", 0x1b, 2, 1), ("shr", 0x1c, 2, 1), ("sar", 0x1d, 2, 1), ("keccak256", 0x20, 2, 1),
+    ("address", 0x30, 0, 1), ("balance", 0x31, 1, 1), ("origin", 0x32, 0, 1),
+    ("caller", 0x33, 0, 1), ("callvalue", 0x34, 0, 1), ("calldataload", 0x35, 1, 1),
+    ("calldatasize", 0x36, 0, 1), ("calldatacopy", 0x37, 3, 0), ("codesize", 0x38, 0, 1),
+    ("codecopy", 0x39, 3, 0), ("gasprice", 0x3a, 0, 1), ("extcodesize", 0x3b, 1, 1),
+    ("extcodecopy", 0x3c, 4, 0), ("returndatasize", 0x3d, 0, 1),
+    ("returndatacopy", 0x3e, 3, 0), ("extcodehash", 0x3f, 1, 1), ("blockhash", 0x40, 1, 1),
+    ("coinbase", 0x41, 0, 1), ("timestamp", 0x42, 0, 1), ("number", 0x43, 0, 1),
+    ("difficulty", 0x44, 0, 1), ("prevrandao", 0x44, 0, 1), ("gaslimit", 0x45, 0, 1),
+    ("chainid", 0x46, 0, 1), ("selfbalance", 0x47, 0, 1), ("basefee", 0x48, 0, 1),
+    ("blobhash", 0x49, 1, 1), ("blobbasefee", 0x4a, 0, 1), ("pop", 0x50, 1, 0),
+    ("mload", 0x51, 1, 1), ("mstore", 0x52, 2, 0), ("mstore8", 0x53, 2, 0),
+    ("sload", 0x54, 1, 1), ("sstore", 0x55, 2, 0), ("pc", 0x58, 0, 1), ("msize", 0x59, 0, 1),
+    ("gas", 0x5a, 0, 1), ("tload", 0x5c, 1, 1), ("tstore", 0x5d, 2, 0), ("mcopy", 0x5e, 3, 0),
+    ("log0", 0xa0, 2, 0), ("log1", 0xa1, 3, 0), ("log2", 0xa2, 4, 0), ("log3", 0xa3, 5, 0),
+    ("log4", 0xa4, 6, 0), ("create", 0xf0, 3, 1), ("call", 0xf1, 7, 1),
+    ("callcode", 0xf2, 7, 1), ("return", 0xf3, 2, 0), ("delegatecall", 0xf4, 6, 1),
+    ("create2", 0xf5, 4, 1), ("staticcall", 0xfa, 6, 1), ("revert", 0xfd, 2, 0),
+    ("invalid", 0xfe, 0, 0), ("selfdestruct", 0xff, 1, 0),
+];
+
+/// The deepest stack slot `DUP` and `SWAP` can reach.
+const MAX_DEPTH: usize = 16;
+
+type Label = usize;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Asm {
+    Op(u8),
+    Push(U256),
+    PushLabel(Label),
+    /// The offset of a sub-object, which is only known once this object's code is laid out.
+    PushOffset(String),
+    Label(Label),
+    /// Where the following code came from in the query, which takes up no space.
+    Source(Option<Span>),
+}
+
+impl Asm {
+    fn size(&self) -> usize {
+        match self {
+            Asm::Source(_) => 0,
+            Asm::Op(_) | Asm::Label(_) => 1,
+            Asm::Push(value) => 1 + push_bytes(value).len(),
+            Asm::PushLabel(_) | Asm::PushOffset(_) => 3,
+        }
+    }
+}
+
+fn push_bytes(value: &U256) -> Vec<u8> {
+    let mut word = [0; 32];
+    value.to_big_endian(&mut word);
+    let start = word.iter().position(|b| *b != 0).unwrap_or(31);
+    word[start..].to_vec()
+}
+
+// What the code generator knows about a stack slot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Slot {
+    Var(usize),
+    Temp,
+    ReturnAddress,
+}
+
+#[derive(Clone, Copy)]
+struct FunctionInfo {
+    label: Label,
+    params: usize,
+    returns: usize,
+}
+
+struct Loop {
+    post: Label,
+    end: Label,
+    height: usize,
+}
+
+// A function body which still has to be generated, with the functions visible to it.
+struct Pending<'a> {
+    function: &'a Function,
+    label: Label,
+    functions: Vec<HashMap<String, FunctionInfo>>,
+}
+
+// Generates the code of one object. The stack is modelled as the code is generated, so
+// variables are found by their depth, and every path leaves a block with the same layout.
+struct Codegen<'a> {
+    asm: Vec<Asm>,
+    stack: Vec<Slot>,
+    vars: Vec<HashMap<String, usize>>,
+    functions: Vec<HashMap<String, FunctionInfo>>,
+    loops: Vec<Loop>,
+    // The current function's exit label, and the stack height `leave` unwinds to.
+    exit: Option<(Label, usize)>,
+    pending: Vec<Pending<'a>>,
+    // The entry labels of the functions generated so far.
+    entries: Vec<Label>,
+    sizes: &'a HashMap<String, usize>,
+    next_var: usize,
+    next_label: Label,
+}
+
+impl<'a> Codegen<'a> {
+    fn label(&mut self) -> Label {
+        self.next_label += 1;
+        self.next_label - 1
+    }
+
+    fn op(&mut self, opcode: u8, args: usize, returns: usize) {
+        self.asm.push(Asm::Op(opcode));
+        self.stack.truncate(self.stack.len() - args);
+        self.stack.extend((0..returns).map(|_| Slot::Temp));
+    }
+
+    fn push(&mut self, value: U256) {
+        self.asm.push(Asm::Push(value));
+        self.stack.push(Slot::Temp);
+    }
+
+    fn push_label(&mut self, label: Label) {
+        self.asm.push(Asm::PushLabel(label));
+        self.stack.push(Slot::Temp);
+    }
+
+    // Jumps to `label`, first dropping whatever is above `height`. The stack model is left
+    // alone, since the code after the jump can only be reached by another path.
+    fn jump_out(&mut self, label: Label, height: usize) {
+        for _ in height..self.stack.len() {
+            self.asm.push(Asm::Op(POP));
+        }
+        self.asm.push(Asm::PushLabel(label));
+        self.asm.push(Asm::Op(JUMP));
+    }
+
+    fn depth(&self, slot: Slot, name: &str) -> Result<usize, String> {
+        let position = self
+            .stack
+            .iter()
+            .rposition(|s| *s == slot)
+            .ok_or_else(|| format!("Variable {} is not on the stack", name))?;
+        let depth = self.stack.len() - 1 - position;
+        if depth >= MAX_DEPTH {
+            return Err(format!(
+                "Stack too deep: {} is {} slots deep, but only {} can be reached",
+                name,
+                depth + 1,
+                MAX_DEPTH
+            ));
+        }
+        Ok(depth)
+    }
+
+    fn swap(&mut self, depth: usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("Stack too deep: cannot swap {} slots deep", depth));
+        }
+        self.asm.push(Asm::Op(SWAP1 + depth as u8 - 1));
+        let top = self.stack.len() - 1;
+        self.stack.swap(top, top - depth);
+        Ok(())
+    }
+
+    fn pop(&mut self) {
+        self.op(POP, 1, 0);
+    }
+
+    fn lookup(&self, name: &Ident) -> Result<usize, String> {
+        self.vars
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name.as_str()).copied())
+            .ok_or_else(|| format!("Undefined variable {}", name))
+    }
+
+    fn function(&self, name: &Ident) -> Option<FunctionInfo> {
+        self.functions
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name.as_str()).copied())
+    }
+
+    fn declare(&mut self, name: &Ident) -> Slot {
+        let id = self.next_var;
+        self.next_var += 1;
+        self.vars
+            .last_mut()
+            .unwrap()
+            .insert(String::from(name.as_str()), id);
+        Slot::Var(id)
+    }
+
+    fn literal(literal: &Literal) -> U256 {
+        match literal {
+            Literal::Decimal(n) | Literal::Hex(n) => *n,
+            Literal::Bool(b) => U256::from(*b as u8),
+            Literal::String(bytes) => U256::from_big_endian(&pad_right(bytes)),
+        }
+    }
+
+    fn data_name(args: &[Expression]) -> Result<String, String> {
+        match args {
+            [Expression::Literal(Literal::String(name))] => {
+                Ok(String::from_utf8_lossy(name).into_owned())
+            }
+            _ => Err(String::from("Expected the name of an object")),
+        }
+    }
+
+    // Pushes the values of an expression, returning how many there are.
+    fn expression(&mut self, expression: &Expression) -> Result<usize, String> {
+        match expression {
+            Expression::Literal(literal) => {
+                self.push(Codegen::literal(literal));
+                Ok(1)
+            }
+            Expression::Identifier(name) => {
+                let depth = self.depth(Slot::Var(self.lookup(name)?), name.as_str())?;
+                self.op(DUP1 + depth as u8, 0, 1);
+                Ok(1)
+            }
+            Expression::Call(name, args) => self.call(name, args),
+        }
+    }
+
+    // Pushes each argument, last first, so the first ends up on top.
+    fn arguments(&mut self, name: &Ident, args: &[Expression]) -> Result<(), String> {
+        for arg in args.iter().rev() {
+            if self.expression(arg)? != 1 {
+                return Err(format!("Each argument to {} must be a single value", name));
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, name: &Ident, args: &[Expression]) -> Result<usize, String> {
+        if let Some(function) = self.function(name) {
+            if args.len() != function.params {
+                return Err(format!(
+                    "{} takes {} arguments, got {}",
+                    name,
+                    function.params,
+                    args.len()
+                ));
+            }
+            let back = self.label();
+            self.push_label(back);
+            self.arguments(name, args)?;
+            self.push_label(function.label);
+            self.op(JUMP, function.params + 2, function.returns);
+            self.asm.push(Asm::Label(back));
+            return Ok(function.returns);
+        }
+        match name.as_str() {
+            "datasize" => {
+                let data = Codegen::data_name(args)?;
+                let size = self
+                    .sizes
+                    .get(&data)
+                    .ok_or_else(|| format!("Unknown object {}", data))?;
+                self.push((*size).into());
+                return Ok(1);
+            }
+            "dataoffset" => {
+                let data = Codegen::data_name(args)?;
+                if !self.sizes.contains_key(&data) {
+                    return Err(format!("Unknown object {}", data));
+                }
+                self.asm.push(Asm::PushOffset(data));
+                self.stack.push(Slot::Temp);
+                return Ok(1);
+            }
+            "datacopy" => {
+                self.arguments(name, args)?;
+                self.op(CODECOPY, 3, 0);
+                return Ok(0);
+            }
+            "memoryguard" => {
+                self.arguments(name, args)?;
+                return Ok(1);
+            }
+            _ => {}
+        }
+        let (_, opcode, params, returns) = OPCODES
+            .iter()
+            .find(|(op, ..)| *op == name.as_str())
+            .ok_or_else(|| format!("Unknown function {}", name))?;
+        if args.len() != *params {
+            return Err(format!(
+                "{} takes {} arguments, got {}",
+                name,
+                params,
+                args.len()
+            ));
+        }
+        self.arguments(name, args)?;
+        self.op(*opcode, *params, *returns);
+        Ok(*returns)
+    }
+
+    // Opens a scope in which the block's functions are visible, and queues their bodies.
+    fn open_scope(&mut self, statements: &'a [Statement]) {
+        self.vars.push(HashMap::new());
+        let mut functions = HashMap::new();
+        let mut bodies = vec![];
+        for statement in statements {
+            if let Statement::Function(function) = statement {
+                let label = self.label();
+                functions.insert(
+                    String::from(function.name.as_str()),
+                    FunctionInfo {
+                        label,
+                        params: function.params.len(),
+                        returns: function.returns.len(),
+                    },
+                );
+                bodies.push((function, label));
+            }
+        }
+        self.functions.push(functions);
+        for (function, label) in bodies {
+            self.pending.push(Pending {
+                function,
+                label,
+                functions: self.functions.clone(),
+            });
+        }
+    }
+
+    // Closes a scope, dropping the variables declared in it.
+    fn close_scope(&mut self, height: usize) {
+        while self.stack.len() > height {
+            self.pop();
+        }
+        self.vars.pop();
+        self.functions.pop();
+    }
+
+    fn statements(&mut self, statements: &'a [Statement]) -> Result<(), String> {
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, block: &'a Block) -> Result<(), String> {
+        let height = self.stack.len();
+        self.open_scope(&block.0);
+        self.statements(&block.0)?;
+        self.close_scope(height);
+        Ok(())
+    }
+
+    fn assign(&mut self, names: &[Ident]) -> Result<(), String> {
+        // The first value is on top.
+        for name in names {
+            let depth = self.depth(Slot::Var(self.lookup(name)?), name.as_str())?;
+            // Only the values move: the slot keeps its variable and the old value is popped.
+            self.asm.push(Asm::Op(SWAP1 + depth as u8 - 1));
+            self.pop();
+        }
+        Ok(())
+    }
+
+    fn values(&mut self, expression: &Expression, count: usize) -> Result<(), String> {
+        let values = self.expression(expression)?;
+        if values != count {
+            return Err(format!(
+                "Expected {} values from {}, got {}",
+                count, expression, values
+            ));
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &'a Statement) -> Result<(), String> {
+        match statement {
+            Statement::Block(block) => self.block(block)?,
+            Statement::Source(span) => self.asm.push(Asm::Source(*span)),
+            // Generated after the code which can call it.
+            Statement::Function(_) => {}
+            Statement::Let(names, value) => {
+                match value {
+                    Some(value) => self.values(value, names.len())?,
+                    None => names.iter().for_each(|_| self.push(U256::zero())),
+                }
+                let top = self.stack.len();
+                for (i, name) in names.iter().enumerate() {
+                    self.stack[top - 1 - i] = self.declare(name);
+                }
+            }
+            Statement::Assign(names, value) => {
+                self.values(value, names.len())?;
+                self.assign(names)?;
+            }
+            Statement::If(condition, body) => {
+                let end = self.label();
+                self.values(condition, 1)?;
+                self.op(ISZERO, 1, 1);
+                self.push_label(end);
+                self.op(JUMPI, 2, 0);
+                self.block(body)?;
+                self.asm.push(Asm::Label(end));
+            }
+            Statement::Switch(value, cases, default) => {
+                self.values(value, 1)?;
+                let stack = self.stack.clone();
+                let labels: Vec<Label> = cases.iter().map(|_| self.label()).collect();
+                let end = self.label();
+                for (case, label) in cases.iter().zip(&labels) {
+                    self.op(DUP1, 0, 1);
+                    self.push(Codegen::literal(&case.value));
+                    self.op(EQ, 2, 1);
+                    self.push_label(*label);
+                    self.op(JUMPI, 2, 0);
+                }
+                self.pop();
+                if let Some(default) = default {
+                    self.block(default)?;
+                }
+                self.jump_out(end, self.stack.len());
+                for (case, label) in cases.iter().zip(labels) {
+                    self.stack = stack.clone();
+                    self.asm.push(Asm::Label(label));
+                    self.pop();
+                    self.block(&case.body)?;
+                    self.jump_out(end, self.stack.len());
+                }
+                self.asm.push(Asm::Label(end));
+            }
+            Statement::For(init, condition, post, body) => {
+                let height = self.stack.len();
+                let (start, next, end) = (self.label(), self.label(), self.label());
+                self.open_scope(&init.0);
+                self.statements(&init.0)?;
+                self.asm.push(Asm::Label(start));
+                self.values(condition, 1)?;
+                self.op(ISZERO, 1, 1);
+                self.push_label(end);
+                self.op(JUMPI, 2, 0);
+                self.loops.push(Loop {
+                    post: next,
+                    end,
+                    height: self.stack.len(),
+                });
+                self.block(body)?;
+                self.loops.pop();
+                self.asm.push(Asm::Label(next));
+                self.block(post)?;
+                self.jump_out(start, self.stack.len());
+                self.asm.push(Asm::Label(end));
+                self.close_scope(height);
+            }
+            Statement::Break | Statement::Continue => {
+                let (label, height) = match (statement, self.loops.last()) {
+                    (Statement::Break, Some(l)) => (l.end, l.height),
+                    (_, Some(l)) => (l.post, l.height),
+                    (_, None) => return Err(String::from("break or continue outside a loop")),
+                };
+                self.jump_out(label, height);
+            }
+            Statement::Leave => {
+                let (exit, height) = self
+                    .exit
+                    .ok_or_else(|| String::from("leave outside a function"))?;
+                self.jump_out(exit, height);
+            }
+            Statement::Expression(expression) => self.values(expression, 0)?,
+        }
+        Ok(())
+    }
+
+    // Generates a function which is entered with its return address below its arguments,
+    // the first on top, and leaves its return values in the same order.
+    fn function_body(&mut self, pending: Pending<'a>) -> Result<(), String> {
+        let function = pending.function;
+        self.functions = pending.functions;
+        self.vars = vec![HashMap::new()];
+        self.stack = vec![Slot::ReturnAddress];
+        for param in function.params.iter().rev() {
+            let slot = self.declare(param);
+            self.stack.push(slot);
+        }
+        self.entries.push(pending.label);
+        self.asm.push(Asm::Source(None));
+        self.asm.push(Asm::Label(pending.label));
+        let mut returns = vec![];
+        for name in &function.returns {
+            self.push(U256::zero());
+            let slot = self.declare(name);
+            *self.stack.last_mut().unwrap() = slot;
+            returns.push(slot);
+        }
+        let exit = self.label();
+        self.exit = Some((exit, self.stack.len()));
+        self.block(&function.body)?;
+        self.asm.push(Asm::Label(exit));
+        let mut target: Vec<Slot> = returns.into_iter().rev().collect();
+        target.push(Slot::ReturnAddress);
+        self.shuffle(&target)?;
+        self.op(JUMP, 1, 0);
+        self.exit = None;
+        Ok(())
+    }
+
+    // Rearranges the stack into `target`, dropping any slots which aren't part of it.
+    fn shuffle(&mut self, target: &[Slot]) -> Result<(), String> {
+        while let Some(i) = self.stack.iter().rposition(|s| !target.contains(s)) {
+            let depth = self.stack.len() - 1 - i;
+            if depth > 0 {
+                self.swap(depth)?;
+            }
+            self.pop();
+        }
+        let top = self.stack.len() - 1;
+        for (i, slot) in target.iter().enumerate() {
+            if self.stack[i] != *slot {
+                let j = self.stack.iter().position(|s| s == slot).unwrap();
+                if j != top {
+                    self.swap(top - j)?;
+                }
+                self.swap(top - i)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn pad_right(bytes: &[u8]) -> [u8; 32] {
+    let mut word = [0; 32];
+    let len = bytes.len().min(32);
+    word[..len].copy_from_slice(&bytes[..len]);
+    word
+}
+
+// Generates the code of an object, along with the entry labels of its functions.
+fn generate(
+    code: &Block,
+    sizes: &HashMap<String, usize>,
+) -> Result<(Vec<Asm>, Vec<Label>), String> {
+    let mut codegen = Codegen {
+        asm: vec![],
+        stack: vec![],
+        vars: vec![],
+        functions: vec![],
+        loops: vec![],
+        exit: None,
+        pending: vec![],
+        entries: vec![],
+        sizes,
+        next_var: 0,
+        next_label: 0,
+    };
+    codegen.block(code)?;
+    codegen.asm.push(Asm::Source(None));
+    codegen.asm.push(Asm::Op(STOP));
+    while let Some(pending) = codegen.pending.pop() {
+        codegen.function_body(pending)?;
+    }
+    codegen.asm.push(Asm::Source(None));
+    Ok((codegen.asm, codegen.entries))
+}
+
+/// Bytecode generated from part of the query: a range of program counters in `object`'s code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceRange {
+    pub object: String,
+    pub pc: Range<usize>,
+    pub span: Span,
+}
+
+/// An object's bytecode, with a source map for it and its sub-objects.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assembly {
+    pub bytecode: Vec<u8>,
+    pub sources: Vec<SourceRange>,
+    /// The size of each sub-object appended to the code, by name.
+    pub objects: Vec<(String, usize)>,
+}
+
+/// Compiles a Yul object to bytecode: its code, followed by each of its sub-objects and then
+/// its data. Code between `Statement::Source` markers is mapped back to the query.
+pub fn assemble(object: &Object) -> Result<Assembly, String> {
+    let mut sources = vec![];
+    let objects = object
+        .objects
+        .iter()
+        .map(|o| {
+            let assembly = assemble(o)?;
+            sources.extend(assembly.sources);
+            Ok((o.name.clone(), assembly.bytecode))
+        })
+        .collect::<Result<Vec<(String, Vec<u8>)>, String>>()?;
+    let sizes = objects
+        .iter()
+        .chain(&object.data)
+        .map(|(name, code)| (name.clone(), code.len()))
+        .collect();
+    let (asm, _) = generate(&object.code, &sizes)?;
+
+    let mut labels = HashMap::new();
+    let mut code_size = 0;
+    for instruction in &asm {
+        if let Asm::Label(label) = instruction {
+            labels.insert(*label, code_size);
+        }
+        code_size += instruction.size();
+    }
+    let mut offsets = HashMap::new();
+    let mut offset = code_size;
+    for (name, code) in objects.iter().chain(&object.data) {
+        offsets.insert(name.clone(), offset);
+        offset += code.len();
+    }
+    if offset > usize::from(u16::MAX) {
+        return Err(format!(
+            "Object {} is {} bytes, which is too large to address",
+            object.name, offset
+        ));
+    }
+
+    let mut bytecode = Vec::with_capacity(offset);
+    let mut source: Option<(Span, usize)> = None;
+    for instruction in &asm {
+        match instruction {
+            Asm::Source(span) => {
+                let pc = bytecode.len();
+                if let Some((span, start)) = source.filter(|(_, start)| *start < pc) {
+                    sources.push(SourceRange {
+                        object: object.name.clone(),
+                        pc: start..pc,
+                        span,
+                    });
+                }
+                source = span.map(|span| (span, pc));
+            }
+            Asm::Op(opcode) => bytecode.push(*opcode),
+            Asm::Label(_) => bytecode.push(JUMPDEST),
+            Asm::Push(value) => {
+                let bytes = push_bytes(value);
+                bytecode.push(PUSH1 + bytes.len() as u8 - 1);
+                bytecode.extend(bytes);
+            }
+            Asm::PushLabel(label) => {
+                bytecode.push(PUSH2);
+                bytecode.extend((labels[label] as u16).to_be_bytes());
+            }
+            Asm::PushOffset(name) => {
+                bytecode.push(PUSH2);
+                bytecode.extend((offsets[name] as u16).to_be_bytes());
+            }
+        }
+    }
+    let sizes = objects
+        .iter()
+        .map(|(name, code)| (name.clone(), code.len()))
+        .collect();
+    for (_, code) in objects.iter().chain(&object.data) {
+        bytecode.extend(code);
+    }
+    Ok(Assembly {
+        bytecode,
+        sources,
+        objects: sizes,
+    })
+}
+
+/// Bounds on the gas spent running some code, not counting what it forwards to calls or
+/// what memory and copying cost per word. `max` is `None` when the code can loop.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GasBounds {
+    pub min: u64,
+    pub max: Option<u64>,
+}
+
+impl GasBounds {
+    fn then(self, other: GasBounds) -> GasBounds {
+        GasBounds {
+            min: self.min + other.min,
+            max: self.max.zip(other.max).map(|(a, b)| a + b),
+        }
+    }
+
+    fn or(self, other: GasBounds) -> GasBounds {
+        GasBounds {
+            min: self.min.min(other.min),
+            max: self.max.zip(other.max).map(|(a, b)| a.max(b)),
+        }
+    }
+}
+
+// The static gas of an opcode: cheapest with warm accounts and slots, dearest with cold ones.
+fn opcode_gas(opcode: u8) -> (u64, u64) {
+    match opcode {
+        0x00 | 0xf3 | 0xfd | 0xfe => (0, 0),
+        0x02 | 0x04..=0x07 | 0x0b | 0x47 => (5, 5),
+        0x08 | 0x09 | 0x56 => (8, 8),
+        0x0a | 0x57 => (10, 10),
+        0x20 => (30, 30),
+        0x30 | 0x32..=0x34 | 0x36 | 0x38 | 0x3a | 0x3d | 0x41..=0x46 | 0x48 | 0x4a => (2, 2),
+        0x50 | 0x58..=0x5a => (2, 2),
+        0x31 | 0x3b | 0x3c | 0x3f | 0xf1 | 0xf2 | 0xf4 | 0xfa => (100, 2600),
+        0x40 => (20, 20),
+        0x54 => (100, 2100),
+        0x55 => (100, 22100),
+        0x5b => (1, 1),
+        0x5c | 0x5d => (100, 100),
+        0xa0..=0xa4 => {
+            let topics = u64::from(opcode - 0xa0);
+            (375 * (topics + 1), 375 * (topics + 1))
+        }
+        0xf0 | 0xf5 => (32000, 32000),
+        0xff => (5000, 32600),
+        _ => (3, 3),
+    }
+}
+
+// Where a path through a function's code ends.
+#[derive(Clone, Copy, PartialEq)]
+enum Exit {
+    // Jumping back to the caller.
+    Return,
+    // Halting successfully, with `STOP` or `RETURN`.
+    Finish,
+    // Reverting.
+    Abort,
+}
+
+// The bounds of the paths through a function by how they end, once known.
+type Summary = HashMap<Label, Vec<(Exit, GasBounds)>>;
+
+// Where an instruction leads, with the gas spent getting there, and how it ends paths.
+type Edges = (Vec<(usize, GasBounds)>, Vec<(Exit, GasBounds)>);
+
+struct GasAnalysis<'a> {
+    asm: &'a [Asm],
+    labels: HashMap<Label, usize>,
+    entries: &'a [Label],
+    summaries: Summary,
+    // Functions being analysed, which only recursion reaches again.
+    active: Vec<Label>,
+}
+
+impl<'a> GasAnalysis<'a> {
+    fn edges(&mut self, index: usize) -> Edges {
+        let fixed = |gas: u64| GasBounds {
+            min: gas,
+            max: Some(gas),
+        };
+        let target = match index.checked_sub(1).map(|i| &self.asm[i]) {
+            Some(Asm::PushLabel(label)) => Some(*label),
+            _ => None,
+        };
+        match &self.asm[index] {
+            Asm::Source(_) => (vec![(index + 1, fixed(0))], vec![]),
+            Asm::Label(_) => (vec![(index + 1, fixed(1))], vec![]),
+            Asm::Op(opcode @ (0x00 | 0xf3 | 0xff)) => {
+                let (min, max) = opcode_gas(*opcode);
+                (
+                    vec![],
+                    vec![(
+                        Exit::Finish,
+                        GasBounds {
+                            min,
+                            max: Some(max),
+                        },
+                    )],
+                )
+            }
+            Asm::Op(0xfd | 0xfe) => (vec![], vec![(Exit::Abort, fixed(0))]),
+            Asm::Op(0x57) => {
+                let target = self.labels[&target.expect("JUMPI to a pushed label")];
+                (vec![(index + 1, fixed(10)), (target, fixed(10))], vec![])
+            }
+            Asm::Op(0x56) => match target {
+                Some(label) if self.entries.contains(&label) => {
+                    let callee = self.summary(label);
+                    let mut next = vec![];
+                    let mut exits = vec![];
+                    for (exit, bounds) in callee {
+                        let bounds = fixed(8).then(bounds);
+                        match exit {
+                            Exit::Return => next.push((index + 1, bounds)),
+                            exit => exits.push((exit, bounds)),
+                        }
+                    }
+                    (next, exits)
+                }
+                Some(label) => (vec![(self.labels[&label], fixed(8))], vec![]),
+                None => (vec![], vec![(Exit::Return, fixed(8))]),
+            },
+            Asm::Op(opcode) => {
+                let (min, max) = opcode_gas(*opcode);
+                (
+                    vec![(
+                        index + 1,
+                        GasBounds {
+                            min,
+                            max: Some(max),
+                        },
+                    )],
+                    vec![],
+                )
+            }
+            Asm::Push(_) | Asm::PushLabel(_) | Asm::PushOffset(_) => {
+                (vec![(index + 1, fixed(3))], vec![])
+            }
+        }
+    }
+
+    // Bounds on the paths from `start` to each kind of exit.
+    fn paths(&mut self, start: usize) -> Vec<(Exit, GasBounds)> {
+        // Collects the instructions reachable from `start`, in depth-first post-order.
+        let mut edges = HashMap::new();
+        let mut order = vec![];
+        let mut stack = vec![(start, false)];
+        let mut cyclic = false;
+        let mut state: HashMap<usize, bool> = HashMap::new();
+        while let Some((index, done)) = stack.pop() {
+            if done {
+                state.insert(index, true);
+                order.push(index);
+                continue;
+            }
+            match state.get(&index) {
+                Some(true) => continue,
+                Some(false) => {
+                    // Reached again before it was finished, so it's on a cycle.
+                    cyclic = true;
+                    continue;
+                }
+                None => {}
+            }
+            state.insert(index, false);
+            stack.push((index, true));
+            let (next, exits) = self.edges(index);
+            for (target, _) in &next {
+                match state.get(target) {
+                    Some(false) => cyclic = true,
+                    Some(true) => {}
+                    None => stack.push((*target, false)),
+                }
+            }
+            edges.insert(index, (next, exits));
+        }
+
+        // Without cycles, reverse post-order visits each instruction after everything leading
+        // to it. With them, there's no upper bound, and the lower ones settle after a few
+        // passes.
+        let mut reached: HashMap<usize, GasBounds> = HashMap::new();
+        let max = if cyclic { None } else { Some(0) };
+        reached.insert(start, GasBounds { min: 0, max });
+        let mut exits: Vec<(Exit, GasBounds)> = vec![];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for index in order.iter().rev() {
+                let here = match reached.get(index) {
+                    Some(here) => *here,
+                    None => continue,
+                };
+                let (next, ends) = &edges[index];
+                for (target, cost) in next {
+                    let bounds = here.then(*cost);
+                    let merged = match reached.get(target) {
+                        Some(existing) => existing.or(bounds),
+                        None => bounds,
+                    };
+                    if reached.get(target) != Some(&merged) {
+                        reached.insert(*target, merged);
+                        changed = true;
+                    }
+                }
+                for (exit, cost) in ends {
+                    let bounds = here.then(*cost);
+                    match exits.iter_mut().find(|(e, _)| e == exit) {
+                        Some((_, existing)) => *existing = existing.or(bounds),
+                        None => exits.push((*exit, bounds)),
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+            exits.clear();
+        }
+        exits
+    }
+
+    fn summary(&mut self, entry: Label) -> Vec<(Exit, GasBounds)> {
+        if let Some(summary) = self.summaries.get(&entry) {
+            return summary.clone();
+        }
+        if self.active.contains(&entry) {
+            // A recursive call could return after any number of rounds.
+            return vec![(Exit::Return, GasBounds { min: 0, max: None })];
+        }
+        self.active.push(entry);
+        let summary = self.paths(self.labels[&entry]);
+        self.active.pop();
+        self.summaries.insert(entry, summary.clone());
+        summary
+    }
+}
+
+/// Bounds on the gas spent by an object's code on the way to halting successfully, or
+/// `None` if it always reverts.
+pub fn static_gas(object: &Object) -> Result<Option<GasBounds>, String> {
+    let sizes = object
+        .objects
+        .iter()
+        .map(|o| Ok((o.name.clone(), assemble(o)?.bytecode.len())))
+        .chain(
+            object
+                .data
+                .iter()
+                .map(|(name, data)| Ok((name.clone(), data.len()))),
+        )
+        .collect::<Result<HashMap<String, usize>, String>>()?;
+    let (asm, entries) = generate(&object.code, &sizes)?;
+    let labels = asm
+        .iter()
+        .enumerate()
+        .filter_map(|(index, instruction)| match instruction {
+            Asm::Label(label) => Some((*label, index)),
+            _ => None,
+        })
+        .collect();
+    let mut analysis = GasAnalysis {
+        asm: &asm,
+        labels,
+        entries: &entries,
+        summaries: HashMap::new(),
+        active: vec![],
+    };
+    Ok(analysis
+        .paths(0)
+        .into_iter()
+        .find(|(exit, _)| *exit == Exit::Finish)
+        .map(|(_, bounds)| bounds))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evm::{assemble, static_gas, GasBounds, SourceRange};
+    use crate::query::Span;
+    use crate::yul_ast::{Block, Object, Statement};
+    use crate::yul_parse::parse_yul;
+    use ethers::abi::{decode, param_type::Reader, ParamType, Token};
+    use ethers::utils::hex;
+    use revm::db::InMemoryDB;
+    use revm::primitives::{
+        AccountInfo, Address, Bytecode, Bytes, CreateScheme, ExecutionResult, Output, TransactTo,
+        U256,
+    };
+    use revm::Evm;
+
+    fn object(code: &str) -> Object {
+        Object {
+            name: String::from("Test"),
+            code: Block(parse_yul(code).unwrap()),
+            objects: vec![],
+            data: vec![],
+        }
+    }
+
+    fn transact(db: &mut InMemoryDB, to: TransactTo, data: Vec<u8>) -> ExecutionResult {
+        let mut evm = Evm::builder()
+            .with_db(db)
+            .modify_tx_env(|tx| {
+                tx.caller = Address::repeat_byte(0xca);
+                tx.transact_to = to;
+                tx.data = Bytes::from(data);
+                tx.gas_limit = 30_000_000;
+            })
+            .build();
+        evm.transact_commit().unwrap()
+    }
+
+    fn install(db: &mut InMemoryDB, address: Address, code: Vec<u8>) {
+        let bytecode = Bytecode::new_raw(Bytes::from(code));
+        let info = AccountInfo::new(U256::ZERO, 1, bytecode.hash_slow(), bytecode);
+        db.insert_account_info(address, info);
+    }
+
+    // Runs code at a fresh address and returns its output, or the revert data as an error.
+    fn run(code: &str) -> Result<Vec<u8>, Vec<u8>> {
+        let mut db = InMemoryDB::default();
+        let address = Address::repeat_byte(0x42);
+        install(&mut db, address, assemble(&object(code)).unwrap().bytecode);
+        match transact(&mut db, TransactTo::Call(address), vec![]) {
+            ExecutionResult::Success { output, .. } => Ok(output.into_data().to_vec()),
+            ExecutionResult::Revert { output, .. } => Err(output.to_vec()),
+            halt => panic!("Halted: {:?}", halt),
+        }
+    }
+
+    fn words(output: Vec<u8>, count: usize) -> Vec<Token> {
+        decode(&vec![ParamType::Uint(256); count], &output).unwrap()
+    }
+
+    #[test]
+    fn assemble_straight_line_code() {
+        assert_eq!(
+            hex::encode(
+                assemble(&object("mstore(0, 0x2a) return(0, 0x20)"))
+                    .unwrap()
+                    .bytecode
+            ),
+            "602a6000526020 6000f300".replace(' ', "")
+        );
+    }
+
+    #[test]
+    fn run_control_flow() {
+        let output = run(r#"
+            function fact(n) -> r {
+                r := 1
+                if gt(n, 1) {
+                    r := mul(n, fact(sub(n, 1)))
+                }
+            }
+            function divmod(a, b) -> q, r {
+                if iszero(b) { leave }
+                q := div(a, b)
+                r := mod(a, b)
+            }
+            let sum := 0
+            for { let i := 0 } lt(i, 100) { i := add(i, 1) } {
+                if eq(i, 10) { break }
+                if mod(i, 2) { continue }
+                sum := add(sum, i)
+            }
+            let q, r := divmod(17, 5)
+            let kind := 0
+            switch r
+            case 1 { kind := 11 }
+            case 2 { kind := 22 }
+            default { kind := 99 }
+            mstore(0, fact(5))
+            mstore(0x20, sum)
+            mstore(0x40, q)
+            mstore(0x60, r)
+            mstore(0x80, kind)
+            mstore(0xa0, "ab")
+            return(0, 0xc0)
+            "#)
+        .unwrap();
+        let mut ab = [0; 32];
+        ab[..2].copy_from_slice(b"ab");
+        assert_eq!(
+            words(output, 6),
+            vec![
+                Token::Uint(120.into()),
+                Token::Uint(20.into()),
+                Token::Uint(3.into()),
+                Token::Uint(2.into()),
+                Token::Uint(22.into()),
+                Token::Uint(ethers::types::U256::from_big_endian(&ab)),
+            ]
+        );
+    }
+
+    #[test]
+    fn run_revert() {
+        assert_eq!(run("mstore(0, 7) revert(0x1f, 1)"), Err(vec![7]));
+    }
+
+    #[test]
+    fn run_data() {
+        let mut code = object(
+            r#"
+            datacopy(0, dataoffset("b"), datasize("b"))
+            datacopy(datasize("b"), dataoffset("a"), datasize("a"))
+            return(0, add(datasize("a"), datasize("b")))
+            "#,
+        );
+        code.data = vec![
+            (String::from("a"), b"sleuth".to_vec()),
+            (String::from("b"), b"hi ".to_vec()),
+        ];
+        let bytecode = assemble(&code).unwrap().bytecode;
+        assert!(bytecode.ends_with(b"sleuthhi "));
+        let mut db = InMemoryDB::default();
+        let address = Address::repeat_byte(0x42);
+        install(&mut db, address, bytecode);
+        match transact(&mut db, TransactTo::Call(address), vec![]) {
+            ExecutionResult::Success { output, .. } => {
+                assert_eq!(output.into_data().to_vec(), b"hi sleuth".to_vec())
+            }
+            result => panic!("Failed: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn assemble_source_map() {
+        let span = Span { start: 7, end: 24 };
+        let mut code = vec![Statement::Source(Some(span))];
+        code.extend(parse_yul("mstore(0, f())").unwrap());
+        code.push(Statement::Source(None));
+        code.extend(parse_yul("return(0, 0x20) function f() -> r { r := 0x2a }").unwrap());
+        let assembly = assemble(&Object {
+            name: String::from("Test"),
+            code: Block(code),
+            objects: vec![],
+            data: vec![],
+        })
+        .unwrap();
+        // PUSH2 back, PUSH2 f, JUMP, JUMPDEST, PUSH1 0, MSTORE: the body of `f` isn't included.
+        assert_eq!(
+            assembly.sources,
+            vec![SourceRange {
+                object: String::from("Test"),
+                pc: 0..11,
+                span,
+            }]
+        );
+    }
+
+    #[test]
+    fn static_gas_bounds() {
+        // PUSH1, PUSH1, MSTORE, PUSH1, PUSH1 and RETURN.
+        assert_eq!(
+            static_gas(&object("mstore(0, 0x2a) return(0, 0x20)")),
+            Ok(Some(GasBounds {
+                min: 15,
+                max: Some(15)
+            }))
+        );
+
+        let branches = static_gas(&object(
+            r#"
+            function f(x) -> r {
+                if x {
+                    r := balance(x)
+                }
+            }
+            mstore(0, f(calldataload(0)))
+            if iszero(mload(0)) {
+                revert(0, 0)
+            }
+            return(0, 0x20)
+            "#,
+        ))
+        .unwrap()
+        .unwrap();
+        // Reading a cold balance on one path, against skipping it on the other.
+        assert!(branches.max.unwrap() - branches.min > 2600);
+
+        let looping = static_gas(&object(
+            "for { let i := 0 } lt(i, calldataload(0)) { i := add(i, 1) } { } return(0, 0)",
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(looping.max, None);
+        assert!(looping.min > 0);
+
+        assert_eq!(static_gas(&object("revert(0, 0)")), Ok(None));
+    }
+
+    #[test]
+    fn assemble_errors() {
+        let deep = (0..17)
+            .map(|i| format!("let v{} := {}", i, i))
+            .collect::<Vec<String>>()
+            .join("\n");
+        assert_eq!(
+            assemble(&object(&format!("{}\nmstore(0, v0)", deep))),
+            Err(String::from(
+                "Stack too deep: v0 is 17 slots deep, but only 16 can be reached"
+            ))
+        );
+        assert_eq!(
+            assemble(&object("mstore(0)")),
+            Err(String::from("mstore takes 2 arguments, got 1"))
+        );
+        assert_eq!(
+            assemble(&object("frobnicate()")),
+            Err(String::from("Unknown function frobnicate"))
+        );
+        assert_eq!(
+            assemble(&object("pop(x)")),
+            Err(String::from("Undefined variable x"))
+        );
+    }
+
+    const TOKEN_QUERY: &str = r#"
+        REGISTER CONTRACT token AT 0x1111111111111111111111111111111111111111 WITH INTERFACE [
+            "function totalSupply() view returns (uint256)",
+            "function symbol() view returns (string)",
+            "function decimals() view returns (uint8)"
+        ];
+        SELECT token.totalSupply, TRY(token.decimals), "hi", 5, token.symbol, token.totalSupply FROM token;
+        "#;
+
+    // A chain with a token at 0x1111..., which answers `totalSupply()` with 1000 and
+    // `symbol()` with "TKN", and reverts otherwise.
+    fn token_db() -> InMemoryDB {
+        let mut db = InMemoryDB::default();
+        let token = Object {
+            name: String::from("Token"),
+            code: Block(
+                parse_yul(
+                    r#"
+                    switch shr(224, calldataload(0))
+                    case 0x18160ddd {
+                        mstore(0, 1000)
+                        return(0, 0x20)
+                    }
+                    case 0x95d89b41 {
+                        mstore(0, 0x20)
+                        mstore(0x20, 3)
+                        mstore(0x40, "TKN")
+                        return(0, 0x60)
+                    }
+                    default {
+                        revert(0, 0)
+                    }
+                    "#,
+                )
+                .unwrap(),
+            ),
+            objects: vec![],
+            data: vec![],
+        };
+        install(
+            &mut db,
+            Address::repeat_byte(0x11),
+            assemble(&token).unwrap().bytecode,
+        );
+        db
+    }
+
+    fn decode_token_query(compiled: &crate::compile::Compiled, output: &[u8]) -> Vec<Token> {
+        let types: Vec<ParamType> = compiled
+            .columns
+            .iter()
+            .map(|c| Reader::read(&c.ty.replace("tuple(", "(")).unwrap())
+            .collect();
+        decode(&types, output).unwrap()
+    }
+
+    fn token_query_result() -> Vec<Token> {
+        vec![
+            Token::Tuple(vec![Token::Uint(1000.into())]),
+            Token::Tuple(vec![
+                Token::Bool(false),
+                Token::Tuple(vec![Token::Uint(0.into())]),
+            ]),
+            Token::String(String::from("hi")),
+            Token::Uint(5.into()),
+            Token::Tuple(vec![Token::String(String::from("TKN"))]),
+            Token::Tuple(vec![Token::Uint(1000.into())]),
+        ]
+    }
+
+    #[test]
+    fn deploy_and_run_query() {
+        let compiled = crate::compile::compile(TOKEN_QUERY).unwrap();
+        let mut db = token_db();
+
+        let init = hex::decode(&compiled.bytecode[2..]).unwrap();
+        let query = match transact(&mut db, TransactTo::Create(CreateScheme::Create), init) {
+            ExecutionResult::Success {
+                output: Output::Create(_, Some(address)),
+                ..
+            } => address,
+            result => panic!("Deployment failed: {:?}", result),
+        };
+        let output = match transact(
+            &mut db,
+            TransactTo::Call(query),
+            vec![0x2c, 0x46, 0xb2, 0x05],
+        ) {
+            ExecutionResult::Success { output, .. } => output.into_data().to_vec(),
+            result => panic!("Query failed: {:?}", result),
+        };
+        assert_eq!(decode_token_query(&compiled, &output), token_query_result());
+    }
+
+    #[test]
+    fn run_query_in_initcode() {
+        let compiled = crate::compile::compile(TOKEN_QUERY).unwrap();
+        let mut db = token_db();
+
+        let init = hex::decode(&compiled.initcode.as_ref().unwrap()[2..]).unwrap();
+        // What the init code returns is what an `eth_call` creating it returns.
+        let output = match transact(&mut db, TransactTo::Create(CreateScheme::Create), init) {
+            ExecutionResult::Success {
+                output: Output::Create(output, _),
+                ..
+            } => output.to_vec(),
+            result => panic!("Query failed: {:?}", result),
+        };
+        assert_eq!(decode_token_query(&compiled, &output), token_query_result());
+
+        let named = crate::compile::compile("QUERY code(:a) AS SELECT CODESIZE(:a)").unwrap();
+        assert_eq!(named.initcode, None);
+    }
+
+    #[test]
+    fn deploy_and_run_named_queries() {
+        let compiled = crate::compile::compile(
+            "QUERY block AS SELECT block.number FROM block; QUERY account(:a) AS SELECT :a, BALANCE(:a), IS_CONTRACT(:a)",
+        )
+        .unwrap();
+        let mut db = InMemoryDB::default();
+        let account = Address::repeat_byte(0x11);
+        db.insert_account_info(account, AccountInfo::from_balance(U256::from(7)));
+
+        let init = hex::decode(&compiled.bytecode[2..]).unwrap();
+        let query = match transact(&mut db, TransactTo::Create(CreateScheme::Create), init) {
+            ExecutionResult::Success {
+                output: Output::Create(_, Some(address)),
+                ..
+            } => address,
+            result => panic!("Deployment failed: {:?}", result),
+        };
+        let selector = hex::decode(&compiled.queries[1].selector[2..]).unwrap();
+        // The high bytes of the argument are ignored.
+        let mut argument = [0xff; 32];
+        argument[12..].copy_from_slice(account.as_slice());
+        let output = match transact(
+            &mut db,
+            TransactTo::Call(query),
+            [selector, argument.to_vec()].concat(),
+        ) {
+            ExecutionResult::Success { output, .. } => output.into_data().to_vec(),
+            result => panic!("Query failed: {:?}", result),
+        };
+        assert_eq!(
+            decode(
+                &[ParamType::Address, ParamType::Uint(256), ParamType::Bool],
+                &output
+            )
+            .unwrap(),
+            vec![
+                Token::Address(ethers::types::H160::repeat_byte(0x11)),
+                Token::Uint(7.into()),
+                Token::Bool(false),
+            ]
+        );
+        // `query()` isn't defined, as there are no plain `SELECT`s.
+        assert!(matches!(
+            transact(
+                &mut db,
+                TransactTo::Call(query),
+                vec![0x2c, 0x46, 0xb2, 0x05]
+            ),
+            ExecutionResult::Revert { .. }
+        ));
+    }
+}
@@ -0,0 +1,321 @@
+use crate::compile::Compiled;
+use crate::revert::describe_revert;
+use crate::sleuth::{decode_response, encode_request, sleuth_address, SLEUTH_DEPLOYER};
+use ethers::abi::Token;
+use ethers::types::{Address, H256};
+#[cfg(all(not(target_arch = "wasm32"), any(test, feature = "http")))]
+use ethers::utils::hex;
+use serde_json::{json, Value};
+
+/// The block a call runs at.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockTag {
+    Latest,
+    Pending,
+    Safe,
+    Finalized,
+    Earliest,
+    Number(u64),
+    /// A block by hash, as in EIP-1898, so that a reorg makes the call fail rather than run
+    /// against another block.
+    Hash(H256),
+}
+
+impl BlockTag {
+    /// The block as a JSON-RPC parameter.
+    pub fn to_json(&self) -> Value {
+        match self {
+            BlockTag::Latest => json!("latest"),
+            BlockTag::Pending => json!("pending"),
+            BlockTag::Safe => json!("safe"),
+            BlockTag::Finalized => json!("finalized"),
+            BlockTag::Earliest => json!("earliest"),
+            BlockTag::Number(n) => json!(format!("0x{:x}", n)),
+            BlockTag::Hash(hash) => json!({ "blockHash": format!("{:?}", hash) }),
+        }
+    }
+}
+
+/// A call to make with `eth_call`. Without `to`, `data` is run as init code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CallRequest {
+    pub to: Option<Address>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CallResult {
+    Success(Vec<u8>),
+    /// The call reverted, with this revert data.
+    Revert(Vec<u8>),
+}
+
+/// What a query needs from a node.
+pub trait Provider {
+    /// `eth_call`. A revert is a `CallResult`, while errors are for calls which couldn't be
+    /// made at all.
+    fn call(&self, request: &CallRequest, block: &BlockTag) -> Result<CallResult, String>;
+
+    /// `eth_chainId`.
+    fn chain_id(&self) -> Result<u64, String>;
+
+    /// `eth_blockNumber`.
+    fn block_number(&self) -> Result<u64, String>;
+}
+
+#[cfg(all(not(target_arch = "wasm32"), any(test, feature = "http")))]
+fn quantity(value: &Value) -> Result<u64, String> {
+    value
+        .as_str()
+        .and_then(|s| u64::from_str_radix(s.trim_start_matches("0x"), 16).ok())
+        .ok_or_else(|| format!("Expected a quantity, got {}", value))
+}
+
+#[cfg(all(not(target_arch = "wasm32"), any(test, feature = "http")))]
+fn data(value: &Value) -> Result<Vec<u8>, String> {
+    value
+        .as_str()
+        .and_then(|s| hex::decode(s.trim_start_matches("0x")).ok())
+        .ok_or_else(|| format!("Expected data, got {}", value))
+}
+
+/// A provider which sends JSON-RPC requests over HTTP, with the `http` feature.
+#[cfg(all(not(target_arch = "wasm32"), any(test, feature = "http")))]
+pub struct HttpProvider {
+    pub url: String,
+    client: reqwest::blocking::Client,
+    next_id: std::cell::Cell<u64>,
+}
+
+#[cfg(all(not(target_arch = "wasm32"), any(test, feature = "http")))]
+impl HttpProvider {
+    pub fn new(url: &str) -> Self {
+        HttpProvider {
+            url: String::from(url),
+            client: reqwest::blocking::Client::new(),
+            next_id: std::cell::Cell::new(1),
+        }
+    }
+
+    // Sends a request, returning its result, or the error object the node responded with.
+    fn request(&self, method: &str, params: Value) -> Result<Result<Value, Value>, String> {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        let body = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let response: Value = self
+            .client
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(|e| format!("Error sending {} to {}: {}", method, self.url, e))?;
+        match (response.get("result"), response.get("error")) {
+            (_, Some(error)) => Ok(Err(error.clone())),
+            (Some(result), None) => Ok(Ok(result.clone())),
+            (None, None) => Err(format!("Invalid response to {}: {}", method, response)),
+        }
+    }
+
+    fn result(&self, method: &str, params: Value) -> Result<Value, String> {
+        self.request(method, params)?
+            .map_err(|error| format!("{} failed: {}", method, error))
+    }
+}
+
+#[cfg(all(not(target_arch = "wasm32"), any(test, feature = "http")))]
+impl Provider for HttpProvider {
+    fn call(&self, request: &CallRequest, block: &BlockTag) -> Result<CallResult, String> {
+        let mut transaction = json!({ "data": format!("0x{}", hex::encode(&request.data)) });
+        if let Some(to) = request.to {
+            transaction["to"] = json!(format!("{:?}", to));
+        }
+        match self.request("eth_call", json!([transaction, block.to_json()]))? {
+            Ok(result) => Ok(CallResult::Success(data(&result)?)),
+            // Nodes give revert data with the error, and the message alone when there isn't any.
+            Err(error) => match error.get("data") {
+                Some(revert) if revert.is_string() => Ok(CallResult::Revert(data(revert)?)),
+                _ if error["message"]
+                    .as_str()
+                    .is_some_and(|m| m.contains("revert")) =>
+                {
+                    Ok(CallResult::Revert(vec![]))
+                }
+                _ => Err(format!("eth_call failed: {}", error)),
+            },
+        }
+    }
+
+    fn chain_id(&self) -> Result<u64, String> {
+        quantity(&self.result("eth_chainId", json!([]))?)
+    }
+
+    fn block_number(&self) -> Result<u64, String> {
+        quantity(&self.result("eth_blockNumber", json!([]))?)
+    }
+}
+
+/// Runs compiled queries through a `Sleuth.sol` deployment.
+pub struct Executor<P: Provider> {
+    pub provider: P,
+    /// Where `Sleuth.sol` is deployed.
+    pub sleuth: Address,
+}
+
+impl<P: Provider> Executor<P> {
+    /// An executor using the given version of `Sleuth.sol`, from the usual deployer.
+    pub fn new(provider: P, version: u64) -> Result<Self, String> {
+        let deployer = SLEUTH_DEPLOYER.parse().unwrap();
+        Ok(Executor {
+            provider,
+            sleuth: sleuth_address(deployer, version)?,
+        })
+    }
+
+    /// Runs the named query with `args` at `block`, returning its columns, or a description
+    /// of why it reverted.
+    pub fn query(
+        &self,
+        compiled: &Compiled,
+        name: &str,
+        args: &[Address],
+        block: &BlockTag,
+    ) -> Result<Vec<Token>, String> {
+        let request = CallRequest {
+            to: Some(self.sleuth),
+            data: encode_request(compiled, name, args)?,
+        };
+        match self.provider.call(&request, block)? {
+            CallResult::Success(data) => decode_response(compiled, name, &data),
+            CallResult::Revert(data) => Err(describe_revert(compiled, &data)?),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use crate::compile::compile;
+    use crate::executor::{BlockTag, Executor, HttpProvider, Provider};
+    use crate::sleuth::{sleuth_address, SLEUTH_DEPLOYER};
+    use ethers::abi::{self, Token};
+    use ethers::types::H256;
+    use serde_json::{json, Value};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Serves one JSON-RPC response per request, each from `respond`, and returns the server's
+    // URL.
+    fn serve(requests: usize, respond: fn(&Value) -> Value) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    let lower = line.to_lowercase();
+                    if let Some(value) = lower.strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let request: Value = serde_json::from_slice(&body).unwrap();
+                let mut response = respond(&request);
+                response["jsonrpc"] = json!("2.0");
+                response["id"] = request["id"].clone();
+                let response = response.to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn block_tags() {
+        assert_eq!(BlockTag::Latest.to_json(), json!("latest"));
+        assert_eq!(BlockTag::Number(16).to_json(), json!("0x10"));
+        assert_eq!(
+            BlockTag::Hash(H256::repeat_byte(0xab)).to_json(),
+            json!({ "blockHash": format!("0x{}", "ab".repeat(32)) })
+        );
+    }
+
+    #[test]
+    fn chain_id_and_block_number() {
+        let url = serve(2, |request| match request["method"].as_str().unwrap() {
+            "eth_chainId" => json!({ "result": "0x1" }),
+            "eth_blockNumber" => json!({ "result": "0x10d4f" }),
+            _ => json!({ "error": { "code": -32601, "message": "method not found" } }),
+        });
+        let provider = HttpProvider::new(&url);
+        assert_eq!(provider.chain_id(), Ok(1));
+        assert_eq!(provider.block_number(), Ok(68943));
+    }
+
+    #[test]
+    fn execute_query() {
+        // Answers with a query's result when called at block 16 through the first Sleuth.
+        let url = serve(1, |request| {
+            let sleuth = sleuth_address(SLEUTH_DEPLOYER.parse().unwrap(), 1).unwrap();
+            assert_eq!(request["method"], "eth_call");
+            assert_eq!(request["params"][0]["to"], format!("{:?}", sleuth));
+            assert_eq!(request["params"][1], "0x10");
+            let result = abi::encode(&[Token::Bytes(abi::encode(&[
+                Token::Uint(5.into()),
+                Token::String(String::from("cat")),
+            ]))]);
+            json!({ "result": format!("0x{}", ethers::utils::hex::encode(result)) })
+        });
+        let executor = Executor::new(HttpProvider::new(&url), 1).unwrap();
+        let compiled = compile(r#"SELECT 5, "cat""#).unwrap();
+        assert_eq!(
+            executor.query(&compiled, "query", &[], &BlockTag::Number(16)),
+            Ok(vec![
+                Token::Uint(5.into()),
+                Token::String(String::from("cat"))
+            ])
+        );
+    }
+
+    #[test]
+    fn describe_reverts() {
+        let url = serve(2, |request| {
+            if request["params"][1] == "latest" {
+                let selector = ethers::utils::id("DeploymentError()");
+                json!({ "error": {
+                    "code": 3,
+                    "message": "execution reverted",
+                    "data": format!("0x{}", ethers::utils::hex::encode(selector)),
+                } })
+            } else {
+                json!({ "error": { "code": -32000, "message": "header not found" } })
+            }
+        });
+        let executor = Executor::new(HttpProvider::new(&url), 1).unwrap();
+        let compiled = compile("SELECT 5").unwrap();
+        assert_eq!(
+            executor.query(&compiled, "query", &[], &BlockTag::Latest),
+            Err(String::from("Query reverted: DeploymentError()"))
+        );
+        assert_eq!(
+            executor.query(&compiled, "query", &[], &BlockTag::Number(1)),
+            Err(String::from(
+                r#"eth_call failed: {"code":-32000,"message":"header not found"}"#
+            ))
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use crate::compile::Compiled;
+
+/// Describes a compiled query for people: each function it has and its columns, then what
+/// running it costs.
+pub fn explain(compiled: &Compiled) -> String {
+    let mut lines = vec![];
+    for query in &compiled.queries {
+        lines.push(format!(
+            "{} [{}] returns {}",
+            query.signature, query.selector, query.tuple
+        ));
+        for (index, column) in query.columns.iter().enumerate() {
+            lines.push(format!("  {}: {}", column.label(index), column.ty));
+        }
+    }
+    let mut options = vec![];
+    if compiled.strict {
+        options.push("strict");
+    }
+    if !compiled.read_only {
+        options.push("may modify state");
+    }
+    if !options.is_empty() {
+        lines.push(format!("options: {}", options.join(", ")));
+    }
+
+    let estimate = &compiled.estimate;
+    lines.push(format!(
+        "code: {} bytes deployed, {} bytes of init code",
+        estimate.runtime_size, estimate.init_size
+    ));
+    lines.push(format!(
+        "calls: {}, memory: {} bytes",
+        estimate.calls, estimate.memory
+    ));
+    let max_gas = match estimate.max_gas {
+        Some(max) => max.to_string(),
+        None => String::from("unbounded"),
+    };
+    lines.push(format!(
+        "gas: {} to {}, besides what calls spend",
+        estimate.min_gas, max_gas
+    ));
+    for error in &compiled.errors {
+        lines.push(format!("may revert with {}", error));
+    }
+    for warning in &estimate.warnings {
+        lines.push(format!("warning: {}", warning));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::compile;
+    use crate::explain::explain;
+
+    #[test]
+    fn explain_query() {
+        let compiled = compile(
+            r###"
+            SET STRICT;
+            REGISTER CONTRACT comet AT 0xc3d688B66703497DAA19211EEdff47f25384cdc3 WITH INTERFACE [
+                "function totalSupply() view returns (uint256)",
+                "error Paused()"
+            ];
+            SELECT comet.totalSupply, 5 FROM comet;
+            QUERY account(:a) AS SELECT BALANCE(:a)
+            "###,
+        )
+        .unwrap();
+        let estimate = &compiled.estimate;
+        assert_eq!(
+            explain(&compiled),
+            [
+                String::from(
+                    "query() [0x2c46b205] returns tuple(tuple(uint256) totalSupply,uint256)"
+                ),
+                String::from("  column 0 (`comet.totalSupply`): tuple(uint256)"),
+                String::from("  column 1 (`5`): uint256"),
+                String::from("account(address) [0x73b9aa91] returns tuple(uint256)"),
+                String::from("  column 0 (`BALANCE(:a)`): uint256"),
+                String::from("options: strict"),
+                format!(
+                    "code: {} bytes deployed, {} bytes of init code",
+                    estimate.runtime_size, estimate.init_size
+                ),
+                format!("calls: 1, memory: {} bytes", estimate.memory),
+                format!(
+                    "gas: {} to {}, besides what calls spend",
+                    estimate.min_gas,
+                    estimate.max_gas.unwrap()
+                ),
+                String::from("may revert with error Paused()"),
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use crate::parse::parse_query_cls;
+use crate::query::{
+    FullSelectVar, NamedQuery, Query, QueryOption, RegisterQuery, SelectQuery, SelectVar, Selection,
+};
+
+fn format_var(var: &FullSelectVar) -> String {
+    let variable = match &var.variable {
+        SelectVar::Wildcard => "*",
+        SelectVar::Var(variable) => variable,
+    };
+    match var.source {
+        Some(source) => format!("{}.{}", source, variable),
+        None => String::from(variable),
+    }
+}
+
+fn format_selection(selection: &Selection) -> String {
+    match selection {
+        Selection::Var(var) => format_var(var),
+        // Functions are builtins, whose names aren't case sensitive.
+        Selection::Function(function) => format!(
+            "{}({})",
+            function.name.to_uppercase(),
+            format_selections(&function.args)
+        ),
+        Selection::Number(n) => n.to_string(),
+        Selection::String(s) => format!("\"{}\"", s),
+        Selection::Hex(h) => String::from(*h),
+        Selection::Gas(inner, limit) => format!("{} GAS {}", format_selection(inner), limit),
+        Selection::Param(name) => format!(":{}", name),
+    }
+}
+
+fn format_selections(selections: &[Selection]) -> String {
+    selections
+        .iter()
+        .map(format_selection)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn format_select(select: &SelectQuery) -> String {
+    let mut res = format!("SELECT {}", format_selections(&select.select));
+    if let Some(source) = select.source {
+        res.push_str(&format!(" FROM {}", source));
+    }
+    if let Some(gas) = select.gas {
+        res.push_str(&format!(" GAS {}", gas));
+    }
+    res
+}
+
+fn format_register(register: &RegisterQuery) -> String {
+    let mut res = format!(
+        "REGISTER CONTRACT {} AT {}",
+        register.source, register.address
+    );
+    if let Some(builtin) = register.builtin {
+        res.push_str(&format!(" AS {}", builtin));
+    }
+    if !register.interface.is_empty() {
+        let items = register
+            .interface
+            .iter()
+            .map(|item| format!("  \"{}\"", item))
+            .collect::<Vec<String>>()
+            .join(",\n");
+        res.push_str(&format!(" WITH INTERFACE [\n{}\n]", items));
+    }
+    res
+}
+
+fn format_named(named: &NamedQuery) -> String {
+    let params = if named.params.is_empty() {
+        String::new()
+    } else {
+        let params = named
+            .params
+            .iter()
+            .map(|p| format!(":{}", p))
+            .collect::<Vec<String>>()
+            .join(", ");
+        format!("({})", params)
+    };
+    format!(
+        "QUERY {}{} AS {}",
+        named.name,
+        params,
+        format_select(&named.select)
+    )
+}
+
+fn format_statement(query: &Query) -> String {
+    match query {
+        Query::Select(select) => format_select(select),
+        Query::Register(register) => format_register(register),
+        Query::Set(QueryOption::Strict) => String::from("SET STRICT"),
+        Query::Set(QueryOption::AllowMutations) => String::from("SET ALLOW_MUTATIONS"),
+        Query::Set(QueryOption::GasBudget(budget)) => format!("SET GAS_BUDGET {}", budget),
+        Query::Named(named) => format_named(named),
+    }
+}
+
+/// Formats a query in the canonical style: one statement per line, each ending in `;`, with
+/// keywords and functions in upper case and an interface item per line.
+pub fn format_query(query: &str) -> Result<String, String> {
+    // Comments are skipped by the parser, so formatting would drop them.
+    if query.contains("/*") {
+        return Err(String::from("Queries with comments can't be formatted"));
+    }
+    let query_set = parse_query_cls(query)?;
+    Ok(query_set
+        .iter()
+        .map(|q| format!("{};\n", format_statement(q)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::format_query;
+
+    #[test]
+    fn format_queries() {
+        let query = r#"set strict ; register contract comet at 0xc3d688B66703497DAA19211EEdff47f25384cdc3 with interface ["function totalSupply() view returns (uint256)","function name() view returns (string)"];
+            select comet.totalSupply gas 50000,try( comet.name ),"cat",5 from comet gas 200000;
+            query account( :a,:b ) as select balance(:a), Is_Contract(:b)"#;
+        let formatted = r#"SET STRICT;
+REGISTER CONTRACT comet AT 0xc3d688B66703497DAA19211EEdff47f25384cdc3 WITH INTERFACE [
+  "function totalSupply() view returns (uint256)",
+  "function name() view returns (string)"
+];
+SELECT comet.totalSupply GAS 50000, TRY(comet.name), "cat", 5 FROM comet GAS 200000;
+QUERY account(:a, :b) AS SELECT BALANCE(:a), IS_CONTRACT(:b);
+"#;
+        assert_eq!(format_query(query), Ok(String::from(formatted)));
+        assert_eq!(format_query(formatted), Ok(String::from(formatted)));
+    }
+
+    #[test]
+    fn refuses_comments() {
+        assert_eq!(
+            format_query("/* the block */ SELECT block.number FROM block"),
+            Err(String::from("Queries with comments can't be formatted"))
+        );
+        assert!(format_query("SELECT").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+const ERC20: &[&str] = &[
+    "function name() view returns (string)",
+    "function symbol() view returns (string)",
+    "function decimals() view returns (uint8)",
+    "function totalSupply() view returns (uint256)",
+    "function balanceOf(address owner) view returns (uint256)",
+    "function allowance(address owner, address spender) view returns (uint256)",
+    "function transfer(address to, uint256 amount) returns (bool)",
+    "function approve(address spender, uint256 amount) returns (bool)",
+    "function transferFrom(address from, address to, uint256 amount) returns (bool)",
+    "event Transfer(address indexed from, address indexed to, uint256 value)",
+    "event Approval(address indexed owner, address indexed spender, uint256 value)",
+];
+
+const ERC721: &[&str] = &[
+    "function name() view returns (string)",
+    "function symbol() view returns (string)",
+    "function tokenURI(uint256 tokenId) view returns (string)",
+    "function balanceOf(address owner) view returns (uint256)",
+    "function ownerOf(uint256 tokenId) view returns (address)",
+    "function getApproved(uint256 tokenId) view returns (address)",
+    "function isApprovedForAll(address owner, address operator) view returns (bool)",
+    "function supportsInterface(bytes4 interfaceId) view returns (bool)",
+    "function approve(address to, uint256 tokenId)",
+    "function setApprovalForAll(address operator, bool approved)",
+    "function transferFrom(address from, address to, uint256 tokenId)",
+    "function safeTransferFrom(address from, address to, uint256 tokenId)",
+    "function safeTransferFrom(address from, address to, uint256 tokenId, bytes data)",
+    "event Transfer(address indexed from, address indexed to, uint256 indexed tokenId)",
+    "event Approval(address indexed owner, address indexed approved, uint256 indexed tokenId)",
+    "event ApprovalForAll(address indexed owner, address indexed operator, bool approved)",
+];
+
+const ERC721_ENUMERABLE: &[&str] = &[
+    "function totalSupply() view returns (uint256)",
+    "function tokenByIndex(uint256 index) view returns (uint256)",
+    "function tokenOfOwnerByIndex(address owner, uint256 index) view returns (uint256)",
+];
+
+const ERC1155: &[&str] = &[
+    "function uri(uint256 id) view returns (string)",
+    "function balanceOf(address account, uint256 id) view returns (uint256)",
+    "function balanceOfBatch(address[] accounts, uint256[] ids) view returns (uint256[])",
+    "function isApprovedForAll(address account, address operator) view returns (bool)",
+    "function supportsInterface(bytes4 interfaceId) view returns (bool)",
+    "function setApprovalForAll(address operator, bool approved)",
+    "function safeTransferFrom(address from, address to, uint256 id, uint256 amount, bytes data)",
+    "function safeBatchTransferFrom(address from, address to, uint256[] ids, uint256[] amounts, bytes data)",
+    "event TransferSingle(address indexed operator, address indexed from, address indexed to, uint256 id, uint256 value)",
+    "event TransferBatch(address indexed operator, address indexed from, address indexed to, uint256[] ids, uint256[] values)",
+    "event ApprovalForAll(address indexed account, address indexed operator, bool approved)",
+    "event URI(string value, uint256 indexed id)",
+];
+
+const ERC4626: &[&str] = &[
+    "function asset() view returns (address)",
+    "function totalAssets() view returns (uint256)",
+    "function convertToShares(uint256 assets) view returns (uint256)",
+    "function convertToAssets(uint256 shares) view returns (uint256)",
+    "function maxDeposit(address receiver) view returns (uint256)",
+    "function previewDeposit(uint256 assets) view returns (uint256)",
+    "function maxMint(address receiver) view returns (uint256)",
+    "function previewMint(uint256 shares) view returns (uint256)",
+    "function maxWithdraw(address owner) view returns (uint256)",
+    "function previewWithdraw(uint256 assets) view returns (uint256)",
+    "function maxRedeem(address owner) view returns (uint256)",
+    "function previewRedeem(uint256 shares) view returns (uint256)",
+    "function deposit(uint256 assets, address receiver) returns (uint256)",
+    "function mint(uint256 shares, address receiver) returns (uint256)",
+    "function withdraw(uint256 assets, address receiver, address owner) returns (uint256)",
+    "function redeem(uint256 shares, address receiver, address owner) returns (uint256)",
+    "event Deposit(address indexed sender, address indexed owner, uint256 assets, uint256 shares)",
+    "event Withdraw(address indexed sender, address indexed receiver, address indexed owner, uint256 assets, uint256 shares)",
+];
+
+const AGGREGATOR_V3: &[&str] = &[
+    "function decimals() view returns (uint8)",
+    "function description() view returns (string)",
+    "function version() view returns (uint256)",
+    "function getRoundData(uint80 roundId) view returns (uint80 roundId, int256 answer, uint256 startedAt, uint256 updatedAt, uint80 answeredInRound)",
+    "function latestRoundData() view returns (uint80 roundId, int256 answer, uint256 startedAt, uint256 updatedAt, uint80 answeredInRound)",
+];
+
+/// Names accepted by `REGISTER CONTRACT ... AS <name>`, matched case-insensitively.
+pub const BUILTIN_INTERFACES: &[&str] = &[
+    "ERC20",
+    "ERC721",
+    "ERC721Enumerable",
+    "ERC1155",
+    "ERC4626",
+    "AggregatorV3Interface",
+];
+
+pub fn builtin_interface(name: &str) -> Option<Vec<&'static str>> {
+    let parts: Vec<&[&str]> = match name.to_uppercase().as_str() {
+        "ERC20" => vec![ERC20],
+        "ERC721" => vec![ERC721],
+        "ERC721ENUMERABLE" => vec![ERC721, ERC721_ENUMERABLE],
+        "ERC1155" => vec![ERC1155],
+        "ERC4626" => vec![ERC20, ERC4626],
+        "AGGREGATORV3INTERFACE" => vec![AGGREGATOR_V3],
+        _ => return None,
+    };
+    Some(parts.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interfaces::{builtin_interface, BUILTIN_INTERFACES};
+
+    #[test]
+    fn builtin_interfaces_parse() {
+        for name in BUILTIN_INTERFACES {
+            let interface = builtin_interface(name).unwrap();
+            assert!(
+                ethers::abi::parse_abi(&interface).is_ok(),
+                "failed to parse {}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn builtin_interface_case_insensitive() {
+        assert_eq!(builtin_interface("erc20"), builtin_interface("ERC20"));
+        assert_eq!(builtin_interface("ERC42"), None);
+    }
+
+    #[test]
+    fn builtin_interface_extends() {
+        let interface = builtin_interface("ERC4626").unwrap();
+        assert!(interface.contains(&"function decimals() view returns (uint8)"));
+        assert!(interface.contains(&"function asset() view returns (address)"));
+    }
+}
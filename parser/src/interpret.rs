@@ -0,0 +1,518 @@
+use crate::abi::{default_token, min_encoded_size};
+use crate::compile::compile;
+use crate::evm::assemble;
+use crate::executor::CallResult;
+use crate::ir::{lower, Call, Expr, Failure, Plan, Value};
+use crate::parse::parse_query_cls;
+use crate::resolve::{resolve_entries, settings};
+use crate::revert::describe_revert;
+use crate::sleuth::{decode_result, encode_call, find_query};
+use crate::source::{get_all_sources, Account, SourceIndex};
+use crate::yul_ast::{Block, Object};
+use crate::yul_parse::parse_yul;
+use ethers::abi::{self, Address, ParamType, Token};
+use ethers::types::U256;
+use ethers::utils::{hex, id, keccak256};
+use std::collections::{BTreeMap, HashMap};
+
+/// What a mock contract's function does when called.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockResult {
+    /// Returns this data.
+    Return(Vec<u8>),
+    /// Reverts with this data.
+    Revert(Vec<u8>),
+}
+
+/// A contract which answers calls with canned results, chosen by the calldata's selector
+/// alone. Calls to any other function revert without data.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MockContract {
+    functions: BTreeMap<[u8; 4], MockResult>,
+}
+
+impl MockContract {
+    pub fn new() -> Self {
+        MockContract::default()
+    }
+
+    /// Has the function with `signature`, e.g. `balanceOf(address)`, return `tokens`.
+    pub fn returns(mut self, signature: &str, tokens: &[Token]) -> Self {
+        let data = abi::encode(tokens);
+        self.functions
+            .insert(id(signature), MockResult::Return(data));
+        self
+    }
+
+    /// Has the function with `signature` revert with `data`.
+    pub fn reverts(mut self, signature: &str, data: &[u8]) -> Self {
+        self.functions
+            .insert(id(signature), MockResult::Revert(data.to_vec()));
+        self
+    }
+
+    fn answer(&self, calldata: &[u8]) -> Option<&MockResult> {
+        let mut selector = [0; 4];
+        let len = calldata.len().min(4);
+        selector[..len].copy_from_slice(&calldata[..len]);
+        self.functions.get(&selector)
+    }
+
+    /// Runtime bytecode which behaves as the mock does, so that it can be installed in an EVM.
+    pub fn code(&self) -> Result<Vec<u8>, String> {
+        let mut yul = String::from("switch shr(224, calldataload(0))\n");
+        for (selector, result) in &self.functions {
+            let (data, exit) = match result {
+                MockResult::Return(data) => (data, "return"),
+                MockResult::Revert(data) => (data, "revert"),
+            };
+            yul.push_str(&format!("case 0x{} {{\n", hex::encode(selector)));
+            for (i, word) in data.chunks(32).enumerate() {
+                let mut padded = word.to_vec();
+                padded.resize(32, 0);
+                yul.push_str(&format!("mstore({}, 0x{})\n", i * 32, hex::encode(padded)));
+            }
+            yul.push_str(&format!("{}(0, {})\n}}\n", exit, data.len()));
+        }
+        yul.push_str("default {\nrevert(0, 0)\n}\n");
+        let object = Object {
+            name: String::from("Mock"),
+            code: Block(parse_yul(&yul)?),
+            objects: vec![],
+            data: vec![],
+        };
+        Ok(assemble(&object)?.bytecode)
+    }
+}
+
+/// Chain state for the interpreter: balances and mock contracts at the given block. Every
+/// other account is empty.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MockChain {
+    pub block_number: u64,
+    pub balances: HashMap<Address, U256>,
+    pub contracts: HashMap<Address, MockContract>,
+}
+
+// What a call left behind: whether it succeeded, and its returndata or revert data.
+struct Called {
+    ok: bool,
+    data: Vec<u8>,
+}
+
+impl MockChain {
+    pub fn new(block_number: u64) -> Self {
+        MockChain {
+            block_number,
+            ..MockChain::default()
+        }
+    }
+
+    pub fn with_balance(mut self, address: Address, balance: U256) -> Self {
+        self.balances.insert(address, balance);
+        self
+    }
+
+    pub fn with_contract(mut self, address: Address, contract: MockContract) -> Self {
+        self.contracts.insert(address, contract);
+        self
+    }
+
+    fn balance(&self, address: &Address) -> U256 {
+        self.balances.get(address).copied().unwrap_or_default()
+    }
+
+    fn code(&self, address: &Address) -> Result<Vec<u8>, String> {
+        match self.contracts.get(address) {
+            Some(contract) => contract.code(),
+            None => Ok(vec![]),
+        }
+    }
+
+    // As `extcodehash`: zero for an empty account, and the hash of its code otherwise.
+    fn code_hash(&self, address: &Address) -> Result<[u8; 32], String> {
+        let code = self.code(address)?;
+        if code.is_empty() && self.balance(address).is_zero() {
+            Ok([0; 32])
+        } else {
+            Ok(keccak256(code))
+        }
+    }
+
+    // Calls without code succeed and return nothing, as they do on chain.
+    fn call(&self, call: &Call) -> Called {
+        let answer = self
+            .contracts
+            .get(&call.address)
+            .map(|contract| contract.answer(&call.calldata));
+        match answer {
+            None => Called {
+                ok: true,
+                data: vec![],
+            },
+            Some(Some(MockResult::Return(data))) => Called {
+                ok: true,
+                data: data.clone(),
+            },
+            Some(Some(MockResult::Revert(data))) => Called {
+                ok: false,
+                data: data.clone(),
+            },
+            Some(None) => Called {
+                ok: false,
+                data: vec![],
+            },
+        }
+    }
+
+    /// Runs the named query against the mocks, returning its columns, or a description of why
+    /// it reverted, as the compiled query would when run through an `Executor`.
+    pub fn query(&self, query: &str, name: &str, args: &[Address]) -> Result<Vec<Token>, String> {
+        let compiled = compile(query)?;
+        let function = find_query(&compiled, name)?;
+        // Checks the arguments as encoding the call would.
+        encode_call(function, args)?;
+        match interpret(&plan(query, name)?, self, args)? {
+            CallResult::Success(data) => decode_result(function, &data),
+            CallResult::Revert(data) => Err(describe_revert(&compiled, &data)?),
+        }
+    }
+}
+
+/// The plan of the named query, before any passes run, which is what the interpreter runs.
+pub fn plan(query: &str, name: &str) -> Result<Plan, String> {
+    let query_set = parse_query_cls(query)?;
+    let settings = settings(&query_set);
+    let all_sources = get_all_sources(&query_set)?;
+    let entry = resolve_entries(&query_set, &SourceIndex::new(&all_sources))?
+        .into_iter()
+        .find(|entry| entry.name == name)
+        .ok_or_else(|| format!("Query does not define {}", name))?;
+    lower(entry.resolutions, &settings)
+}
+
+fn argument(args: &[Address], index: usize) -> Result<Address, String> {
+    args.get(index)
+        .copied()
+        .ok_or_else(|| format!("Missing argument for parameter {}", index))
+}
+
+fn account(account: &Account, args: &[Address]) -> Result<Address, String> {
+    match account {
+        Account::Address(address) => Ok(*address),
+        Account::Param(index) => argument(args, *index),
+    }
+}
+
+// As `tryCall`: a call which returns too little to decode has failed.
+fn try_call(chain: &MockChain, call: &Call) -> Called {
+    let mut called = chain.call(call);
+    if called.data.len() < min_encoded_size(&call.returns) {
+        called.ok = false;
+    }
+    called
+}
+
+fn decode_returndata(ty: &ParamType, data: &[u8]) -> Result<Token, String> {
+    let decoded = match ty {
+        ParamType::Tuple(types) => abi::decode(types, data).map(Token::Tuple),
+        ty => abi::decode(std::slice::from_ref(ty), data).map(|mut tokens| tokens.remove(0)),
+    };
+    decoded.map_err(|e| format!("Can't decode 0x{} as {}: {}", hex::encode(data), ty, e))
+}
+
+// As `safeString`: a `string`, or a `bytes32` up to its first zero byte, or otherwise empty.
+fn safe_string(called: &Called) -> Vec<u8> {
+    let data = &called.data;
+    let word = |offset: usize| U256::from_big_endian(&data[offset..offset + 32]);
+    if !called.ok {
+        return vec![];
+    }
+    if data.len() == 32 {
+        return data.iter().take_while(|b| **b != 0).copied().collect();
+    }
+    if data.len() < 64 {
+        return vec![];
+    }
+    let offset = word(0);
+    if offset > U256::from(data.len() - 32) {
+        return vec![];
+    }
+    let start = offset.as_usize() + 32;
+    let len = word(offset.as_usize());
+    if len > U256::from(data.len() - start) {
+        return vec![];
+    }
+    data[start..start + len.as_usize()].to_vec()
+}
+
+fn evaluate(value: &Value, chain: &MockChain, args: &[Address]) -> Result<Token, String> {
+    Ok(match value {
+        Value::BlockNumber => Token::Uint(chain.block_number.into()),
+        Value::Balance(a) => Token::Uint(chain.balance(&account(a, args)?)),
+        Value::CodeSize(a) => Token::Uint(chain.code(&account(a, args)?)?.len().into()),
+        Value::CodeHash(a) => Token::FixedBytes(chain.code_hash(&account(a, args)?)?.to_vec()),
+        Value::IsContract(a) => Token::Bool(!chain.code(&account(a, args)?)?.is_empty()),
+        Value::Param(index) => Token::Address(argument(args, *index)?),
+        // `string` and `bytes` encode alike, and the bytes needn't be UTF-8.
+        Value::SafeString(call) => Token::Bytes(safe_string(&chain.call(call))),
+        Value::Call(_) => Err(String::from(
+            "A contract call must be read through its result",
+        ))?,
+    })
+}
+
+fn contract_call(value: &Value) -> Result<&Call, String> {
+    match value {
+        Value::Call(call) => Ok(call),
+        value => Err(format!("Expected a contract call, got {:?}", value)),
+    }
+}
+
+fn call_failed(column: usize, data: &[u8]) -> Vec<u8> {
+    [
+        id("CallFailed(uint256,bytes)").to_vec(),
+        abi::encode(&[Token::Uint(column.into()), Token::Bytes(data.to_vec())]),
+    ]
+    .concat()
+}
+
+/// Runs a plan directly against mock state, returning what its compiled `query()` would:
+/// its ABI-encoded columns, or its revert data. Gas limits are ignored, since mocks don't
+/// spend gas.
+pub fn interpret(plan: &Plan, chain: &MockChain, args: &[Address]) -> Result<CallResult, String> {
+    let mut tokens = vec![];
+    for (index, column) in plan.columns.iter().enumerate() {
+        let token = match &column.expr {
+            Expr::Const(token) => token.clone(),
+            Expr::Value(value) => evaluate(&plan.values[*value], chain, args)?,
+            Expr::Try(value) => {
+                let call = contract_call(&plan.values[*value])?;
+                let called = try_call(chain, call);
+                let result = if called.ok {
+                    decode_returndata(&call.returns, &called.data)?
+                } else {
+                    default_token(&call.returns)
+                };
+                Token::Tuple(vec![Token::Bool(called.ok), result])
+            }
+            expr => {
+                let (value, failure) = expr
+                    .call_result()
+                    .ok_or_else(|| format!("Unsupported column expression {:?}", expr))?;
+                let call = contract_call(&plan.values[value])?;
+                let called = try_call(chain, call);
+                match failure {
+                    _ if called.ok => decode_returndata(&call.returns, &called.data)?,
+                    Failure::Zero => default_token(&call.returns),
+                    Failure::Default(token) => token,
+                    Failure::Revert => {
+                        return Ok(CallResult::Revert(call_failed(index, &called.data)))
+                    }
+                }
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(CallResult::Success(abi::encode(&tokens)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::compile;
+    use crate::executor::{BlockTag, CallRequest, CallResult, Provider};
+    use crate::interpret::{interpret, plan, MockChain, MockContract};
+    use crate::local::LocalChain;
+    use ethers::abi::{Address, Token};
+    use ethers::types::U256;
+    use ethers::utils::{hex, id};
+
+    const COMET: &str = r#"
+        REGISTER CONTRACT comet AT 0x1111111111111111111111111111111111111111 WITH INTERFACE [
+            "function totalSupply() view returns (uint256)",
+            "function name() view returns (string)",
+            "function symbol() view returns (bytes32)",
+            "error Paused()"
+        ];
+    "#;
+
+    fn comet() -> Address {
+        Address::repeat_byte(0x11)
+    }
+
+    fn word(n: u64) -> Token {
+        Token::Uint(n.into())
+    }
+
+    fn bytes32(s: &str) -> Token {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.resize(32, 0);
+        Token::FixedBytes(bytes)
+    }
+
+    #[test]
+    fn mocked_calls() {
+        let query = format!(
+            "{} SELECT comet.totalSupply, TRY(comet.totalSupply), COALESCE(comet.totalSupply, 7) FROM comet",
+            COMET
+        );
+        let cases = vec![
+            (
+                MockContract::new().returns("totalSupply()", &[word(5)]),
+                vec![
+                    Token::Tuple(vec![word(5)]),
+                    Token::Tuple(vec![Token::Bool(true), Token::Tuple(vec![word(5)])]),
+                    Token::Tuple(vec![word(5)]),
+                ],
+            ),
+            (
+                MockContract::new().reverts("totalSupply()", &id("Paused()")),
+                vec![
+                    Token::Tuple(vec![word(0)]),
+                    Token::Tuple(vec![Token::Bool(false), Token::Tuple(vec![word(0)])]),
+                    Token::Tuple(vec![word(7)]),
+                ],
+            ),
+        ];
+        for (contract, columns) in cases {
+            let chain = MockChain::new(1).with_contract(comet(), contract);
+            assert_eq!(chain.query(&query, "query", &[]), Ok(columns));
+        }
+    }
+
+    #[test]
+    fn strict_failures() {
+        let query = format!(
+            "SET STRICT; {} SELECT 5, comet.totalSupply FROM comet",
+            COMET
+        );
+        let chain = MockChain::new(1).with_contract(
+            comet(),
+            MockContract::new().reverts("totalSupply()", &id("Paused()")),
+        );
+        assert_eq!(
+            chain.query(&query, "query", &[]),
+            Err(String::from(
+                "column 1 (`comet.totalSupply`) reverted: Paused()"
+            ))
+        );
+        // Without code, the call succeeds but returns nothing.
+        assert_eq!(
+            MockChain::new(1).query(&query, "query", &[]),
+            Err(String::from(
+                "column 1 (`comet.totalSupply`) reverted: reverted without data"
+            ))
+        );
+    }
+
+    #[test]
+    fn accounts_and_params() {
+        let query =
+            "QUERY account(:a) AS SELECT :a, BALANCE(:a), IS_CONTRACT(:a), block.number FROM block";
+        let chain = MockChain::new(16)
+            .with_balance(Address::repeat_byte(0x22), U256::from(1000))
+            .with_contract(comet(), MockContract::new());
+        assert_eq!(
+            chain.query(query, "account", &[Address::repeat_byte(0x22)]),
+            Ok(vec![
+                Token::Address(Address::repeat_byte(0x22)),
+                word(1000),
+                Token::Bool(false),
+                word(16),
+            ])
+        );
+        assert_eq!(
+            chain.query(query, "account", &[comet()]),
+            Ok(vec![
+                Token::Address(comet()),
+                word(0),
+                Token::Bool(true),
+                word(16)
+            ])
+        );
+        assert_eq!(
+            chain.query(query, "account", &[]),
+            Err(String::from(
+                "account(address) expects 1 argument(s), got 0"
+            ))
+        );
+    }
+
+    // Runs each query against each chain both in the interpreter and, compiled, in an EVM,
+    // expecting exactly the same returndata or revert data.
+    #[test]
+    fn matches_compiled_queries() {
+        let queries = vec![
+            format!(
+                "{} SELECT comet.totalSupply, TRY(comet.name), COALESCE(comet.totalSupply, 7) FROM comet",
+                COMET
+            ),
+            format!(
+                "{} SELECT SAFE_STRING(comet.name), SAFE_STRING(comet.symbol), comet.name FROM comet",
+                COMET
+            ),
+            // Defaults the compiled query folds away, since a failed call gives them anyway.
+            format!(
+                "{} SELECT COALESCE(comet.totalSupply, 0), COALESCE(comet.name, \"\"), 5, \"cat\" FROM comet",
+                COMET
+            ),
+            format!(
+                "SET STRICT; {} SELECT comet.name, comet.totalSupply FROM comet",
+                COMET
+            ),
+            format!(
+                "SELECT BALANCE({0}), CODESIZE({0}), CODEHASH({0}), IS_CONTRACT({0})",
+                "0x1111111111111111111111111111111111111111"
+            ),
+            format!(
+                "SELECT BALANCE({0}), CODEHASH({0}), CODEHASH({1}), block.number FROM block",
+                "0x2222222222222222222222222222222222222222", "0x3333333333333333333333333333333333333333"
+            ),
+        ];
+        let full = MockContract::new()
+            .returns("totalSupply()", &[word(1_000_000)])
+            .returns("name()", &[Token::String(String::from("Compound USDC"))])
+            .returns("symbol()", &[bytes32("cUSDCv3")]);
+        let reverting = MockContract::new()
+            .reverts("totalSupply()", &id("Paused()"))
+            .returns("name()", &[Token::String(String::from("x").repeat(40))]);
+        let chains = vec![
+            MockChain::new(16).with_contract(comet(), full),
+            MockChain::new(17)
+                .with_contract(comet(), reverting)
+                .with_balance(comet(), U256::from(5))
+                .with_balance(Address::repeat_byte(0x22), U256::from(7)),
+            MockChain::new(18),
+        ];
+        for query in &queries {
+            let bytecode = hex::decode(&compile(query).unwrap().bytecode[2..]).unwrap();
+            let plan = plan(query, "query").unwrap();
+            for chain in &chains {
+                let local = LocalChain::from_mock(chain).unwrap();
+                let selector = id("query()");
+                assert_eq!(
+                    interpret(&plan, chain, &[]),
+                    local.run(&bytecode, &selector),
+                    "{} on {:?}",
+                    query,
+                    chain
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mock_code() {
+        let contract = MockContract::new().returns("totalSupply()", &[word(5)]);
+        let local =
+            LocalChain::from_mock(&MockChain::new(1).with_contract(comet(), contract)).unwrap();
+        let request = CallRequest {
+            to: Some(comet()),
+            data: id("totalSupply()").to_vec(),
+        };
+        assert_eq!(
+            local.call(&request, &BlockTag::Latest),
+            Ok(CallResult::Success(ethers::abi::encode(&[word(5)])))
+        );
+    }
+}
@@ -0,0 +1,340 @@
+use crate::abi::field_param;
+use crate::query::Span;
+use crate::resolve::{Resolution, Settings};
+use crate::source::{Account, DataSource};
+use ethers::abi::{ParamType, StateMutability, Token};
+use ethers::types::{Address, Bytes};
+
+/// Index of a value in `Plan::values`.
+pub type ValueId = usize;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call {
+    pub address: Address,
+    pub calldata: Bytes,
+    /// The function's output tuple.
+    pub returns: ParamType,
+    pub mutability: StateMutability,
+    pub gas: Option<u64>,
+}
+
+impl Call {
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.mutability, StateMutability::View | StateMutability::Pure)
+    }
+}
+
+/// Something the query reads: chain state, a parameter, or the outcome of a contract call.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    BlockNumber,
+    Balance(Account),
+    CodeSize(Account),
+    CodeHash(Account),
+    IsContract(Account),
+    /// A named query's parameter, read from calldata.
+    Param(usize),
+    /// Makes a contract call. Its result is read by `Expr::Result` and `Expr::Try`.
+    Call(Call),
+    /// Makes a contract call and decodes its result as a `string` or `bytes32`.
+    SafeString(Call),
+}
+
+/// What a column holds when its call fails.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Failure {
+    /// The zero value of the call's return type.
+    Zero,
+    /// The given value, encoded as the call's return type.
+    Default(Token),
+    /// Nothing: the whole query reverts, naming the column.
+    Revert,
+}
+
+/// How a column is computed from the values the query reads.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    /// A literal, e.g. `5` or `"cat"`.
+    Const(Token),
+    Value(ValueId),
+    /// The decoded result of a call, or the zero value of its return type if it failed.
+    Result(ValueId),
+    /// `(bool ok, result)` for a call, with a zero result if it failed.
+    Try(ValueId),
+    /// `then` if the call succeeded, and `otherwise` if it failed.
+    IfOk {
+        call: ValueId,
+        then: Box<Expr>,
+        otherwise: Box<Expr>,
+    },
+    /// Reverts the whole query, naming the column.
+    Revert,
+}
+
+impl Expr {
+    /// The call's result if it succeeded, otherwise `otherwise`.
+    pub fn if_ok(call: ValueId, otherwise: Expr) -> Expr {
+        Expr::IfOk {
+            call,
+            then: Box::new(Expr::Result(call)),
+            otherwise: Box::new(otherwise),
+        }
+    }
+
+    /// The values the expression reads, each once.
+    pub fn values(&self) -> Vec<ValueId> {
+        let mut values = vec![];
+        self.visit(&mut |value| {
+            if !values.contains(&value) {
+                values.push(value);
+            }
+        });
+        values
+    }
+
+    fn visit(&self, f: &mut impl FnMut(ValueId)) {
+        match self {
+            Expr::Const(_) | Expr::Revert => {}
+            Expr::Value(value) | Expr::Result(value) | Expr::Try(value) => f(*value),
+            Expr::IfOk {
+                call,
+                then,
+                otherwise,
+            } => {
+                f(*call);
+                then.visit(f);
+                otherwise.visit(f);
+            }
+        }
+    }
+
+    /// Replaces each value the expression reads with `f` of it.
+    pub fn renumber(&mut self, f: &impl Fn(ValueId) -> ValueId) {
+        match self {
+            Expr::Const(_) | Expr::Revert => {}
+            Expr::Value(value) | Expr::Result(value) | Expr::Try(value) => *value = f(*value),
+            Expr::IfOk {
+                call,
+                then,
+                otherwise,
+            } => {
+                *call = f(*call);
+                then.renumber(f);
+                otherwise.renumber(f);
+            }
+        }
+    }
+
+    /// The call whose result the expression is, and what it holds instead if the call fails:
+    /// for a call's result, or a branch on a call between its result and a constant or a
+    /// revert. These are the branches backends compile.
+    pub fn call_result(&self) -> Option<(ValueId, Failure)> {
+        match self {
+            Expr::Result(value) => Some((*value, Failure::Zero)),
+            Expr::IfOk {
+                call,
+                then,
+                otherwise,
+            } if **then == Expr::Result(*call) => match &**otherwise {
+                Expr::Const(token) => Some((*call, Failure::Default(token.clone()))),
+                Expr::Revert => Some((*call, Failure::Revert)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column {
+    pub name: Option<String>,
+    pub source: Option<String>,
+    pub ty: ParamType,
+    pub expr: Expr,
+    pub span: Option<Span>,
+}
+
+/// A query plan: the values a query reads and the columns it returns from them, in order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    pub values: Vec<Value>,
+    pub columns: Vec<Column>,
+    pub gas_budget: Option<u64>,
+}
+
+impl Plan {
+    /// How many columns use each value.
+    pub fn uses(&self) -> Vec<usize> {
+        let mut uses = vec![0; self.values.len()];
+        for column in &self.columns {
+            for value in column.expr.values() {
+                uses[value] += 1;
+            }
+        }
+        uses
+    }
+}
+
+fn lower_call(data_source: &DataSource, gas: Option<u64>) -> Result<Call, String> {
+    match data_source {
+        DataSource::Call(address, calldata, abi, mutability) => Ok(Call {
+            address: *address,
+            calldata: calldata.clone(),
+            returns: field_param(abi).clone(),
+            mutability: *mutability,
+            gas,
+        }),
+        ds => Err(format!("Expected a contract call, got {:?}", ds)),
+    }
+}
+
+/// Lowers resolved columns into a plan with a value for each column which reads one.
+pub fn lower(resolutions: Vec<Resolution>, settings: &Settings) -> Result<Plan, String> {
+    let mut values = vec![];
+    let mut columns = vec![];
+    for resolution in resolutions {
+        let gas = resolution.gas;
+        let id = values.len();
+        let (value, expr) = match &resolution.data_source {
+            DataSource::Number(n) => (None, Expr::Const(Token::Uint((*n).into()))),
+            DataSource::String(s) => (None, Expr::Const(Token::String(s.clone()))),
+            DataSource::BlockNumber => (Some(Value::BlockNumber), Expr::Value(id)),
+            DataSource::Balance(address) => (Some(Value::Balance(*address)), Expr::Value(id)),
+            DataSource::CodeSize(address) => (Some(Value::CodeSize(*address)), Expr::Value(id)),
+            DataSource::CodeHash(address) => (Some(Value::CodeHash(*address)), Expr::Value(id)),
+            DataSource::IsContract(address) => (Some(Value::IsContract(*address)), Expr::Value(id)),
+            DataSource::Param(i) => (Some(Value::Param(*i)), Expr::Value(id)),
+            DataSource::SafeString(call) => {
+                let call = lower_call(call, gas)
+                    .map_err(|_| format!("SAFE_STRING requires a contract call, got {:?}", call))?;
+                (Some(Value::SafeString(call)), Expr::Value(id))
+            }
+            call @ DataSource::Call(..) => {
+                let expr = if settings.strict {
+                    Expr::if_ok(id, Expr::Revert)
+                } else {
+                    Expr::Result(id)
+                };
+                (Some(Value::Call(lower_call(call, gas)?)), expr)
+            }
+            DataSource::Try(call) => (Some(Value::Call(lower_call(call, gas)?)), Expr::Try(id)),
+            DataSource::Coalesce(call, token) => (
+                Some(Value::Call(lower_call(call, gas)?)),
+                Expr::if_ok(id, Expr::Const(token.clone())),
+            ),
+        };
+        values.extend(value);
+        columns.push(Column {
+            name: resolution.name,
+            source: resolution.source,
+            ty: field_param(&resolution.abi).clone(),
+            expr,
+            span: resolution.span,
+        });
+    }
+    Ok(Plan {
+        values,
+        columns,
+        gas_budget: settings.gas_budget,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{lower, Call, Expr, Failure, Value};
+    use crate::resolve::{Resolution, Settings};
+    use crate::source::DataSource;
+    use ethers::abi::{struct_def::FieldType, ParamType, StateMutability, Token};
+    use ethers::types::{Bytes, H160};
+
+    fn total_supply() -> DataSource {
+        DataSource::Call(
+            H160::repeat_byte(0x11),
+            Bytes::from([0x18, 0x16, 0x0d, 0xdd]),
+            FieldType::Elementary(ParamType::Tuple(vec![ParamType::Uint(256)])),
+            StateMutability::View,
+        )
+    }
+
+    fn resolution(data_source: DataSource, gas: Option<u64>) -> Resolution {
+        Resolution {
+            name: None,
+            source: None,
+            abi: data_source.abi(),
+            data_source,
+            gas,
+            span: None,
+        }
+    }
+
+    #[test]
+    fn lower_columns() {
+        let fallback = Token::Tuple(vec![Token::Uint(7.into())]);
+        let settings = Settings {
+            strict: true,
+            gas_budget: Some(1000000),
+            ..Settings::default()
+        };
+        let plan = lower(
+            vec![
+                resolution(DataSource::Number(5), None),
+                resolution(total_supply(), Some(50000)),
+                resolution(DataSource::Try(Box::new(total_supply())), None),
+                resolution(
+                    DataSource::Coalesce(Box::new(total_supply()), fallback.clone()),
+                    None,
+                ),
+            ],
+            &settings,
+        )
+        .unwrap();
+        let call = |gas| {
+            Value::Call(Call {
+                address: H160::repeat_byte(0x11),
+                calldata: Bytes::from([0x18, 0x16, 0x0d, 0xdd]),
+                returns: ParamType::Tuple(vec![ParamType::Uint(256)]),
+                mutability: StateMutability::View,
+                gas,
+            })
+        };
+        assert_eq!(plan.values, vec![call(Some(50000)), call(None), call(None)]);
+        assert_eq!(
+            plan.columns.iter().map(|c| c.expr.clone()).collect::<Vec<Expr>>(),
+            vec![
+                Expr::Const(Token::Uint(5.into())),
+                Expr::if_ok(0, Expr::Revert),
+                Expr::Try(1),
+                Expr::if_ok(2, Expr::Const(fallback.clone())),
+            ]
+        );
+        assert_eq!(
+            plan.columns
+                .iter()
+                .map(|c| c.expr.call_result())
+                .collect::<Vec<_>>(),
+            vec![
+                None,
+                Some((0, Failure::Revert)),
+                None,
+                Some((2, Failure::Default(fallback))),
+            ]
+        );
+        assert_eq!(plan.uses(), vec![1, 1, 1]);
+        assert_eq!(plan.gas_budget, Some(1000000));
+    }
+
+    #[test]
+    fn expr_values() {
+        let mut expr = Expr::IfOk {
+            call: 2,
+            then: Box::new(Expr::Result(2)),
+            otherwise: Box::new(Expr::Value(0)),
+        };
+        assert_eq!(expr.values(), vec![2, 0]);
+        assert_eq!(expr.call_result(), None);
+        expr.renumber(&|value| value + 1);
+        assert_eq!(expr.values(), vec![3, 1]);
+        assert_eq!(Expr::Result(3).call_result(), Some((3, Failure::Zero)));
+        assert!(Expr::Const(Token::Bool(true)).values().is_empty());
+    }
+}
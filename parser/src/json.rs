@@ -0,0 +1,122 @@
+use crate::abi::parse_type;
+use crate::compile::Column;
+use ethers::abi::{self, ParamType, Token};
+use ethers::types::I256;
+use ethers::utils::{hex, to_checksum};
+use serde_json::{Map, Value};
+
+/// A decoded value as JSON: integers as decimal strings, since they may not fit in a double,
+/// addresses checksummed, bytes as `0x`-prefixed hex, and arrays and tuples as arrays.
+pub fn token_json(token: &Token) -> Value {
+    match token {
+        Token::Address(address) => Value::String(to_checksum(address, None)),
+        Token::Uint(value) => Value::String(value.to_string()),
+        Token::Int(value) => Value::String(I256::from_raw(*value).to_string()),
+        Token::Bool(value) => Value::Bool(*value),
+        Token::String(value) => Value::String(value.clone()),
+        Token::Bytes(value) | Token::FixedBytes(value) => {
+            Value::String(format!("0x{}", hex::encode(value)))
+        }
+        Token::Array(tokens) | Token::FixedArray(tokens) | Token::Tuple(tokens) => {
+            Value::Array(tokens.iter().map(token_json).collect())
+        }
+    }
+}
+
+/// Decodes what a query returned into a JSON object with a field per column. Columns are
+/// keyed by name, or by index when they're unnamed or share a name with another column.
+pub fn decode_json(columns: &[Column], return_data: &[u8]) -> Result<Value, String> {
+    let types = columns
+        .iter()
+        .map(|column| parse_type(&column.ty))
+        .collect::<Result<Vec<ParamType>, String>>()?;
+    let tokens =
+        abi::decode(&types, return_data).map_err(|e| format!("Invalid query result: {}", e))?;
+    let mut fields = Map::new();
+    for (index, (column, token)) in columns.iter().zip(tokens.iter()).enumerate() {
+        let key = match &column.name {
+            Some(name) if columns.iter().filter(|c| c.name == column.name).count() == 1 => {
+                name.clone()
+            }
+            _ => index.to_string(),
+        };
+        fields.insert(key, token_json(token));
+    }
+    Ok(Value::Object(fields))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::compile;
+    use crate::json::{decode_json, token_json};
+    use ethers::abi::{self, Token};
+    use ethers::types::{Address, I256, U256};
+    use serde_json::json;
+
+    #[test]
+    fn tokens_as_json() {
+        assert_eq!(
+            token_json(&Token::Uint(U256::MAX)),
+            json!("115792089237316195423570985008687907853269984665640564039457584007913129639935")
+        );
+        assert_eq!(
+            token_json(&Token::Int(I256::from(-5).into_raw())),
+            json!("-5")
+        );
+        assert_eq!(
+            token_json(&Token::Address(
+                "0xc3d688b66703497daa19211eedff47f25384cdc3"
+                    .parse::<Address>()
+                    .unwrap()
+            )),
+            json!("0xc3d688B66703497DAA19211EEdff47f25384cdc3")
+        );
+        assert_eq!(
+            token_json(&Token::Tuple(vec![
+                Token::Bool(true),
+                Token::Bytes(vec![0xab, 0xcd]),
+                Token::Array(vec![Token::String(String::from("cat"))]),
+            ])),
+            json!([true, "0xabcd", ["cat"]])
+        );
+    }
+
+    #[test]
+    fn decode_query_result() {
+        let compiled = compile(
+            r###"
+            REGISTER CONTRACT comet AT 0xc3d688B66703497DAA19211EEdff47f25384cdc3 WITH INTERFACE [
+                "function totalSupply() view returns (uint256)",
+                "function name() view returns (string)"
+            ];
+            SELECT comet.totalSupply, TRY(comet.name), 5, comet.totalSupply FROM comet;
+            "###,
+        )
+        .unwrap();
+        let data = abi::encode(&[
+            Token::Tuple(vec![Token::Uint(1000.into())]),
+            Token::Tuple(vec![
+                Token::Bool(true),
+                Token::Tuple(vec![Token::String(String::from("Compound USDC"))]),
+            ]),
+            Token::Uint(5.into()),
+            Token::Tuple(vec![Token::Uint(1000.into())]),
+        ]);
+        let decoded = decode_json(&compiled.columns, &data).unwrap();
+        assert_eq!(
+            decoded,
+            json!({
+                "0": ["1000"],
+                "name": [true, ["Compound USDC"]],
+                "2": "5",
+                "3": ["1000"],
+            })
+        );
+        // Fields keep the order of the columns.
+        assert_eq!(
+            decoded.to_string(),
+            r#"{"0":["1000"],"name":[true,["Compound USDC"]],"2":"5","3":["1000"]}"#
+        );
+        assert!(decode_json(&compiled.columns, &[]).is_err());
+    }
+}
@@ -8,6 +8,25 @@ mod resolve;
 mod abi;
 mod yul;
 mod source;
+mod interfaces;
+pub mod compile;
+mod revert;
+mod ir;
+mod passes;
+mod yul_ast;
+mod yul_parse;
+mod evm;
+mod estimate;
+mod multicall;
+mod json;
+pub mod format;
+pub mod explain;
+pub mod executor;
+pub mod interpret;
+pub mod session;
+pub mod sleuth;
+#[cfg(any(test, feature = "revm"))]
+pub mod local;
 
 use wasm_bindgen::prelude::*;
 
@@ -18,11 +37,91 @@ use wasm_bindgen::prelude::*;
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
 #[wasm_bindgen]
-pub fn parse(query: String) -> Result<String, String> { 
+pub fn parse(query: String) -> Result<String, String> {
+    utils::set_panic_hook();
     let query_set = parse::parse_query_cls(&query)?;
+    let settings = resolve::settings(&query_set);
     let resolutions = resolve::resolve(&query_set)?;
     let tuple = abi::get_tuple_abi(&resolutions);
-    let yul = yul::derive_yul(resolutions)?;
+    let plan = passes::PassManager::default().run(ir::lower(resolutions, &settings)?)?;
+    let yul = yul::derive_yul(&plan)?;
 
     Ok(format!("{};{}", tuple, yul))
 }
+
+#[wasm_bindgen]
+pub fn compile(query: String) -> Result<String, String> {
+    utils::set_panic_hook();
+    let compiled = compile::compile(&query)?;
+    serde_json::to_string(&compiled).map_err(|e| format!("Error serializing compiled query: {}", e))
+}
+
+#[wasm_bindgen]
+pub fn compile_multicall(query: String) -> Result<String, String> {
+    utils::set_panic_hook();
+    let multicall = compile::compile_multicall(&query)?;
+    serde_json::to_string(&multicall)
+        .map_err(|e| format!("Error serializing Multicall3 query: {}", e))
+}
+
+#[wasm_bindgen]
+pub fn decode_multicall(multicall: String, data: String) -> Result<String, String> {
+    utils::set_panic_hook();
+    let multicall: multicall::Multicall = serde_json::from_str(&multicall)
+        .map_err(|e| format!("Invalid Multicall3 query: {}", e))?;
+    let data = ethers::utils::hex::decode(data.trim_start_matches("0x"))
+        .map_err(|e| format!("Invalid aggregate3 result: {}", e))?;
+    let decoded = multicall::decode(&multicall, &data)?;
+    Ok(format!("0x{}", ethers::utils::hex::encode(decoded)))
+}
+
+#[wasm_bindgen]
+pub fn decode(compiled: String, data: String) -> Result<String, String> {
+    utils::set_panic_hook();
+    let compiled: compile::Compiled = serde_json::from_str(&compiled)
+        .map_err(|e| format!("Invalid compiled query: {}", e))?;
+    let data = ethers::utils::hex::decode(data.trim_start_matches("0x"))
+        .map_err(|e| format!("Invalid query result: {}", e))?;
+    Ok(json::decode_json(&compiled.columns, &data)?.to_string())
+}
+
+#[wasm_bindgen]
+pub fn describe_revert(compiled: String, data: String) -> Result<String, String> {
+    utils::set_panic_hook();
+    let compiled: compile::Compiled = serde_json::from_str(&compiled)
+        .map_err(|e| format!("Invalid compiled query: {}", e))?;
+    let data = ethers::utils::hex::decode(data.trim_start_matches("0x"))
+        .map_err(|e| format!("Invalid revert data: {}", e))?;
+    revert::describe_revert(&compiled, &data)
+}
+
+/// Contracts registered once and shared by the queries compiled against them. Compiled
+/// queries are returned as JSON, as from `compile`.
+#[wasm_bindgen(js_name = Session)]
+#[derive(Default)]
+pub struct WasmSession(session::Session);
+
+#[wasm_bindgen(js_class = Session)]
+impl WasmSession {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WasmSession {
+        utils::set_panic_hook();
+        WasmSession(session::Session::new())
+    }
+
+    pub fn register(&mut self, registrations: String) -> Result<(), String> {
+        self.0.register(&registrations)
+    }
+
+    pub fn compile(&self, query: String) -> Result<String, String> {
+        let compiled = self.0.compile(&query)?;
+        serde_json::to_string(&compiled)
+            .map_err(|e| format!("Error serializing compiled query: {}", e))
+    }
+
+    pub fn compile_multicall(&self, query: String) -> Result<String, String> {
+        let multicall = self.0.compile_multicall(&query)?;
+        serde_json::to_string(&multicall)
+            .map_err(|e| format!("Error serializing Multicall3 query: {}", e))
+    }
+}
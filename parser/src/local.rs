@@ -0,0 +1,354 @@
+use crate::compile::Compiled;
+use crate::executor::{BlockTag, CallRequest, CallResult, Provider};
+use crate::interpret::MockChain;
+use crate::revert::describe_revert;
+use crate::sleuth::{decode_result, encode_call, find_query};
+use ethers::abi::Token;
+use ethers::utils::hex;
+use revm::db::InMemoryDB;
+use revm::primitives::{
+    AccountInfo, Address, Bytecode, Bytes, CreateScheme, ExecutionResult, Output, TransactTo, U256,
+};
+use revm::Evm;
+use serde_json::Value;
+use std::convert::TryFrom;
+
+const GAS_LIMIT: u64 = 30_000_000;
+
+/// A chain held in memory, which runs calls in an embedded EVM. Calls never change its state.
+#[derive(Clone, Debug)]
+pub struct LocalChain {
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub timestamp: u64,
+    db: InMemoryDB,
+}
+
+fn quantity(value: &Value) -> Result<U256, String> {
+    match value {
+        Value::Number(n) => n.as_u64().map(U256::from),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| format!("Expected a quantity, got {}", value))
+}
+
+fn small_quantity(value: &Value) -> Result<u64, String> {
+    u64::try_from(quantity(value)?).map_err(|_| format!("Quantity {} is too large", value))
+}
+
+fn data(value: &Value) -> Result<Vec<u8>, String> {
+    value
+        .as_str()
+        .and_then(|s| hex::decode(s.trim_start_matches("0x")).ok())
+        .ok_or_else(|| format!("Expected data, got {}", value))
+}
+
+impl Default for LocalChain {
+    fn default() -> Self {
+        LocalChain {
+            chain_id: 1,
+            block_number: 0,
+            timestamp: 0,
+            db: InMemoryDB::default(),
+        }
+    }
+}
+
+impl LocalChain {
+    /// A chain with the state in a JSON dump: either anvil's `--dump-state` file, a genesis
+    /// file's `alloc`, or a fixture mapping addresses to their `balance`, `nonce`, `code` and
+    /// `storage`, each of which may be left out.
+    pub fn from_state_dump(dump: &str) -> Result<Self, String> {
+        let dump: Value =
+            serde_json::from_str(dump).map_err(|e| format!("Invalid state dump: {}", e))?;
+        let mut chain = LocalChain::default();
+        if let Some(block) = dump.get("block") {
+            if let Some(number) = block.get("number") {
+                chain.block_number = small_quantity(number)?;
+            }
+            if let Some(timestamp) = block.get("timestamp") {
+                chain.timestamp = small_quantity(timestamp)?;
+            }
+        }
+        let accounts = dump
+            .get("accounts")
+            .or_else(|| dump.get("alloc"))
+            .unwrap_or(&dump)
+            .as_object()
+            .ok_or_else(|| String::from("State dump has no accounts"))?;
+        for (address, account) in accounts {
+            chain.load_account(address, account)?;
+        }
+        Ok(chain)
+    }
+
+    /// A chain with the balances and mock contracts of the interpreter's chain, so queries can
+    /// run against the same state in both.
+    pub fn from_mock(mock: &MockChain) -> Result<Self, String> {
+        let mut chain = LocalChain {
+            block_number: mock.block_number,
+            ..LocalChain::default()
+        };
+        for (address, balance) in &mock.balances {
+            let code = mock.contracts.get(address).map(|c| c.code()).transpose()?;
+            let balance = U256::from_limbs(balance.0);
+            chain.insert(
+                Address::from(address.0),
+                balance,
+                0,
+                code.unwrap_or_default(),
+            );
+        }
+        for (address, contract) in &mock.contracts {
+            if !mock.balances.contains_key(address) {
+                chain.insert(Address::from(address.0), U256::ZERO, 0, contract.code()?);
+            }
+        }
+        Ok(chain)
+    }
+
+    fn insert(&mut self, address: Address, balance: U256, nonce: u64, code: Vec<u8>) {
+        let bytecode = Bytecode::new_raw(Bytes::from(code));
+        let info = AccountInfo::new(balance, nonce, bytecode.hash_slow(), bytecode);
+        self.db.insert_account_info(address, info);
+    }
+
+    fn load_account(&mut self, address: &str, account: &Value) -> Result<(), String> {
+        let address: Address = address
+            .parse()
+            .map_err(|_| format!("Invalid address {} in state dump", address))?;
+        let field = |name| account.get(name).filter(|value: &&Value| !value.is_null());
+        let balance = field("balance").map(quantity).transpose()?;
+        let nonce = field("nonce").map(small_quantity).transpose()?;
+        let code = field("code").map(data).transpose()?;
+        self.insert(
+            address,
+            balance.unwrap_or_default(),
+            nonce.unwrap_or_default(),
+            code.unwrap_or_default(),
+        );
+        if let Some(storage) = field("storage") {
+            let storage = storage
+                .as_object()
+                .ok_or_else(|| format!("Invalid storage for {}", address))?;
+            for (slot, value) in storage {
+                let slot = quantity(&Value::String(slot.clone()))?;
+                self.db
+                    .insert_account_storage(address, slot, quantity(value)?)
+                    .unwrap_or_else(|e| match e {});
+            }
+        }
+        Ok(())
+    }
+
+    // Runs a transaction from the zero address against `db`, keeping its changes there.
+    fn transact(
+        &self,
+        db: &mut InMemoryDB,
+        to: TransactTo,
+        data: Vec<u8>,
+    ) -> Result<ExecutionResult, String> {
+        let mut evm = Evm::builder()
+            .with_db(db)
+            .modify_cfg_env(|cfg| cfg.chain_id = self.chain_id)
+            .modify_block_env(|block| {
+                block.number = U256::from(self.block_number);
+                block.timestamp = U256::from(self.timestamp);
+            })
+            .modify_tx_env(|tx| {
+                tx.transact_to = to;
+                tx.data = Bytes::from(data);
+                tx.gas_limit = GAS_LIMIT;
+            })
+            .build();
+        evm.transact_commit()
+            .map_err(|e| format!("EVM error: {:?}", e))
+    }
+
+    /// Deploys `bytecode`, the way `Sleuth.sol` would, and calls it with `calldata`.
+    pub fn run(&self, bytecode: &[u8], calldata: &[u8]) -> Result<CallResult, String> {
+        let mut db = self.db.clone();
+        let create = TransactTo::Create(CreateScheme::Create);
+        let address = match self.transact(&mut db, create, bytecode.to_vec())? {
+            ExecutionResult::Success {
+                output: Output::Create(_, Some(address)),
+                ..
+            } => address,
+            result => return Err(format!("Deploying the query failed: {:?}", result)),
+        };
+        match self.transact(&mut db, TransactTo::Call(address), calldata.to_vec())? {
+            ExecutionResult::Success { output, .. } => {
+                Ok(CallResult::Success(output.into_data().to_vec()))
+            }
+            ExecutionResult::Revert { output, .. } => Ok(CallResult::Revert(output.to_vec())),
+            ExecutionResult::Halt { reason, .. } => Err(format!("Query halted: {:?}", reason)),
+        }
+    }
+
+    /// Runs the named query with `args`, returning its columns, or a description of why it
+    /// reverted.
+    pub fn query(
+        &self,
+        compiled: &Compiled,
+        name: &str,
+        args: &[ethers::types::Address],
+    ) -> Result<Vec<Token>, String> {
+        let query = find_query(compiled, name)?;
+        let bytecode = data(&Value::String(compiled.bytecode.clone()))?;
+        match self.run(&bytecode, &encode_call(query, args)?)? {
+            CallResult::Success(result) => decode_result(query, &result),
+            CallResult::Revert(data) => Err(describe_revert(compiled, &data)?),
+        }
+    }
+}
+
+impl Provider for LocalChain {
+    fn call(&self, request: &CallRequest, block: &BlockTag) -> Result<CallResult, String> {
+        let number = match block {
+            BlockTag::Number(number) => Some(*number),
+            BlockTag::Earliest => Some(0),
+            BlockTag::Hash(_) => return Err(String::from("Blocks can't be found by hash locally")),
+            _ => None,
+        };
+        if number.is_some_and(|number| number != self.block_number) {
+            return Err(format!(
+                "Only block {} is available locally",
+                self.block_number
+            ));
+        }
+        let to = match request.to {
+            Some(to) => TransactTo::Call(Address::from(to.0)),
+            None => TransactTo::Create(CreateScheme::Create),
+        };
+        match self.transact(&mut self.db.clone(), to, request.data.clone())? {
+            ExecutionResult::Success { output, .. } => {
+                Ok(CallResult::Success(output.into_data().to_vec()))
+            }
+            ExecutionResult::Revert { output, .. } => Ok(CallResult::Revert(output.to_vec())),
+            ExecutionResult::Halt { reason, .. } => Err(format!("Call halted: {:?}", reason)),
+        }
+    }
+
+    fn chain_id(&self) -> Result<u64, String> {
+        Ok(self.chain_id)
+    }
+
+    fn block_number(&self) -> Result<u64, String> {
+        Ok(self.block_number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::compile;
+    use crate::executor::{BlockTag, CallRequest, CallResult, Provider};
+    use crate::local::LocalChain;
+    use ethers::abi::{Address, Token};
+    use ethers::utils::hex;
+
+    // An anvil state dump with one account, holding 1 ether, and a contract which returns
+    // its first storage slot.
+    const DUMP: &str = r#"{
+        "block": { "number": "0x10", "timestamp": "0x6553f100" },
+        "accounts": {
+            "0x1111111111111111111111111111111111111111": {
+                "nonce": 0,
+                "balance": "0xde0b6b3a7640000",
+                "code": "0x",
+                "storage": {}
+            },
+            "0x2222222222222222222222222222222222222222": {
+                "nonce": 1,
+                "balance": "0x0",
+                "code": "0x60005460005260206000f3",
+                "storage": { "0x0": "0x2a" }
+            }
+        }
+    }"#;
+
+    #[test]
+    fn load_state_dump() {
+        let chain = LocalChain::from_state_dump(DUMP).unwrap();
+        assert_eq!(chain.block_number(), Ok(16));
+        assert_eq!(chain.timestamp, 1700000000);
+        let request = CallRequest {
+            to: Some(Address::repeat_byte(0x22)),
+            data: vec![],
+        };
+        let mut result = vec![0; 32];
+        result[31] = 0x2a;
+        assert_eq!(
+            chain.call(&request, &BlockTag::Latest),
+            Ok(CallResult::Success(result))
+        );
+        assert_eq!(
+            chain.call(&request, &BlockTag::Number(15)),
+            Err(String::from("Only block 16 is available locally"))
+        );
+        assert!(LocalChain::from_state_dump(r#"{ "0x11": {} }"#).is_err());
+    }
+
+    #[test]
+    fn run_queries() {
+        let chain = LocalChain::from_state_dump(DUMP).unwrap();
+        let compiled = compile(
+            r#"
+            SELECT block.number FROM block;
+            QUERY account(:a) AS SELECT BALANCE(:a), IS_CONTRACT(:a)
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            chain.query(&compiled, "query", &[]),
+            Ok(vec![Token::Uint(16.into())])
+        );
+        assert_eq!(
+            chain.query(&compiled, "account", &[Address::repeat_byte(0x11)]),
+            Ok(vec![
+                Token::Uint(ethers::utils::parse_ether(1).unwrap()),
+                Token::Bool(false)
+            ])
+        );
+        assert_eq!(
+            chain.query(&compiled, "account", &[Address::repeat_byte(0x22)]),
+            Ok(vec![Token::Uint(0.into()), Token::Bool(true)])
+        );
+    }
+
+    #[test]
+    fn run_initcode() {
+        let chain = LocalChain::from_state_dump(DUMP).unwrap();
+        let compiled =
+            compile("SELECT BALANCE(0x1111111111111111111111111111111111111111)").unwrap();
+        let initcode = compiled.initcode.as_ref().unwrap();
+        let request = CallRequest {
+            to: None,
+            data: hex::decode(&initcode[2..]).unwrap(),
+        };
+        let result = ethers::abi::encode(&[Token::Uint(ethers::utils::parse_ether(1).unwrap())]);
+        assert_eq!(
+            chain.call(&request, &BlockTag::Latest),
+            Ok(CallResult::Success(result))
+        );
+    }
+
+    #[test]
+    fn describe_reverts() {
+        let chain = LocalChain::from_state_dump(DUMP).unwrap();
+        let compiled = compile(
+            r#"
+            SET STRICT;
+            REGISTER CONTRACT comet AT 0x1111111111111111111111111111111111111111 WITH INTERFACE ["function totalSupply() view returns (uint256)"];
+            SELECT comet.totalSupply FROM comet
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            chain.query(&compiled, "query", &[]),
+            Err(String::from(
+                "column 0 (`comet.totalSupply`) reverted: reverted without data"
+            ))
+        );
+    }
+}
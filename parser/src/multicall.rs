@@ -0,0 +1,450 @@
+use crate::abi::{default_token, param_type, parse_type};
+use crate::ir::{Call, Expr, Failure, Plan, Value, ValueId};
+use crate::source::Account;
+use ethers::abi::{self, ParamType, Token};
+use ethers::types::Address;
+use ethers::utils::{hex, id, to_checksum};
+use serde::{Deserialize, Serialize};
+
+/// Where Multicall3 is deployed, which is the same address on most chains.
+pub const MULTICALL3_ADDRESS: &str = "0xcA11bde05977b3631167028862bE2a173976CA11";
+
+const AGGREGATE3: &str = "aggregate3((address,bool,bytes)[])";
+const GET_BLOCK_NUMBER: &str = "getBlockNumber()";
+const GET_ETH_BALANCE: &str = "getEthBalance(address)";
+
+/// One call in an `aggregate3` batch.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Call3 {
+    pub target: String,
+    /// False when the whole batch should revert if the call fails, as in `STRICT` mode.
+    pub allow_failure: bool,
+    pub call_data: String,
+}
+
+/// Where a column's value comes from, with values ABI-encoded as `0x`-prefixed hex.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Output {
+    Const {
+        value: String,
+    },
+    /// The decoded result of a call, or `default` if it fails. Calls without a default are
+    /// made with `allow_failure` false.
+    Result {
+        call: usize,
+        default: Option<String>,
+    },
+    /// `(bool ok, result)` for a call, with a zero result if it failed.
+    Try {
+        call: usize,
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Column {
+    #[serde(rename = "type")]
+    pub ty: String,
+    #[serde(flatten)]
+    pub output: Output,
+}
+
+/// A query run as a single `aggregate3` call to Multicall3, rather than by deploying it.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Multicall {
+    /// The address to send `calldata` to.
+    pub to: String,
+    pub calldata: String,
+    pub calls: Vec<Call3>,
+    pub columns: Vec<Column>,
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>, String> {
+    hex::decode(value.trim_start_matches("0x")).map_err(|e| format!("Invalid hex {}: {}", value, e))
+}
+
+fn multicall3() -> Address {
+    MULTICALL3_ADDRESS.parse().unwrap()
+}
+
+fn call3(target: Address, allow_failure: bool, call_data: Vec<u8>) -> Call3 {
+    Call3 {
+        target: to_checksum(&target, None),
+        allow_failure,
+        call_data: encode_hex(&call_data),
+    }
+}
+
+// Calls Multicall3 itself to read chain state it has a getter for.
+fn getter(value: &Value) -> Result<Call3, String> {
+    let call_data = match value {
+        Value::BlockNumber => id(GET_BLOCK_NUMBER).to_vec(),
+        Value::Balance(Account::Address(address)) => [
+            id(GET_ETH_BALANCE).to_vec(),
+            abi::encode(&[Token::Address(*address)]),
+        ]
+        .concat(),
+        value => {
+            return Err(format!(
+                "{:?} is not supported by the Multicall3 backend",
+                value
+            ))
+        }
+    };
+    Ok(call3(multicall3(), false, call_data))
+}
+
+fn contract_call(call: &Call, allow_failure: bool) -> Result<Call3, String> {
+    if call.gas.is_some() {
+        return Err(String::from(
+            "GAS limits are not supported by the Multicall3 backend",
+        ));
+    }
+    Ok(call3(call.address, allow_failure, call.calldata.to_vec()))
+}
+
+/// Builds the `aggregate3` batch which runs a plan, making each of its calls once. Only plans
+/// of calls, literals, the block number and balances of fixed addresses can be run this way.
+pub fn derive_multicall(plan: &Plan) -> Result<Multicall, String> {
+    if plan.gas_budget.is_some() {
+        return Err(String::from(
+            "GAS_BUDGET is not supported by the Multicall3 backend",
+        ));
+    }
+    let mut calls: Vec<Call3> = vec![];
+    let mut made: Vec<(ValueId, usize)> = vec![];
+    let mut columns = vec![];
+    for column in &plan.columns {
+        let output = match &column.expr {
+            Expr::Const(token) => Output::Const {
+                value: encode_hex(&abi::encode(std::slice::from_ref(token))),
+            },
+            Expr::Value(value) => match &plan.values[*value] {
+                Value::SafeString(_) => {
+                    return Err(String::from(
+                        "SAFE_STRING is not supported by the Multicall3 backend",
+                    ))
+                }
+                state => {
+                    let call = match made.iter().find(|(v, _)| v == value) {
+                        Some((_, call)) => *call,
+                        None => {
+                            calls.push(getter(state)?);
+                            made.push((*value, calls.len() - 1));
+                            calls.len() - 1
+                        }
+                    };
+                    Output::Result {
+                        call,
+                        default: None,
+                    }
+                }
+            },
+            expr => {
+                // A `TRY` column has no failure handling of its own.
+                let (value, failure) = match expr {
+                    Expr::Try(value) => (*value, None),
+                    expr => match expr.call_result() {
+                        Some((value, failure)) => (value, Some(failure)),
+                        None => return Err(format!("Cannot read {:?}", expr)),
+                    },
+                };
+                let contract = match &plan.values[value] {
+                    Value::Call(contract) => contract,
+                    value => return Err(format!("Cannot read {:?} with {:?}", value, expr)),
+                };
+                let default = match &failure {
+                    Some(Failure::Revert) => None,
+                    Some(Failure::Default(token)) => Some(token.clone()),
+                    _ => Some(default_token(&contract.returns)),
+                };
+                let allow_failure = default.is_some();
+                let call = match made.iter().find(|(v, _)| *v == value) {
+                    Some((_, call)) => {
+                        // A call shared with a `STRICT` column must never fail.
+                        calls[*call].allow_failure &= allow_failure;
+                        *call
+                    }
+                    None => {
+                        calls.push(contract_call(contract, allow_failure)?);
+                        made.push((value, calls.len() - 1));
+                        calls.len() - 1
+                    }
+                };
+                match failure {
+                    None => Output::Try { call },
+                    Some(_) => Output::Result {
+                        call,
+                        default: default
+                            .map(|token| encode_hex(&abi::encode(std::slice::from_ref(&token)))),
+                    },
+                }
+            }
+        };
+        columns.push(Column {
+            ty: param_type(&column.ty),
+            output,
+        });
+    }
+
+    let batch = calls
+        .iter()
+        .map(|call| {
+            Ok(Token::Tuple(vec![
+                Token::Address(
+                    call.target
+                        .parse()
+                        .map_err(|e| format!("Invalid address {}: {}", call.target, e))?,
+                ),
+                Token::Bool(call.allow_failure),
+                Token::Bytes(decode_hex(&call.call_data)?),
+            ]))
+        })
+        .collect::<Result<Vec<Token>, String>>()?;
+    let calldata = [id(AGGREGATE3).to_vec(), abi::encode(&[Token::Array(batch)])].concat();
+
+    Ok(Multicall {
+        to: to_checksum(&multicall3(), None),
+        calldata: encode_hex(&calldata),
+        calls,
+        columns,
+    })
+}
+
+// Decodes returndata as a value of type `ty`: a function's output tuple, or a single value.
+fn decode_returned(ty: &ParamType, data: &[u8]) -> Result<Token, String> {
+    let decoded = match ty {
+        ParamType::Tuple(components) => abi::decode(components, data).map(Token::Tuple),
+        _ => abi::decode(std::slice::from_ref(ty), data).map(|mut tokens| tokens.remove(0)),
+    };
+    decoded.map_err(|e| format!("Invalid result: {}", e))
+}
+
+fn decode_value(ty: &ParamType, encoded: &str) -> Result<Token, String> {
+    abi::decode(std::slice::from_ref(ty), &decode_hex(encoded)?)
+        .map(|mut tokens| tokens.remove(0))
+        .map_err(|e| format!("Invalid value {}: {}", encoded, e))
+}
+
+/// Decodes what `aggregate3` returned into the query's columns.
+pub fn decode_tokens(multicall: &Multicall, return_data: &[u8]) -> Result<Vec<Token>, String> {
+    let results = abi::decode(
+        &[ParamType::Array(Box::new(ParamType::Tuple(vec![
+            ParamType::Bool,
+            ParamType::Bytes,
+        ])))],
+        return_data,
+    )
+    .map_err(|e| format!("Invalid aggregate3 result: {}", e))?;
+    let results: Vec<(bool, Vec<u8>)> = match &results[..] {
+        [Token::Array(results)] => results
+            .iter()
+            .map(|result| match result {
+                Token::Tuple(fields) => match &fields[..] {
+                    [Token::Bool(success), Token::Bytes(data)] => (*success, data.clone()),
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            })
+            .collect(),
+        _ => unreachable!(),
+    };
+    if results.len() != multicall.calls.len() {
+        return Err(format!(
+            "Expected {} results from aggregate3, got {}",
+            multicall.calls.len(),
+            results.len()
+        ));
+    }
+
+    let mut tokens = vec![];
+    for (index, column) in multicall.columns.iter().enumerate() {
+        let ty = parse_type(&column.ty)?;
+        let token = match &column.output {
+            Output::Const { value } => decode_value(&ty, value)?,
+            Output::Result { call, default } => {
+                let (success, data) = &results[*call];
+                let returned = if *success {
+                    decode_returned(&ty, data).ok()
+                } else {
+                    None
+                };
+                match (returned, default) {
+                    (Some(token), _) => token,
+                    (None, Some(default)) => decode_value(&ty, default)?,
+                    (None, None) => {
+                        return Err(format!(
+                            "Call for column {} failed or returned an invalid result",
+                            index
+                        ))
+                    }
+                }
+            }
+            Output::Try { call } => {
+                let inner = match &ty {
+                    ParamType::Tuple(components) if components.len() == 2 => &components[1],
+                    _ => {
+                        return Err(format!(
+                            "Expected (bool, result) for TRY, got {}",
+                            column.ty
+                        ))
+                    }
+                };
+                let (success, data) = &results[*call];
+                let returned = if *success {
+                    decode_returned(inner, data).ok()
+                } else {
+                    None
+                };
+                Token::Tuple(vec![
+                    Token::Bool(returned.is_some()),
+                    returned.unwrap_or_else(|| default_token(inner)),
+                ])
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Decodes what `aggregate3` returned into what the deployed query would have returned.
+pub fn decode(multicall: &Multicall, return_data: &[u8]) -> Result<Vec<u8>, String> {
+    Ok(abi::encode(&decode_tokens(multicall, return_data)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::compile_multicall;
+    use crate::multicall::{decode, decode_tokens, Call3, Column, Output};
+    use ethers::abi::{self, Token};
+    use ethers::utils::hex;
+
+    const QUERY: &str = r###"
+        REGISTER CONTRACT comet AT 0xc3d688B66703497DAA19211EEdff47f25384cdc3 WITH INTERFACE [
+            "function totalSupply() view returns (uint256)",
+            "function name() view returns (string)"
+        ];
+        SELECT comet.totalSupply, TRY(comet.name), BALANCE(0xc3d688B66703497DAA19211EEdff47f25384cdc3), "cat", comet.totalSupply FROM comet;
+        "###;
+
+    fn results(results: Vec<(bool, Vec<Token>)>) -> Vec<u8> {
+        abi::encode(&[Token::Array(
+            results
+                .into_iter()
+                .map(|(success, tokens)| {
+                    Token::Tuple(vec![
+                        Token::Bool(success),
+                        Token::Bytes(abi::encode(&tokens)),
+                    ])
+                })
+                .collect(),
+        )])
+    }
+
+    #[test]
+    fn derive_aggregate3_calldata() {
+        let multicall = compile_multicall(QUERY).unwrap();
+        assert_eq!(multicall.to, "0xcA11bde05977b3631167028862bE2a173976CA11");
+        assert!(multicall.calldata.starts_with("0x82ad56cb"));
+        assert_eq!(
+            multicall.calls,
+            vec![
+                Call3 {
+                    target: String::from("0xc3d688B66703497DAA19211EEdff47f25384cdc3"),
+                    allow_failure: true,
+                    call_data: String::from("0x18160ddd"),
+                },
+                Call3 {
+                    target: String::from("0xc3d688B66703497DAA19211EEdff47f25384cdc3"),
+                    allow_failure: true,
+                    call_data: String::from("0x06fdde03"),
+                },
+                Call3 {
+                    target: String::from("0xcA11bde05977b3631167028862bE2a173976CA11"),
+                    allow_failure: false,
+                    call_data: format!(
+                        "0x4d2301cc{:0>64}",
+                        "c3d688b66703497daa19211eedff47f25384cdc3"
+                    ),
+                },
+            ]
+        );
+        assert_eq!(
+            multicall.columns[4],
+            Column {
+                ty: String::from("tuple(uint256)"),
+                output: Output::Result {
+                    call: 0,
+                    default: Some(format!("0x{}", "00".repeat(32))),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn decode_aggregate3_results() {
+        let multicall = compile_multicall(QUERY).unwrap();
+        let returned = results(vec![
+            (false, vec![]),
+            (true, vec![Token::String(String::from("Compound USDC"))]),
+            (true, vec![Token::Uint(17.into())]),
+        ]);
+        let tokens = vec![
+            Token::Tuple(vec![Token::Uint(0.into())]),
+            Token::Tuple(vec![
+                Token::Bool(true),
+                Token::Tuple(vec![Token::String(String::from("Compound USDC"))]),
+            ]),
+            Token::Uint(17.into()),
+            Token::String(String::from("cat")),
+            Token::Tuple(vec![Token::Uint(0.into())]),
+        ];
+        assert_eq!(decode_tokens(&multicall, &returned), Ok(tokens.clone()));
+        assert_eq!(decode(&multicall, &returned), Ok(abi::encode(&tokens)));
+    }
+
+    #[test]
+    fn strict_calls_must_succeed() {
+        let multicall = compile_multicall(&format!("SET STRICT;{}", QUERY)).unwrap();
+        assert!(!multicall.calls[0].allow_failure);
+        // TRY still allows its call to fail.
+        assert!(multicall.calls[1].allow_failure);
+        assert_eq!(
+            decode_tokens(
+                &multicall,
+                &results(vec![(false, vec![]), (false, vec![]), (true, vec![])])
+            ),
+            Err(String::from(
+                "Call for column 0 failed or returned an invalid result"
+            ))
+        );
+        let returned = results(vec![(true, vec![])]);
+        assert_eq!(
+            decode_tokens(&multicall, &returned),
+            Err(String::from("Expected 3 results from aggregate3, got 1"))
+        );
+        assert!(hex::decode(&multicall.calldata[2..]).is_ok());
+    }
+
+    #[test]
+    fn unsupported_queries() {
+        assert_eq!(
+            compile_multicall(
+                "SELECT CODEHASH(0xc3d688B66703497DAA19211EEdff47f25384cdc3)"
+            ),
+            Err(String::from(
+                "CodeHash(Address(0xc3d688b66703497daa19211eedff47f25384cdc3)) is not supported by the Multicall3 backend"
+            ))
+        );
+        assert_eq!(
+            compile_multicall("SET GAS_BUDGET 100000; SELECT 5"),
+            Err(String::from(
+                "GAS_BUDGET is not supported by the Multicall3 backend"
+            ))
+        );
+    }
+}
@@ -38,10 +38,11 @@ fn parse_full_select_var<'a>(
 enum Literal<'a> {
     Number(u64),
     String(&'a str),
+    Hex(&'a str),
 }
 
 fn parse_literal<'a>(literal_var: Pair<'a, Rule>) -> Result<Literal<'a>, String> {
-    for pair in literal_var.into_inner() {
+    if let Some(pair) = literal_var.into_inner().next() {
         match pair.as_rule() {
             Rule::number => {
                 return Ok(Literal::Number(pair.as_str().parse::<u64>().unwrap()));
@@ -49,17 +50,60 @@ fn parse_literal<'a>(literal_var: Pair<'a, Rule>) -> Result<Literal<'a>, String>
             Rule::string => {
                 return Ok(Literal::String(pair.into_inner().next().unwrap().as_str()));
             }
+            Rule::hex => {
+                return Ok(Literal::Hex(pair.as_str()));
+            }
             r => return Err(format!("parse_literal::unmatched: {:?}", r)),
         }
     }
     Err(String::from("parse_literal::exit"))
 }
 
+fn parse_arguments<'a>(arguments: Pair<'a, Rule>) -> Result<Vec<query::Selection<'a>>, String> {
+    let mut res: Vec<query::Selection<'a>> = vec![];
+    for pair in arguments.into_inner() {
+        match pair.as_rule() {
+            Rule::selection_item => {
+                res.push(parse_selection_item(pair)?);
+            }
+            Rule::argument_n => {
+                res.push(parse_selection_item(pair.into_inner().next().unwrap())?);
+            }
+            r => return Err(format!("parse_arguments::unmatched: {:?}", r)),
+        }
+    }
+    Ok(res)
+}
+
+fn parse_function_call<'a>(
+    function_call: Pair<'a, Rule>,
+) -> Result<query::FunctionCall<'a>, String> {
+    let mut inner = function_call.into_inner();
+    let name_pair = inner.next().unwrap();
+    let name: &str = match name_pair.as_rule() {
+        Rule::function_name => name_pair.as_str(),
+        r => Err(format!(
+            "parse_function_call::name_pair::unmatched: {:?}",
+            r
+        ))?,
+    };
+
+    let args = match inner.next() {
+        Some(pair) => parse_arguments(pair)?,
+        None => vec![],
+    };
+
+    Ok(query::FunctionCall { name, args })
+}
+
 fn parse_selection_item<'a>(
     selection_item: Pair<'a, Rule>,
 ) -> Result<query::Selection<'a>, String> {
-    for pair in selection_item.into_inner() {
+    if let Some(pair) = selection_item.into_inner().next() {
         match pair.as_rule() {
+            Rule::function_call => {
+                return Ok(query::Selection::Function(parse_function_call(pair)?));
+            }
             Rule::full_select_var => {
                 return Ok(query::Selection::Var(parse_full_select_var(pair)?));
             }
@@ -67,32 +111,69 @@ fn parse_selection_item<'a>(
                 return match parse_literal(pair)? {
                     Literal::Number(n) => Ok(query::Selection::Number(n)),
                     Literal::String(s) => Ok(query::Selection::String(s)),
+                    Literal::Hex(h) => Ok(query::Selection::Hex(h)),
                 };
             }
+            Rule::param => {
+                return Ok(query::Selection::Param(&pair.as_str()[1..]));
+            }
             r => return Err(format!("parse_selection_item::unmatched: {:?}", r)),
         }
     }
     Err(String::from("parse_selection_item::exit"))
 }
 
-fn parse_selection<'a>(selection: Pair<'a, Rule>) -> Result<Vec<query::Selection<'a>>, String> {
+fn parse_gas_limit(gas_limit: Pair<Rule>) -> Result<u64, String> {
+    let number = gas_limit.into_inner().next().unwrap();
+    number
+        .as_str()
+        .parse::<u64>()
+        .map_err(|_| format!("Invalid gas amount: {}", number.as_str()))
+}
+
+fn parse_selection<'a>(
+    selection: Pair<'a, Rule>,
+) -> Result<(Vec<query::Selection<'a>>, Vec<query::Span>), String> {
     let mut res: Vec<query::Selection<'a>> = vec![];
-    for pair in selection.into_inner() {
+    let mut spans: Vec<query::Span> = vec![];
+    let mut push_pair = |pair: Pair<'a, Rule>| -> Result<(), String> {
+        let span = pair.as_span();
         match pair.as_rule() {
             Rule::selection_item => {
                 res.push(parse_selection_item(pair)?);
+                spans.push(query::Span {
+                    start: span.start(),
+                    end: span.end(),
+                });
             }
-            Rule::selection_item_n => {
-                res.push(parse_selection_item(pair.into_inner().next().unwrap())?);
+            Rule::gas_limit => {
+                let item = res.pop().unwrap();
+                res.push(query::Selection::Gas(
+                    Box::new(item),
+                    parse_gas_limit(pair)?,
+                ));
+                // The limit belongs to the item, so it's part of its text.
+                spans.last_mut().unwrap().end = span.end();
             }
             r => return Err(format!("parse_selection::unmatched: {:?}", r)),
         }
+        Ok(())
+    };
+    for pair in selection.into_inner() {
+        match pair.as_rule() {
+            Rule::selection_item_n => {
+                for inner in pair.into_inner() {
+                    push_pair(inner)?;
+                }
+            }
+            _ => push_pair(pair)?,
+        }
     }
-    Ok(res)
+    Ok((res, spans))
 }
 
 fn parse_from<'a>(from: Pair<'a, Rule>) -> Result<&'a str, String> {
-    for pair in from.into_inner() {
+    if let Some(pair) = from.into_inner().next() {
         match pair.as_rule() {
             Rule::source => {
                 return Ok(pair.as_str());
@@ -104,8 +185,9 @@ fn parse_from<'a>(from: Pair<'a, Rule>) -> Result<&'a str, String> {
 }
 
 fn parse_select_query<'a>(select_query: Pair<'a, Rule>) -> Result<query::SelectQuery<'a>, String> {
-    let mut selection: Option<Vec<query::Selection<'a>>> = None;
+    let mut selection: Option<(Vec<query::Selection<'a>>, Vec<query::Span>)> = None;
     let mut source: Option<&'a str> = None;
+    let mut gas: Option<u64> = None;
 
     for pair in select_query.into_inner() {
         match pair.as_rule() {
@@ -115,13 +197,19 @@ fn parse_select_query<'a>(select_query: Pair<'a, Rule>) -> Result<query::SelectQ
             Rule::from_cls => {
                 source = Some(parse_from(pair)?);
             }
+            Rule::gas_limit => {
+                gas = Some(parse_gas_limit(pair)?);
+            }
             r => return Err(format!("parse_select_query::unmatched: {:?}", r)),
         }
     }
 
+    let (select, spans) = selection.unwrap();
     Ok(query::SelectQuery {
-        select: selection.unwrap(),
+        select,
         source,
+        gas,
+        spans,
     })
 }
 
@@ -182,20 +270,92 @@ fn parse_register_query<'a>(
         ))?,
     };
 
-    let interface = match inner.next() {
-        Some(pair) => parse_interface(pair)?,
-        None => vec![],
-    };
+    let mut builtin: Option<&str> = None;
+    let mut interface: Vec<&str> = vec![];
+    for pair in inner {
+        match pair.as_rule() {
+            Rule::interface_name => {
+                builtin = Some(pair.as_str());
+            }
+            Rule::interface_cls => {
+                interface = parse_interface(pair)?;
+            }
+            r => Err(format!("parse_register_query::unmatched: {:?}", r))?,
+        }
+    }
 
     Ok(query::RegisterQuery {
         source,
         address,
+        builtin,
         interface,
     })
 }
 
+fn parse_set_query<'a>(set_query: Pair<'a, Rule>) -> Result<query::QueryOption, String> {
+    let option = set_query.into_inner().next().unwrap();
+    if let Some(pair) = option.into_inner().next() {
+        match pair.as_rule() {
+            Rule::strict_option => {
+                return Ok(query::QueryOption::Strict);
+            }
+            Rule::allow_mutations_option => {
+                return Ok(query::QueryOption::AllowMutations);
+            }
+            Rule::gas_budget_option => {
+                return Ok(query::QueryOption::GasBudget(parse_gas_limit(pair)?));
+            }
+            r => return Err(format!("parse_set_query::unmatched: {:?}", r)),
+        }
+    }
+    Err(String::from("parse_set_query::exit"))
+}
+
+fn parse_named_query<'a>(named_query: Pair<'a, Rule>) -> Result<query::NamedQuery<'a>, String> {
+    let mut name: Option<&'a str> = None;
+    let mut params: Vec<&'a str> = vec![];
+    let mut select: Option<query::SelectQuery<'a>> = None;
+
+    for pair in named_query.into_inner() {
+        match pair.as_rule() {
+            Rule::query_name => {
+                name = Some(pair.as_str());
+            }
+            Rule::query_params => {
+                for query_param in pair.into_inner() {
+                    let mut inner = query_param.into_inner();
+                    let param = &inner.next().unwrap().as_str()[1..];
+                    // Parameters are read from calldata as addresses, so that's the only type
+                    // they can be declared with.
+                    if let Some(ty) = inner.next().map(|t| t.as_str()) {
+                        if ty != "address" {
+                            return Err(format!(
+                                "Parameter :{} of query {} has type {}, but query parameters can only be addresses",
+                                param,
+                                name.unwrap_or_default(),
+                                ty
+                            ));
+                        }
+                    }
+                    params.push(param);
+                }
+            }
+            Rule::select_query => {
+                select = Some(parse_select_query(pair)?);
+            }
+            r => return Err(format!("parse_named_query::unmatched: {:?}", r)),
+        }
+    }
+
+    Ok(query::NamedQuery {
+        name: name.unwrap(),
+        params,
+        select: select.unwrap(),
+    })
+}
+
 fn parse_query<'a>(query: Pair<'a, Rule>) -> Result<query::Query<'a>, String> {
-    for pair in query.into_inner().next().unwrap().into_inner() {
+    if let Some(pair) = query.into_inner().next().unwrap().into_inner().next() {
         match pair.as_rule() {
             Rule::select_query => {
                 return Ok(query::Query::Select(parse_select_query(pair)?));
@@ -203,6 +363,12 @@ fn parse_query<'a>(query: Pair<'a, Rule>) -> Result<query::Query<'a>, String> {
             Rule::register_query => {
                 return Ok(query::Query::Register(parse_register_query(pair)?));
             }
+            Rule::set_query => {
+                return Ok(query::Query::Set(parse_set_query(pair)?));
+            }
+            Rule::named_query => {
+                return Ok(query::Query::Named(parse_named_query(pair)?));
+            }
             r => return Err(format!("parse_query::unmatched: {:?}", r)),
         }
     }
@@ -210,7 +376,7 @@ fn parse_query<'a>(query: Pair<'a, Rule>) -> Result<query::Query<'a>, String> {
 }
 
 pub fn parse_query_cls<'a>(query: &'a str) -> Result<Vec<query::Query<'a>>, String> {
-    let mut pairs = SleuthParser::parse(Rule::main, &query).map_err(|e| e.to_string())?;
+    let mut pairs = SleuthParser::parse(Rule::main, query).map_err(|e| e.to_string())?;
     let query_cls = pairs.next().unwrap().into_inner().next().unwrap();
 
     query_cls.into_inner().map(parse_query).collect()
@@ -227,7 +393,9 @@ mod tests {
             parse_query_cls("SELECT 5"),
             Ok(vec![Query::Select(SelectQuery {
                 select: vec![Selection::Number(5)],
-                source: None
+                source: None,
+                gas: None,
+                spans: vec![Span { start: 7, end: 8 }]
             })])
         );
     }
@@ -241,7 +409,9 @@ mod tests {
                     source: Some("blocks"),
                     variable: SelectVar::Var("number")
                 })],
-                source: Some("blocks")
+                source: Some("blocks"),
+                gas: None,
+                spans: vec![Span { start: 7, end: 20 }]
             })])
         );
     }
@@ -259,7 +429,120 @@ mod tests {
                     Selection::Number(5),
                     Selection::String("cat"),
                 ],
-                source: Some("blocks")
+                source: Some("blocks"),
+                gas: None,
+                spans: vec![
+                    Span { start: 7, end: 20 },
+                    Span { start: 22, end: 23 },
+                    Span { start: 25, end: 30 }
+                ]
+            })])
+        );
+    }
+
+    #[test]
+    fn simple_query_with_builtin_interface() {
+        assert_eq!(
+            parse_query_cls(
+                "REGISTER CONTRACT usdc AT 0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48 AS ERC20; SELECT usdc.decimals FROM usdc"
+            ),
+            Ok(vec![
+                Query::Register(RegisterQuery {
+                    source: "usdc",
+                    address: "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+                    builtin: Some("ERC20"),
+                    interface: vec![]
+                }),
+                Query::Select(SelectQuery {
+                    select: vec![Selection::Var(FullSelectVar {
+                        source: Some("usdc"),
+                        variable: SelectVar::Var("decimals")
+                    })],
+                    source: Some("usdc"),
+                    gas: None,
+                    spans: vec![Span { start: 86, end: 99 }]
+                })
+            ])
+        );
+    }
+
+    #[test]
+    fn simple_query_with_set_strict() {
+        assert_eq!(
+            parse_query_cls("SET STRICT; SELECT 5"),
+            Ok(vec![
+                Query::Set(QueryOption::Strict),
+                Query::Select(SelectQuery {
+                    select: vec![Selection::Number(5)],
+                    source: None,
+                    gas: None,
+                    spans: vec![Span { start: 19, end: 20 }]
+                })
+            ])
+        );
+    }
+
+    #[test]
+    fn simple_query_with_set_allow_mutations() {
+        assert_eq!(
+            parse_query_cls("SET STRICT; set allow_mutations;"),
+            Ok(vec![
+                Query::Set(QueryOption::Strict),
+                Query::Set(QueryOption::AllowMutations),
+            ])
+        );
+    }
+
+    #[test]
+    fn simple_query_with_gas() {
+        assert_eq!(
+            parse_query_cls(
+                "SET GAS_BUDGET 1000000; SELECT comet.totalSupply GAS 50000, comet.name FROM comet gas 200000"
+            ),
+            Ok(vec![
+                Query::Set(QueryOption::GasBudget(1000000)),
+                Query::Select(SelectQuery {
+                    select: vec![
+                        Selection::Gas(
+                            Box::new(Selection::Var(FullSelectVar {
+                                source: Some("comet"),
+                                variable: SelectVar::Var("totalSupply")
+                            })),
+                            50000
+                        ),
+                        Selection::Var(FullSelectVar {
+                            source: Some("comet"),
+                            variable: SelectVar::Var("name")
+                        }),
+                    ],
+                    source: Some("comet"),
+                    gas: Some(200000),
+                    spans: vec![Span { start: 31, end: 58 }, Span { start: 60, end: 70 }]
+                })
+            ])
+        );
+    }
+
+    #[test]
+    fn simple_query_with_function_call() {
+        assert_eq!(
+            parse_query_cls(
+                "SELECT BALANCE(0xc3d688B66703497DAA19211EEdff47f25384cdc3), is_contract(0x00)"
+            ),
+            Ok(vec![Query::Select(SelectQuery {
+                select: vec![
+                    Selection::Function(FunctionCall {
+                        name: "BALANCE",
+                        args: vec![Selection::Hex("0xc3d688B66703497DAA19211EEdff47f25384cdc3")]
+                    }),
+                    Selection::Function(FunctionCall {
+                        name: "is_contract",
+                        args: vec![Selection::Hex("0x00")]
+                    }),
+                ],
+                source: None,
+                gas: None,
+                spans: vec![Span { start: 7, end: 58 }, Span { start: 60, end: 77 }]
             })])
         );
     }
@@ -277,6 +560,7 @@ mod tests {
                 Query::Register(RegisterQuery {
                     source: "comet",
                     address: "0xc3d688B66703497DAA19211EEdff47f25384cdc3",
+                    builtin: None,
                     interface: vec![
                         "function totalSupply() returns (uint256)"
                     ]
@@ -288,9 +572,60 @@ mod tests {
                             variable: SelectVar::Var("totalSupply")
                         })
                     ],
-                    source: Some("comet")
+                    source: Some("comet"),
+                    gas: None,
+                    spans: vec![Span { start: 171, end: 188 }]
                 })
             ])
         );
     }
+
+    #[test]
+    fn named_queries() {
+        assert_eq!(
+            parse_query_cls("QUERY markets AS SELECT 5; query account(:a, :b) as SELECT BALANCE(:a), :b"),
+            Ok(vec![
+                Query::Named(NamedQuery {
+                    name: "markets",
+                    params: vec![],
+                    select: SelectQuery {
+                        select: vec![Selection::Number(5)],
+                        source: None,
+                        gas: None,
+                        spans: vec![Span { start: 24, end: 25 }]
+                    }
+                }),
+                Query::Named(NamedQuery {
+                    name: "account",
+                    params: vec!["a", "b"],
+                    select: SelectQuery {
+                        select: vec![
+                            Selection::Function(FunctionCall {
+                                name: "BALANCE",
+                                args: vec![Selection::Param("a")]
+                            }),
+                            Selection::Param("b"),
+                        ],
+                        source: None,
+                        gas: None,
+                        spans: vec![Span { start: 59, end: 70 }, Span { start: 72, end: 74 }]
+                    }
+                })
+            ])
+        );
+    }
+
+    #[test]
+    fn typed_query_params() {
+        match &parse_query_cls("QUERY account(:a address, :b) AS SELECT :a").unwrap()[..] {
+            [Query::Named(named)] => assert_eq!(named.params, vec!["a", "b"]),
+            queries => panic!("Expected one named query, got {:?}", queries),
+        }
+        assert_eq!(
+            parse_query_cls("QUERY totals(:a, :n uint256) AS SELECT 5"),
+            Err(String::from(
+                "Parameter :n of query totals has type uint256, but query parameters can only be addresses"
+            ))
+        );
+    }
 }
@@ -0,0 +1,305 @@
+use crate::abi::default_token;
+use crate::ir::{Expr, Plan, Value, ValueId};
+use ethers::types::{Address, Bytes};
+use std::collections::HashMap;
+
+/// A transformation of a query plan which keeps the columns it returns unchanged.
+pub type Pass = fn(Plan) -> Result<Plan, String>;
+
+pub struct PassManager {
+    passes: Vec<(&'static str, Pass)>,
+}
+
+impl PassManager {
+    pub fn new() -> PassManager {
+        PassManager { passes: vec![] }
+    }
+
+    pub fn add(&mut self, name: &'static str, pass: Pass) -> &mut PassManager {
+        self.passes.push((name, pass));
+        self
+    }
+
+    pub fn run(&self, plan: Plan) -> Result<Plan, String> {
+        self.passes.iter().try_fold(plan, |plan, (name, pass)| {
+            pass(plan).map_err(|e| format!("Error in {} pass: {}", name, e))
+        })
+    }
+}
+
+impl Default for PassManager {
+    fn default() -> PassManager {
+        let mut passes = PassManager::new();
+        passes
+            .add("constant-folding", fold_constants)
+            .add("call-deduplication", deduplicate_calls)
+            .add("dead-value-elimination", eliminate_dead_values);
+        passes
+    }
+}
+
+/// Simplifies branches whose outcome is known at compile time, e.g. a `COALESCE` whose
+/// default is the zero value a failed call already gives.
+pub fn fold_constants(mut plan: Plan) -> Result<Plan, String> {
+    for column in plan.columns.iter_mut() {
+        let expr = std::mem::replace(&mut column.expr, Expr::Revert);
+        column.expr = fold(expr, &plan.values);
+    }
+    Ok(plan)
+}
+
+fn fold(expr: Expr, values: &[Value]) -> Expr {
+    let (call, then, otherwise) = match expr {
+        Expr::IfOk {
+            call,
+            then,
+            otherwise,
+        } => (call, fold(*then, values), fold(*otherwise, values)),
+        expr => return expr,
+    };
+    let known = match &values[call] {
+        Value::Call(c) => {
+            // Both branches give the same value, and the call is either still made by them
+            // or can be skipped.
+            let same = then == otherwise && (c.is_read_only() || then.values().contains(&call));
+            // A failed call's result is already the zero value.
+            let zero_default =
+                then == Expr::Result(call) && otherwise == Expr::Const(default_token(&c.returns));
+            same || zero_default
+        }
+        _ => false,
+    };
+    if known {
+        then
+    } else {
+        Expr::IfOk {
+            call,
+            then: Box::new(then),
+            otherwise: Box::new(otherwise),
+        }
+    }
+}
+
+/// Makes columns reading identical read-only calls share one call, so it's made once.
+/// Calls which may modify state are left alone, since repeating them may matter.
+pub fn deduplicate_calls(mut plan: Plan) -> Result<Plan, String> {
+    // The first of each read-only call, by target, calldata and gas, which are compared in
+    // full only when they match.
+    let mut first: HashMap<(Address, &Bytes, Option<u64>), ValueId> = HashMap::new();
+    let mut shared: Vec<ValueId> = (0..plan.values.len()).collect();
+    for (id, value) in plan.values.iter().enumerate() {
+        if let Value::Call(call) = value {
+            if !call.is_read_only() {
+                continue;
+            }
+            let earlier = *first
+                .entry((call.address, &call.calldata, call.gas))
+                .or_insert(id);
+            if plan.values[earlier] == *value {
+                shared[id] = earlier;
+            }
+        }
+    }
+    for column in plan.columns.iter_mut() {
+        column.expr.renumber(&|value| shared[value]);
+    }
+    Ok(plan)
+}
+
+/// Drops values which no column reads, e.g. calls replaced by an earlier identical call.
+pub fn eliminate_dead_values(mut plan: Plan) -> Result<Plan, String> {
+    let uses = plan.uses();
+    let mut renumbered = vec![None; plan.values.len()];
+    let mut values = vec![];
+    for (id, value) in plan.values.into_iter().enumerate() {
+        if uses[id] > 0 {
+            renumbered[id] = Some(values.len());
+            values.push(value);
+        }
+    }
+    for column in plan.columns.iter_mut() {
+        column.expr.renumber(&|value| renumbered[value].unwrap());
+    }
+    plan.values = values;
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::{Call, Column, Expr, Plan, Value};
+    use crate::passes::{deduplicate_calls, eliminate_dead_values, fold_constants, PassManager};
+    use ethers::abi::{ParamType, StateMutability, Token};
+    use ethers::types::{Bytes, H160};
+
+    fn call(byte: u8, mutability: StateMutability, gas: Option<u64>) -> Value {
+        Value::Call(Call {
+            address: H160::repeat_byte(byte),
+            calldata: Bytes::from([0x18, 0x16, 0x0d, 0xdd]),
+            returns: ParamType::Tuple(vec![ParamType::Uint(256)]),
+            mutability,
+            gas,
+        })
+    }
+
+    fn returning(value: Value, returns: ParamType) -> Value {
+        match value {
+            Value::Call(call) => Value::Call(Call { returns, ..call }),
+            value => value,
+        }
+    }
+
+    fn column(expr: Expr) -> Column {
+        Column {
+            name: None,
+            source: None,
+            ty: ParamType::Tuple(vec![ParamType::Uint(256)]),
+            expr,
+            span: None,
+        }
+    }
+
+    fn plan(values: Vec<Value>, exprs: Vec<Expr>) -> Plan {
+        Plan {
+            values,
+            columns: exprs.into_iter().map(column).collect(),
+            gas_budget: None,
+        }
+    }
+
+    fn exprs(plan: &Plan) -> Vec<Expr> {
+        plan.columns.iter().map(|c| c.expr.clone()).collect()
+    }
+
+    #[test]
+    fn fold_known_branches() {
+        let view = StateMutability::View;
+        let mutating = StateMutability::NonPayable;
+        let zero = Token::Tuple(vec![Token::Uint(0.into())]);
+        let five = Token::Tuple(vec![Token::Uint(5.into())]);
+        let both = |call, token: &Token| Expr::IfOk {
+            call,
+            then: Box::new(Expr::Const(token.clone())),
+            otherwise: Box::new(Expr::Const(token.clone())),
+        };
+        let folded = fold_constants(plan(
+            vec![call(0x11, view, None), call(0x22, mutating, None)],
+            vec![
+                Expr::if_ok(0, Expr::Const(zero.clone())),
+                Expr::if_ok(0, Expr::Const(five.clone())),
+                Expr::if_ok(0, Expr::Revert),
+                both(0, &five),
+                both(1, &five),
+                Expr::if_ok(1, Expr::Const(zero)),
+            ],
+        ))
+        .unwrap();
+        assert_eq!(
+            exprs(&folded),
+            vec![
+                Expr::Result(0),
+                Expr::if_ok(0, Expr::Const(five.clone())),
+                Expr::if_ok(0, Expr::Revert),
+                Expr::Const(five.clone()),
+                both(1, &five),
+                Expr::Result(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn deduplicate_read_only_calls() {
+        let view = StateMutability::View;
+        let mutating = StateMutability::NonPayable;
+        let deduplicated = deduplicate_calls(plan(
+            vec![
+                call(0x11, view, None),
+                call(0x22, view, None),
+                call(0x11, view, None),
+                call(0x11, view, Some(50000)),
+                call(0x33, mutating, None),
+                call(0x33, mutating, None),
+                returning(
+                    call(0x11, view, None),
+                    ParamType::Tuple(vec![ParamType::Bool]),
+                ),
+                call(0x22, view, None),
+            ],
+            vec![
+                Expr::Result(0),
+                Expr::Result(1),
+                Expr::Try(2),
+                Expr::Result(3),
+                Expr::Result(4),
+                Expr::Result(5),
+                Expr::Result(6),
+                Expr::Try(7),
+            ],
+        ))
+        .unwrap();
+        assert_eq!(
+            exprs(&deduplicated),
+            vec![
+                Expr::Result(0),
+                Expr::Result(1),
+                Expr::Try(0),
+                Expr::Result(3),
+                Expr::Result(4),
+                Expr::Result(5),
+                Expr::Result(6),
+                Expr::Try(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn eliminate_unused_values() {
+        let view = StateMutability::View;
+        let eliminated = eliminate_dead_values(plan(
+            vec![
+                call(0x11, view, None),
+                call(0x11, view, None),
+                Value::BlockNumber,
+            ],
+            vec![Expr::Try(0), Expr::Value(2), Expr::Result(0)],
+        ))
+        .unwrap();
+        assert_eq!(eliminated.values, vec![call(0x11, view, None), Value::BlockNumber]);
+        assert_eq!(
+            exprs(&eliminated),
+            vec![Expr::Try(0), Expr::Value(1), Expr::Result(0)]
+        );
+    }
+
+    #[test]
+    fn pass_manager() {
+        let passes = PassManager::default();
+        let view = StateMutability::View;
+        let zero = Token::Tuple(vec![Token::Uint(0.into())]);
+        let optimized = passes
+            .run(plan(
+                vec![
+                    call(0x11, view, None),
+                    call(0x11, view, None),
+                    call(0x11, view, None),
+                ],
+                vec![
+                    Expr::if_ok(0, Expr::Revert),
+                    Expr::Try(1),
+                    Expr::if_ok(2, Expr::Const(zero)),
+                ],
+            ))
+            .unwrap();
+        assert_eq!(optimized.values, vec![call(0x11, view, None)]);
+        assert_eq!(
+            exprs(&optimized),
+            vec![Expr::if_ok(0, Expr::Revert), Expr::Try(0), Expr::Result(0)]
+        );
+
+        let mut failing = PassManager::new();
+        failing.add("failing", |_| Err(String::from("boom")));
+        assert_eq!(
+            failing.run(plan(vec![], vec![])),
+            Err(String::from("Error in failing pass: boom"))
+        );
+    }
+}
@@ -11,30 +11,69 @@ pub struct FullSelectVar<'a> {
   pub variable: SelectVar<'a>
 }
 
+#[derive(Debug, PartialEq)]
+pub struct FunctionCall<'a> {
+  pub name: &'a str,
+  pub args: Vec<Selection<'a>>
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Selection<'a> {
   Var(FullSelectVar<'a>),
+  Function(FunctionCall<'a>),
   Number(u64),
-  String(&'a str)
+  String(&'a str),
+  Hex(&'a str),
+  Gas(Box<Selection<'a>>, u64),
+  /// A parameter of the named query, e.g. `:account`, without the colon.
+  Param(&'a str)
+}
+
+/// A range of the query text, as byte offsets with an exclusive end.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span {
+  pub start: usize,
+  pub end: usize
 }
 
 #[derive(Debug, PartialEq)]
 pub struct SelectQuery<'a> {
   pub select: Vec<Selection<'a>>,
-  pub source: Option<&'a str>
+  pub source: Option<&'a str>,
+  pub gas: Option<u64>,
+  /// Where each item of `select` appears in the query text.
+  pub spans: Vec<Span>
 }
 
 #[derive(Debug, PartialEq)]
 pub struct RegisterQuery<'a> {
   pub source: &'a str,
   pub address: &'a str,
+  pub builtin: Option<&'a str>,
   pub interface: Vec<&'a str>
 }
 
+/// A query with its own entry point, e.g. `QUERY account(:a) AS SELECT BALANCE(:a)`.
+#[derive(Debug, PartialEq)]
+pub struct NamedQuery<'a> {
+  pub name: &'a str,
+  pub params: Vec<&'a str>,
+  pub select: SelectQuery<'a>
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QueryOption {
+  Strict,
+  AllowMutations,
+  GasBudget(u64),
+}
+
 #[derive(Debug, PartialEq)]
 pub enum Query<'a> {
   Select(SelectQuery<'a>),
   Register(RegisterQuery<'a>),
+  Set(QueryOption),
+  Named(NamedQuery<'a>),
 }
 
 #[cfg(test)]
@@ -54,9 +93,19 @@ mod tests {
           variable: SelectVar::Var("number")
         }),
         Selection::Number(55),
-        Selection::String("Hello")
+        Selection::String("Hello"),
+        Selection::Function(FunctionCall {
+          name: "BALANCE",
+          args: vec![Selection::Hex("0xc3d688B66703497DAA19211EEdff47f25384cdc3")]
+        }),
+        Selection::Gas(Box::new(Selection::Var(FullSelectVar {
+          source: Some("comet"),
+          variable: SelectVar::Var("totalSupply")
+        })), 50000)
       ],
-      source: Some("block")
+      source: Some("block"),
+      gas: Some(200000),
+      spans: vec![Span { start: 7, end: 14 }]
     });
   }
 
@@ -65,9 +114,34 @@ mod tests {
     let _: Query = Query::Register(RegisterQuery {
       source: "comet",
       address: "0xc3d688B66703497DAA19211EEdff47f25384cdc3",
+      builtin: None,
       interface: vec![
         "function totalSupply() returns (uint256)"
       ]
     });
   }
+
+  #[test]
+  fn named_query() {
+    let _: Query = Query::Named(NamedQuery {
+      name: "account",
+      params: vec!["a"],
+      select: SelectQuery {
+        select: vec![Selection::Function(FunctionCall {
+          name: "BALANCE",
+          args: vec![Selection::Param("a")]
+        })],
+        source: None,
+        gas: None,
+        spans: vec![Span { start: 28, end: 39 }]
+      }
+    });
+  }
+
+  #[test]
+  fn set_query() {
+    let _: Query = Query::Set(QueryOption::Strict);
+    let _: Query = Query::Set(QueryOption::AllowMutations);
+    let _: Query = Query::Set(QueryOption::GasBudget(1000000));
+  }
 }
@@ -1,23 +1,62 @@
 use crate::query::{self, Selection};
 use crate::source::{
-    find_data_source, find_source, get_all_sources, sources_for_query, DataSource, Source,
+    find_data_source, get_address, get_all_sources, sources_for_query, Account, DataSource, Source,
+    SourceIndex,
 };
-use ethers::abi;
+use crate::abi::field_param;
+use ethers::abi::{self, ParamType, Token};
+use ethers::types::U256;
+use ethers::utils::hex;
 
 #[derive(PartialEq, Debug)]
 pub struct Resolution {
     pub name: Option<String>,
+    pub source: Option<String>,
     pub abi: abi::struct_def::FieldType,
     pub data_source: DataSource,
+    /// Gas forwarded to each contract call made for this column, or all remaining gas.
+    pub gas: Option<u64>,
+    /// The `SELECT` item this column came from.
+    pub span: Option<query::Span>,
 }
 
-fn show_missing_source_error(source: &str, sources: &Vec<Source>) -> String {
+/// A query the compiled contract answers: `query()` for plain `SELECT`s, or a named query.
+#[derive(PartialEq, Debug)]
+pub struct Entry {
+    pub name: String,
+    pub params: Vec<String>,
+    pub resolutions: Vec<Resolution>,
+}
+
+impl Entry {
+    /// The function signature the entry is called with, e.g. `account(address)`.
+    pub fn signature(&self) -> String {
+        let params = vec!["address"; self.params.len()];
+        format!("{}({})", self.name, params.join(","))
+    }
+}
+
+#[derive(PartialEq, Debug, Default)]
+pub struct Settings {
+    pub strict: bool,
+    pub allow_mutations: bool,
+    pub gas_budget: Option<u64>,
+}
+
+// The `source.variable` a resolution was selected from.
+#[derive(Clone, Copy)]
+struct Label<'a> {
+    source: &'a str,
+    name: &'a str,
+}
+
+fn show_missing_source_error(source: &str, sources: &SourceIndex) -> String {
     format!(
         "Cannot find source \"{}\" in sources from query. FROM sources: {}",
         source,
         sources
             .iter()
-            .map(|s| s.name.clone())
+            .map(|s| s.name.to_string())
             .collect::<Vec<String>>()
             .join(",")
     )
@@ -28,78 +67,405 @@ fn show_missing_variable_error(variable: &str, source: &Source) -> String {
         "Cannot find variable with name \"{}\" in source \"{}\". Known variables: {}",
         variable,
         source.name,
-        source
-            .mappings
-            .keys()
-            .map(|s| String::from(s))
-            .collect::<Vec<String>>()
-            .join(",")
+        source.variables().join(",")
     )
 }
 
+fn show_function_arity_error(function: &query::FunctionCall, arity: usize) -> String {
+    format!(
+        "Function {} expects {} argument(s), got {}",
+        function.name,
+        arity,
+        function.args.len()
+    )
+}
+
+fn param_index(name: &str, params: &[&str]) -> Result<usize, String> {
+    params
+        .iter()
+        .position(|p| *p == name)
+        .ok_or_else(|| format!("Unknown parameter :{}", name))
+}
+
+fn address_argument(function: &query::FunctionCall, params: &[&str]) -> Result<Account, String> {
+    match &function.args[..] {
+        [Selection::Hex(address)] => Ok(Account::Address(get_address(address)?)),
+        [Selection::Param(name)] => Ok(Account::Param(param_index(name, params)?)),
+        [_] => Err(format!(
+            "Function {} expects an address literal or parameter argument",
+            function.name
+        )),
+        _ => Err(show_function_arity_error(function, 1)),
+    }
+}
+
+fn resolve_var<'a, 'b>(
+    fsv: &query::FullSelectVar<'a>,
+    sources: &SourceIndex<'b>,
+) -> Result<Option<(Label<'a>, DataSource)>, String> {
+    // TODO: Handle vars without listed source
+    if let Some(source_name) = fsv.source {
+        let source = sources
+            .get(source_name)
+            .ok_or_else(|| show_missing_source_error(source_name, sources))?;
+        match fsv.variable {
+            query::SelectVar::Var(v) => {
+                let data_source = find_data_source(v, source)
+                    .ok_or_else(|| show_missing_variable_error(v, source))?;
+                let label = Label {
+                    source: source_name,
+                    name: v,
+                };
+                Ok(Some((label, data_source)))
+            }
+            query::SelectVar::Wildcard => todo!(),
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+fn call_argument<'a>(
+    function: &query::FunctionCall<'a>,
+    argument: &Selection<'a>,
+    sources: &SourceIndex,
+) -> Result<(Label<'a>, DataSource), String> {
+    let show_call_argument_error = || {
+        format!(
+            "Function {} expects a contract call argument, e.g. {}(token.symbol)",
+            function.name, function.name
+        )
+    };
+    match argument {
+        Selection::Var(fsv) => match resolve_var(fsv, sources)? {
+            Some((label, data_source @ DataSource::Call(..))) => Ok((label, data_source)),
+            _ => Err(show_call_argument_error()),
+        },
+        _ => Err(show_call_argument_error()),
+    }
+}
+
+fn single_call_argument<'a>(
+    function: &query::FunctionCall<'a>,
+    sources: &SourceIndex,
+) -> Result<(Label<'a>, DataSource), String> {
+    match &function.args[..] {
+        [argument] => call_argument(function, argument, sources),
+        _ => Err(show_function_arity_error(function, 1)),
+    }
+}
+
+fn literal_token(
+    function: &query::FunctionCall,
+    param: &ParamType,
+    literal: &Selection,
+) -> Result<Token, String> {
+    let hex_bytes = |h: &str| {
+        hex::decode(h.trim_start_matches("0x")).map_err(|_| format!("Invalid hex literal: {}", h))
+    };
+    match (param, literal) {
+        (ParamType::Uint(_), Selection::Number(n)) => Ok(Token::Uint(U256::from(*n))),
+        (ParamType::Int(_), Selection::Number(n)) => Ok(Token::Int(U256::from(*n))),
+        (ParamType::Bool, Selection::Number(n)) if *n <= 1 => Ok(Token::Bool(*n == 1)),
+        (ParamType::String, Selection::String(s)) => Ok(Token::String(String::from(*s))),
+        (ParamType::Bytes, Selection::String(s)) => Ok(Token::Bytes(s.as_bytes().to_vec())),
+        (ParamType::Bytes, Selection::Hex(h)) => Ok(Token::Bytes(hex_bytes(h)?)),
+        (ParamType::Address, Selection::Hex(h)) => Ok(Token::Address(get_address(h)?)),
+        (ParamType::FixedBytes(sz), Selection::Hex(h)) => {
+            let mut bytes = hex_bytes(h)?;
+            if bytes.len() > *sz {
+                return Err(format!("Hex literal {} does not fit in bytes{}", h, sz));
+            }
+            bytes.resize(*sz, 0);
+            Ok(Token::FixedBytes(bytes))
+        }
+        _ => Err(format!(
+            "Function {} cannot use {:?} as a default for {}",
+            function.name, literal, param
+        )),
+    }
+}
+
+fn coalesce_arguments<'a>(
+    function: &query::FunctionCall<'a>,
+    sources: &SourceIndex,
+) -> Result<(Label<'a>, DataSource), String> {
+    match &function.args[..] {
+        [argument, default] => {
+            let (label, call) = call_argument(function, argument, sources)?;
+            let token = match field_param(&call.abi()) {
+                ParamType::Tuple(outputs) if outputs.len() == 1 => {
+                    Token::Tuple(vec![literal_token(function, &outputs[0], default)?])
+                }
+                _ => Err(format!(
+                    "Function {} requires a call with a single return value",
+                    function.name
+                ))?,
+            };
+            Ok((label, DataSource::Coalesce(Box::new(call), token)))
+        }
+        _ => Err(show_function_arity_error(function, 2)),
+    }
+}
+
+fn resolve_function<'a>(
+    function: &query::FunctionCall<'a>,
+    sources: &SourceIndex,
+    params: &[&str],
+) -> Result<(Option<Label<'a>>, DataSource), String> {
+    let account = || address_argument(function, params);
+    match function.name.to_uppercase().as_str() {
+        "BALANCE" => Ok((None, DataSource::Balance(account()?))),
+        "CODESIZE" => Ok((None, DataSource::CodeSize(account()?))),
+        "CODEHASH" => Ok((None, DataSource::CodeHash(account()?))),
+        "IS_CONTRACT" => Ok((None, DataSource::IsContract(account()?))),
+        "SAFE_STRING" => {
+            let (label, call) = single_call_argument(function, sources)?;
+            Ok((Some(label), DataSource::SafeString(Box::new(call))))
+        }
+        "TRY" => {
+            let (label, call) = single_call_argument(function, sources)?;
+            Ok((Some(label), DataSource::Try(Box::new(call))))
+        }
+        "COALESCE" => {
+            let (label, coalesce) = coalesce_arguments(function, sources)?;
+            Ok((Some(label), coalesce))
+        }
+        _ => Err(format!("Unknown function \"{}\"", function.name)),
+    }
+}
+
+pub fn settings(query_set: &[query::Query]) -> Settings {
+    let mut settings = Settings::default();
+    for query in query_set {
+        if let query::Query::Set(option) = query {
+            match option {
+                query::QueryOption::Strict => settings.strict = true,
+                query::QueryOption::AllowMutations => settings.allow_mutations = true,
+                query::QueryOption::GasBudget(budget) => settings.gas_budget = Some(*budget),
+            }
+        }
+    }
+    settings
+}
+
+fn check_mutability(resolution: &Resolution, settings: &Settings) -> Result<(), String> {
+    if resolution.data_source.is_read_only() {
+        return Ok(());
+    }
+    let label = match (&resolution.source, &resolution.name) {
+        (Some(source), Some(name)) => format!("{}.{}", source, name),
+        _ => String::from("call"),
+    };
+    match resolution.data_source {
+        // `safeString` always uses `staticcall`, so there's nothing to opt in to.
+        DataSource::SafeString(_) => Err(format!(
+            "Function SAFE_STRING requires a view or pure function, but {} may modify state",
+            label
+        )),
+        _ if settings.allow_mutations => Ok(()),
+        _ => Err(format!(
+            "{} is not a view or pure function and may modify state. Use SET ALLOW_MUTATIONS to call it anyway",
+            label
+        )),
+    }
+}
+
 pub fn resolve(query_set: &Vec<query::Query>) -> Result<Vec<Resolution>, String> {
-    let mut resolutions: Vec<Resolution> = vec![];
     let all_sources = get_all_sources(query_set)?;
+    resolve_with_sources(query_set, &SourceIndex::new(&all_sources))
+}
+
+fn resolve_selection(
+    selection: &Selection,
+    sources: &SourceIndex,
+    params: &[&str],
+    gas: Option<u64>,
+) -> Result<Option<Resolution>, String> {
+    let resolution = match selection {
+        Selection::Var(fsv) => match resolve_var(fsv, sources)? {
+            Some((label, data_source)) => Resolution {
+                name: Some(String::from(label.name)),
+                source: Some(String::from(label.source)),
+                abi: data_source.abi(),
+                data_source,
+                gas,
+                span: None,
+            },
+            None => return Ok(None),
+        },
+        Selection::Function(function) => {
+            let (label, data_source) = resolve_function(function, sources, params)?;
+            Resolution {
+                name: label.map(|l| String::from(l.name)),
+                source: label.map(|l| String::from(l.source)),
+                abi: data_source.abi(),
+                data_source,
+                gas,
+                span: None,
+            }
+        }
+        Selection::Number(n) => Resolution {
+            name: None,
+            source: None,
+            abi: abi::struct_def::FieldType::Elementary(abi::ParamType::Uint(256)),
+            data_source: DataSource::Number(*n),
+            gas: None,
+            span: None,
+        },
+        Selection::String(s) => Resolution {
+            name: None,
+            source: None,
+            abi: abi::struct_def::FieldType::Elementary(abi::ParamType::String),
+            data_source: DataSource::String(String::from(*s)),
+            gas: None,
+            span: None,
+        },
+        Selection::Param(name) => Resolution {
+            name: Some(String::from(*name)),
+            source: None,
+            abi: abi::struct_def::FieldType::Elementary(abi::ParamType::Address),
+            data_source: DataSource::Param(param_index(name, params)?),
+            gas: None,
+            span: None,
+        },
+        Selection::Hex(h) => Err(format!(
+            "Hex literal {} can only be used as a function argument",
+            h
+        ))?,
+        Selection::Gas(inner, limit) => {
+            let resolution = resolve_selection(inner, sources, params, Some(*limit))?;
+            match resolution {
+                Some(Resolution { gas: None, .. }) | None => Err(format!(
+                    "GAS {} can only limit a contract call, e.g. comet.totalSupply GAS {}",
+                    limit, limit
+                ))?,
+                Some(resolution) => resolution,
+            }
+        }
+    };
+    // Only contract calls spend gas, so a statement-wide `GAS` skips everything else.
+    let gas = if resolution.data_source.is_call() {
+        resolution.gas
+    } else {
+        None
+    };
+    Ok(Some(Resolution { gas, ..resolution }))
+}
+
+fn resolve_select(
+    query: &query::Query,
+    select_query: &query::SelectQuery,
+    params: &[&str],
+    all_sources: &SourceIndex,
+) -> Result<Vec<Resolution>, String> {
+    let sources = sources_for_query(query, all_sources)?;
+    let mut resolutions = vec![];
+    for (i, selection) in select_query.select.iter().enumerate() {
+        if let Some(resolution) = resolve_selection(selection, &sources, params, select_query.gas)?
+        {
+            resolutions.push(Resolution {
+                span: select_query.spans.get(i).copied(),
+                ..resolution
+            });
+        }
+    }
+    Ok(resolutions)
+}
+
+/// Resolves the columns of the plain `SELECT`s in a query set, which make up `query()`.
+pub fn resolve_with_sources(
+    query_set: &[query::Query],
+    all_sources: &SourceIndex,
+) -> Result<Vec<Resolution>, String> {
+    let settings = settings(query_set);
+    let mut resolutions: Vec<Resolution> = vec![];
     for query in query_set.iter() {
         match query {
             query::Query::Select(select_query) => {
-                let sources = sources_for_query(query, &all_sources)?;
-                for selection in &select_query.select {
-                    match selection {
-                        Selection::Var(fsv) => {
-                            // TODO: Handle vars without listed source
-                            if let Some(source) = fsv.source {
-                                let source = find_source(source, &sources)
-                                    .ok_or_else(|| show_missing_source_error(&source, &sources))?;
-                                match fsv.variable {
-                                    query::SelectVar::Var(v) => {
-                                        let data_source =
-                                            find_data_source(v, source).ok_or_else(|| {
-                                                show_missing_variable_error(&v, &source)
-                                            })?;
-                                        resolutions.push(Resolution {
-                                            name: Some(String::from(v)),
-                                            abi: data_source.abi(),
-                                            data_source: data_source.clone(),
-                                        });
-                                    }
-                                    query::SelectVar::Wildcard => todo!(),
-                                }
-                            }
-                        }
-                        Selection::Number(n) => resolutions.push(Resolution {
-                            name: None,
-                            abi: abi::struct_def::FieldType::Elementary(abi::ParamType::Uint(256)),
-                            data_source: DataSource::Number(*n),
-                        }),
-                        Selection::String(s) => resolutions.push(Resolution {
-                            name: None,
-                            abi: abi::struct_def::FieldType::Elementary(abi::ParamType::String),
-                            data_source: DataSource::String(String::from(*s)),
-                        }),
-                    }
-                }
+                resolutions.extend(resolve_select(query, select_query, &[], all_sources)?);
             }
-            &query::Query::Register(_) => (),
+            query::Query::Register(_) | query::Query::Set(_) | query::Query::Named(_) => (),
         }
     }
+    for resolution in &resolutions {
+        check_mutability(resolution, &settings)?;
+    }
     Ok(resolutions)
 }
 
+/// Resolves every query a query set defines: `query()`, if there are plain `SELECT`s or
+/// nothing else, then each named query.
+pub fn resolve_entries(
+    query_set: &[query::Query],
+    all_sources: &SourceIndex,
+) -> Result<Vec<Entry>, String> {
+    let settings = settings(query_set);
+    let mut entries = vec![];
+    let has_select = query_set
+        .iter()
+        .any(|q| matches!(q, query::Query::Select(_)));
+    let has_named = query_set
+        .iter()
+        .any(|q| matches!(q, query::Query::Named(_)));
+    if has_select || !has_named {
+        entries.push(Entry {
+            name: String::from("query"),
+            params: vec![],
+            resolutions: resolve_with_sources(query_set, all_sources)?,
+        });
+    }
+    for query in query_set {
+        if let query::Query::Named(named) = query {
+            if entries.iter().any(|e| e.name == named.name) {
+                return Err(format!("Query {} is defined more than once", named.name));
+            }
+            if let Some((i, param)) = named
+                .params
+                .iter()
+                .enumerate()
+                .find(|(i, p)| named.params[..*i].contains(p))
+            {
+                return Err(format!(
+                    "Parameter :{} of query {} is declared more than once, again at position {}",
+                    param,
+                    named.name,
+                    i + 1
+                ));
+            }
+            let resolutions = resolve_select(query, &named.select, &named.params, all_sources)?;
+            for resolution in &resolutions {
+                check_mutability(resolution, &settings)?;
+            }
+            entries.push(Entry {
+                name: String::from(named.name),
+                params: named.params.iter().map(|p| String::from(*p)).collect(),
+                resolutions,
+            });
+        }
+    }
+    Ok(entries)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::query::{FullSelectVar, Query, RegisterQuery, SelectQuery, SelectVar, Selection};
-    use crate::resolve::{resolve, Resolution};
-    use crate::source::DataSource;
+    use crate::query::{
+        FullSelectVar, FunctionCall, Query, QueryOption, RegisterQuery, SelectQuery, SelectVar,
+        Selection,
+    };
+    use crate::resolve::{resolve, settings, Resolution, Settings};
+    use crate::source::{Account, DataSource};
     use ethers::abi::param_type::ParamType;
     use ethers::abi::struct_def::FieldType;
+    use ethers::abi::Token;
 
     fn query_set<'a>(source: Option<Option<&'a str>>, variable: Option<&'a str>) -> Vec<Query<'a>> {
         vec![
             Query::Register(RegisterQuery {
                 source: "comet",
                 address: "0xc3d688B66703497DAA19211EEdff47f25384cdc3",
-                interface: vec!["function totalSupply() returns (uint256)"],
+                builtin: None,
+                interface: vec!["function totalSupply() view returns (uint256)"],
             }),
             Query::Select(SelectQuery {
                 select: vec![Selection::Var(FullSelectVar {
@@ -107,6 +473,8 @@ mod tests {
                     variable: SelectVar::Var(variable.unwrap_or("number")),
                 })],
                 source: Some("block"),
+                gas: None,
+                spans: vec![],
             }),
         ]
     }
@@ -119,12 +487,307 @@ mod tests {
             resolutions,
             Ok(vec![Resolution {
                 name: Some(String::from("number")),
+                source: Some(String::from("block")),
                 abi: FieldType::Elementary(ParamType::Uint(256)),
-                data_source: DataSource::BlockNumber
+                data_source: DataSource::BlockNumber,
+                gas: None,
+                span: None,
             }])
         );
     }
 
+    fn function_query_set<'a>(name: &'a str, args: Vec<Selection<'a>>) -> Vec<Query<'a>> {
+        vec![Query::Select(SelectQuery {
+            select: vec![Selection::Function(FunctionCall { name, args })],
+            source: None,
+            gas: None,
+            spans: vec![],
+        })]
+    }
+
+    #[test]
+    fn test_valid_resolution_account_builtins() {
+        let address = Account::Address(ethers::types::H160([
+            0xc3, 0xd6, 0x88, 0xB6, 0x67, 0x03, 0x49, 0x7D, 0xAA, 0x19, 0x21, 0x1E, 0xED, 0xff,
+            0x47, 0xf2, 0x53, 0x84, 0xcd, 0xc3,
+        ]));
+        let cases = vec![
+            ("BALANCE", DataSource::Balance(address), ParamType::Uint(256)),
+            ("codesize", DataSource::CodeSize(address), ParamType::Uint(256)),
+            ("CodeHash", DataSource::CodeHash(address), ParamType::FixedBytes(32)),
+            ("IS_CONTRACT", DataSource::IsContract(address), ParamType::Bool),
+        ];
+        for (name, data_source, param_type) in cases {
+            let qs = function_query_set(
+                name,
+                vec![Selection::Hex("0xc3d688B66703497DAA19211EEdff47f25384cdc3")],
+            );
+            assert_eq!(
+                resolve(&qs),
+                Ok(vec![Resolution {
+                    name: None,
+                    source: None,
+                    abi: FieldType::Elementary(param_type),
+                    data_source,
+                    gas: None,
+                    span: None,
+                }])
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_resolution_function_arguments() {
+        assert_eq!(
+            resolve(&function_query_set("BALANCE", vec![])),
+            Err(String::from("Function BALANCE expects 1 argument(s), got 0"))
+        );
+        assert_eq!(
+            resolve(&function_query_set("BALANCE", vec![Selection::Number(5)])),
+            Err(String::from(
+                "Function BALANCE expects an address literal or parameter argument"
+            ))
+        );
+        assert_eq!(
+            resolve(&function_query_set("AGE", vec![])),
+            Err(String::from("Unknown function \"AGE\""))
+        );
+    }
+
+    #[test]
+    fn test_valid_resolution_safe_string() {
+        let qs = vec![
+            Query::Register(RegisterQuery {
+                source: "mkr",
+                address: "0x9f8F72aA9304c8B593d555F12eF6589cC3A579A2",
+                builtin: Some("ERC20"),
+                interface: vec![],
+            }),
+            Query::Select(SelectQuery {
+                select: vec![Selection::Function(FunctionCall {
+                    name: "SAFE_STRING",
+                    args: vec![Selection::Var(FullSelectVar {
+                        source: Some("mkr"),
+                        variable: SelectVar::Var("symbol"),
+                    })],
+                })],
+                source: Some("mkr"),
+                gas: None,
+                spans: vec![],
+            }),
+        ];
+        let resolutions = resolve(&qs).unwrap();
+        assert_eq!(resolutions.len(), 1);
+        assert_eq!(resolutions[0].name, Some(String::from("symbol")));
+        assert_eq!(resolutions[0].abi, FieldType::Elementary(ParamType::String));
+        match &resolutions[0].data_source {
+            DataSource::SafeString(call) => match &**call {
+                DataSource::Call(_, bytes, _, _) => {
+                    assert_eq!(bytes.to_vec(), vec![0x95, 0xd8, 0x9b, 0x41])
+                }
+                ds => panic!("unexpected inner data source: {:?}", ds),
+            },
+            ds => panic!("unexpected data source: {:?}", ds),
+        }
+    }
+
+    #[test]
+    fn test_invalid_resolution_safe_string_non_call() {
+        let qs = query_set(None, None);
+        let qs = vec![
+            qs.into_iter().next().unwrap(),
+            Query::Select(SelectQuery {
+                select: vec![Selection::Function(FunctionCall {
+                    name: "SAFE_STRING",
+                    args: vec![Selection::Var(FullSelectVar {
+                        source: Some("block"),
+                        variable: SelectVar::Var("number"),
+                    })],
+                })],
+                source: Some("block"),
+                gas: None,
+                spans: vec![],
+            }),
+        ];
+        assert_eq!(
+            resolve(&qs),
+            Err(String::from(
+                "Function SAFE_STRING expects a contract call argument, e.g. SAFE_STRING(token.symbol)"
+            ))
+        );
+    }
+
+    fn comet_function_query_set<'a>(name: &'a str, args: Vec<Selection<'a>>) -> Vec<Query<'a>> {
+        vec![
+            Query::Register(RegisterQuery {
+                source: "comet",
+                address: "0xc3d688B66703497DAA19211EEdff47f25384cdc3",
+                builtin: None,
+                interface: vec![
+                    "function totalSupply() view returns (uint256)",
+                    "function symbol() view returns (string)",
+                    "function getReserves() view returns (uint112, uint112, uint32)",
+                    "function accrue() returns (uint256)",
+                ],
+            }),
+            Query::Select(SelectQuery {
+                select: vec![Selection::Function(FunctionCall { name, args })],
+                source: Some("comet"),
+                gas: None,
+                spans: vec![],
+            }),
+        ]
+    }
+
+    fn comet_var<'a>(variable: &'a str) -> Selection<'a> {
+        Selection::Var(FullSelectVar {
+            source: Some("comet"),
+            variable: SelectVar::Var(variable),
+        })
+    }
+
+    #[test]
+    fn test_valid_resolution_try() {
+        let resolutions = resolve(&comet_function_query_set(
+            "TRY",
+            vec![comet_var("totalSupply")],
+        ))
+        .unwrap();
+        assert_eq!(resolutions[0].name, Some(String::from("totalSupply")));
+        assert_eq!(resolutions[0].source, Some(String::from("comet")));
+        assert_eq!(
+            resolutions[0].abi,
+            FieldType::Elementary(ParamType::Tuple(vec![
+                ParamType::Bool,
+                ParamType::Tuple(vec![ParamType::Uint(256)])
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_valid_resolution_coalesce() {
+        let resolutions = resolve(&comet_function_query_set(
+            "coalesce",
+            vec![comet_var("symbol"), Selection::String("???")],
+        ))
+        .unwrap();
+        assert_eq!(
+            resolutions[0].abi,
+            FieldType::Elementary(ParamType::Tuple(vec![ParamType::String]))
+        );
+        match &resolutions[0].data_source {
+            DataSource::Coalesce(_, token) => assert_eq!(
+                token,
+                &Token::Tuple(vec![Token::String(String::from("???"))])
+            ),
+            ds => panic!("unexpected data source: {:?}", ds),
+        }
+    }
+
+    #[test]
+    fn test_invalid_resolution_coalesce() {
+        assert_eq!(
+            resolve(&comet_function_query_set(
+                "COALESCE",
+                vec![comet_var("totalSupply"), Selection::String("none")],
+            )),
+            Err(String::from(
+                "Function COALESCE cannot use String(\"none\") as a default for uint256"
+            ))
+        );
+        assert_eq!(
+            resolve(&comet_function_query_set(
+                "COALESCE",
+                vec![comet_var("getReserves"), Selection::Number(0)],
+            )),
+            Err(String::from(
+                "Function COALESCE requires a call with a single return value"
+            ))
+        );
+        assert_eq!(
+            resolve(&comet_function_query_set(
+                "COALESCE",
+                vec![comet_var("totalSupply")],
+            )),
+            Err(String::from("Function COALESCE expects 2 argument(s), got 1"))
+        );
+    }
+
+    #[test]
+    fn test_settings() {
+        assert_eq!(settings(&query_set(None, None)), Settings::default());
+        let mut qs = query_set(None, None);
+        qs.insert(0, Query::Set(QueryOption::Strict));
+        assert_eq!(
+            settings(&qs),
+            Settings {
+                strict: true,
+                allow_mutations: false,
+                gas_budget: None
+            }
+        );
+        qs.insert(0, Query::Set(QueryOption::AllowMutations));
+        assert_eq!(
+            settings(&qs),
+            Settings {
+                strict: true,
+                allow_mutations: true,
+                gas_budget: None
+            }
+        );
+    }
+
+    #[test]
+    fn test_gas_limits() {
+        let mut qs = comet_function_query_set("TRY", vec![comet_var("totalSupply")]);
+        if let Query::Select(select) = &mut qs[1] {
+            select.select.push(Selection::Gas(Box::new(comet_var("symbol")), 50000));
+            select.select.push(Selection::Number(5));
+            select.gas = Some(200000);
+        }
+        let gas: Vec<Option<u64>> = resolve(&qs).unwrap().iter().map(|r| r.gas).collect();
+        assert_eq!(gas, vec![Some(200000), Some(50000), None]);
+
+        if let Query::Select(select) = &mut qs[1] {
+            select.select.push(Selection::Gas(Box::new(Selection::Number(5)), 100));
+        }
+        assert_eq!(
+            resolve(&qs),
+            Err(String::from(
+                "GAS 100 can only limit a contract call, e.g. comet.totalSupply GAS 100"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_mutability() {
+        let accrue = |function: &'static str| {
+            let mut qs = comet_function_query_set(function, vec![comet_var("accrue")]);
+            qs.insert(0, Query::Set(QueryOption::Strict));
+            qs
+        };
+        assert_eq!(
+            resolve(&accrue("TRY")),
+            Err(String::from(
+                "comet.accrue is not a view or pure function and may modify state. Use SET ALLOW_MUTATIONS to call it anyway"
+            ))
+        );
+
+        let mut qs = accrue("TRY");
+        qs.insert(0, Query::Set(QueryOption::AllowMutations));
+        let resolutions = resolve(&qs).unwrap();
+        assert!(!resolutions[0].data_source.is_read_only());
+
+        let mut qs = accrue("SAFE_STRING");
+        qs.insert(0, Query::Set(QueryOption::AllowMutations));
+        assert_eq!(
+            resolve(&qs),
+            Err(String::from(
+                "Function SAFE_STRING requires a view or pure function, but comet.accrue may modify state"
+            ))
+        );
+    }
+
     // TODO: Test skipping out on source [detection]
     // TODO: Test skipping out on source [err: ambiguous]
     // TODO: Test wildcard
@@ -0,0 +1,283 @@
+use crate::compile::Compiled;
+use ethers::abi::{self, ethabi::AbiError, ParamType, Token};
+use ethers::types::I256;
+use ethers::utils::{hex, to_checksum};
+
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+const CALL_FAILED_SELECTOR: [u8; 4] = [0x5c, 0x0d, 0xee, 0x5d];
+
+/// Errors raised by `Sleuth.sol` itself, rather than by the query.
+const SLEUTH_ERRORS: &[&str] = &["error DeploymentError()"];
+
+fn panic_reason(code: u64) -> &'static str {
+    match code {
+        0x00 => "generic compiler panic",
+        0x01 => "assertion failed",
+        0x11 => "arithmetic underflow or overflow",
+        0x12 => "division or modulo by zero",
+        0x21 => "invalid enum value",
+        0x22 => "invalid storage byte array",
+        0x31 => "pop on empty array",
+        0x32 => "array index out of bounds",
+        0x41 => "out of memory",
+        0x51 => "call to zero-initialized function",
+        _ => "unknown panic code",
+    }
+}
+
+pub fn format_token(token: &Token) -> String {
+    match token {
+        Token::Address(address) => to_checksum(address, None),
+        Token::Uint(value) => value.to_string(),
+        Token::Int(value) => I256::from_raw(*value).to_string(),
+        Token::Bool(value) => value.to_string(),
+        Token::String(value) => format!("{:?}", value),
+        Token::Bytes(value) | Token::FixedBytes(value) => format!("0x{}", hex::encode(value)),
+        Token::Array(tokens) | Token::FixedArray(tokens) => {
+            format!("[{}]", format_tokens(tokens))
+        }
+        Token::Tuple(tokens) => format!("({})", format_tokens(tokens)),
+    }
+}
+
+fn format_tokens(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .map(format_token)
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn describe_custom_error(error: &AbiError, body: &[u8]) -> Option<String> {
+    let kinds: Vec<ParamType> = error.inputs.iter().map(|p| p.kind.clone()).collect();
+    let tokens = abi::decode(&kinds, body).ok()?;
+    let args = error
+        .inputs
+        .iter()
+        .zip(tokens.iter())
+        .map(|(param, token)| {
+            if param.name.is_empty() {
+                format_token(token)
+            } else {
+                format!("{}: {}", param.name, format_token(token))
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+    Some(format!("{}({})", error.name, args))
+}
+
+fn describe_error(data: &[u8], errors: &[AbiError]) -> String {
+    if data.is_empty() {
+        return String::from("reverted without data");
+    }
+    let unknown = || format!("unknown error 0x{}", hex::encode(data));
+    if data.len() < 4 {
+        return unknown();
+    }
+    let (selector, body) = data.split_at(4);
+    if selector == ERROR_SELECTOR {
+        if let Ok(tokens) = abi::decode(&[ParamType::String], body) {
+            return format!("Error({})", format_token(&tokens[0]));
+        }
+    } else if selector == PANIC_SELECTOR {
+        if let Ok(tokens) = abi::decode(&[ParamType::Uint(256)], body) {
+            if let Token::Uint(code) = tokens[0] {
+                let reason = if code.bits() <= 64 {
+                    panic_reason(code.as_u64())
+                } else {
+                    panic_reason(u64::MAX)
+                };
+                return format!("Panic(0x{:02x}: {})", code, reason);
+            }
+        }
+    } else {
+        for error in errors {
+            if error.signature()[..4] == *selector {
+                if let Some(description) = describe_custom_error(error, body) {
+                    return description;
+                }
+            }
+        }
+    }
+    unknown()
+}
+
+/// Describes the revert data returned from running a compiled query, unwrapping
+/// `CallFailed` errors raised by `SET STRICT` queries into the failing column.
+pub fn describe_revert(compiled: &Compiled, data: &[u8]) -> Result<String, String> {
+    let signatures: Vec<&str> = compiled
+        .errors
+        .iter()
+        .map(String::as_str)
+        .chain(SLEUTH_ERRORS.iter().copied())
+        .collect();
+    let errors: Vec<AbiError> = abi::parse_abi(&signatures)
+        .map_err(|e| format!("Error parsing error definitions: {:?}", e))?
+        .errors()
+        .cloned()
+        .collect();
+
+    if data.len() >= 4 && data[..4] == CALL_FAILED_SELECTOR {
+        if let Ok(tokens) = abi::decode(&[ParamType::Uint(256), ParamType::Bytes], &data[4..]) {
+            if let [Token::Uint(column), Token::Bytes(inner)] = &tokens[..] {
+                let label = compiled
+                    .columns
+                    .get(column.low_u64() as usize)
+                    .map(|c| c.label(column.low_u64() as usize))
+                    .unwrap_or_else(|| format!("column {}", column));
+                return Ok(format!(
+                    "{} reverted: {}",
+                    label,
+                    describe_error(inner, &errors)
+                ));
+            }
+        }
+    }
+    Ok(format!("Query reverted: {}", describe_error(data, &errors)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::{Column, Compiled};
+    use crate::estimate::Estimate;
+    use crate::revert::{describe_revert, format_token};
+    use ethers::abi::{self, Token};
+    use ethers::types::{Address, U256};
+    use std::convert::TryInto;
+
+    fn compiled() -> Compiled {
+        Compiled {
+            tuple: String::from("tuple(tuple(uint256) totalSupply)"),
+            yul: String::new(),
+            bytecode: String::new(),
+            initcode: None,
+            strict: true,
+            read_only: true,
+            columns: vec![Column {
+                name: Some(String::from("totalSupply")),
+                source: Some(String::from("comet")),
+                ty: String::from("tuple(uint256)"),
+                text: None,
+                span: None,
+            }],
+            queries: vec![],
+            errors: vec![String::from("error Unauthorized(address caller, uint256)")],
+            source_map: vec![],
+            estimate: Estimate::default(),
+        }
+    }
+
+    fn with_selector(selector: [u8; 4], tokens: &[Token]) -> Vec<u8> {
+        [selector.to_vec(), abi::encode(tokens)].concat()
+    }
+
+    fn call_failed(column: u64, inner: Vec<u8>) -> Vec<u8> {
+        with_selector(
+            [0x5c, 0x0d, 0xee, 0x5d],
+            &[Token::Uint(U256::from(column)), Token::Bytes(inner)],
+        )
+    }
+
+    #[test]
+    fn describe_error_string() {
+        let data = call_failed(
+            0,
+            with_selector(
+                [0x08, 0xc3, 0x79, 0xa0],
+                &[Token::String(String::from("paused"))],
+            ),
+        );
+        assert_eq!(
+            describe_revert(&compiled(), &data),
+            Ok(String::from("comet.totalSupply reverted: Error(\"paused\")"))
+        );
+    }
+
+    #[test]
+    fn describe_panic() {
+        let data = call_failed(
+            0,
+            with_selector([0x4e, 0x48, 0x7b, 0x71], &[Token::Uint(U256::from(0x11))]),
+        );
+        assert_eq!(
+            describe_revert(&compiled(), &data),
+            Ok(String::from(
+                "comet.totalSupply reverted: Panic(0x11: arithmetic underflow or overflow)"
+            ))
+        );
+    }
+
+    #[test]
+    fn describe_custom_error() {
+        let error = abi::parse_abi(&["error Unauthorized(address caller, uint256)"])
+            .unwrap()
+            .errors()
+            .next()
+            .unwrap()
+            .clone();
+        let selector: [u8; 4] = error.signature()[..4].try_into().unwrap();
+        let data = call_failed(
+            0,
+            with_selector(
+                selector,
+                &[Token::Address(Address::zero()), Token::Uint(U256::from(7))],
+            ),
+        );
+        assert_eq!(
+            describe_revert(&compiled(), &data),
+            Ok(String::from(
+                "comet.totalSupply reverted: Unauthorized(caller: 0x0000000000000000000000000000000000000000, 7)"
+            ))
+        );
+    }
+
+    #[test]
+    fn describe_unknown_and_empty() {
+        assert_eq!(
+            describe_revert(&compiled(), &call_failed(0, vec![])),
+            Ok(String::from("comet.totalSupply reverted: reverted without data"))
+        );
+        assert_eq!(
+            describe_revert(&compiled(), &[0xde, 0xad, 0xbe, 0xef]),
+            Ok(String::from("Query reverted: unknown error 0xdeadbeef"))
+        );
+        assert_eq!(
+            describe_revert(&compiled(), &call_failed(4, vec![0x01])),
+            Ok(String::from("column 4 reverted: unknown error 0x01"))
+        );
+    }
+
+    #[test]
+    fn describe_column_text() {
+        let mut compiled = compiled();
+        compiled.columns[0].text = Some(String::from("comet.totalSupply GAS 50000"));
+        assert_eq!(
+            describe_revert(&compiled, &call_failed(0, vec![])),
+            Ok(String::from(
+                "column 0 (`comet.totalSupply GAS 50000`) reverted: reverted without data"
+            ))
+        );
+    }
+
+    #[test]
+    fn describe_sleuth_error() {
+        assert_eq!(
+            describe_revert(&compiled(), &[0x71, 0x0b, 0xa0, 0x88]),
+            Ok(String::from("Query reverted: DeploymentError()"))
+        );
+    }
+
+    #[test]
+    fn format_tokens() {
+        assert_eq!(
+            format_token(&Token::Tuple(vec![
+                Token::Int(U256::MAX),
+                Token::Bool(true),
+                Token::Array(vec![Token::Bytes(vec![0xab])]),
+            ])),
+            "(-1, true, [0xab])"
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use crate::compile::{compile_multicall_with_sources, compile_with_sources, Compiled};
+use crate::multicall::Multicall;
+use crate::parse::parse_query_cls;
+use crate::query::Query;
+use crate::resolve::resolve_entries;
+use crate::source::{get_all_sources, get_source_from_register, Interfaces, Source, SourceIndex};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Contracts registered once and shared by every query compiled against them, so that
+/// their interfaces are only parsed when they're registered, and only once each however many
+/// contracts use them.
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    sources: Vec<Source>,
+    by_name: HashMap<Rc<str>, usize>,
+    interfaces: Interfaces,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session::default()
+    }
+
+    /// Registers the contracts of one or more `REGISTER CONTRACT` statements, replacing any
+    /// registered before under the same name. Nothing is registered if any of them fails.
+    pub fn register(&mut self, registrations: &str) -> Result<(), String> {
+        let mut sources = vec![];
+        for query in parse_query_cls(registrations)? {
+            match query {
+                Query::Register(register) => {
+                    sources.push(get_source_from_register(&register, &mut self.interfaces)?)
+                }
+                _ => {
+                    return Err(String::from(
+                        "A session can only register contracts, with REGISTER CONTRACT",
+                    ))
+                }
+            }
+        }
+        for source in sources {
+            match self.by_name.get(&source.name) {
+                Some(&i) => self.sources[i] = source,
+                None => {
+                    self.by_name.insert(source.name.clone(), self.sources.len());
+                    self.sources.push(source);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The names of the registered contracts, in the order they were first registered.
+    pub fn source_names(&self) -> Vec<&str> {
+        self.sources.iter().map(|s| &*s.name).collect()
+    }
+
+    // The query's own sources, then the registered contracts it selects from. A contract
+    // the query registers itself hides one of the session's with the same name.
+    fn sources<'a>(&'a self, query_set: &[Query], own: &'a [Source]) -> SourceIndex<'a> {
+        let mut sources = SourceIndex::new(own);
+        for query in query_set {
+            let select = match query {
+                Query::Select(select) => select,
+                Query::Named(named) => &named.select,
+                _ => continue,
+            };
+            if let Some(name) = select.source {
+                if sources.get(name).is_none() {
+                    if let Some(&i) = self.by_name.get(name) {
+                        sources.push(&self.sources[i]);
+                    }
+                }
+            }
+        }
+        sources
+    }
+
+    /// Compiles a query, as `compile::compile`, which may select from registered contracts.
+    pub fn compile(&self, query: &str) -> Result<Compiled, String> {
+        let query_set = parse_query_cls(query)?;
+        let own = get_all_sources(&query_set)?;
+        compile_with_sources(query, &query_set, &self.sources(&query_set, &own))
+    }
+
+    /// Checks a query, as `compile::check`, which may select from registered contracts.
+    pub fn check(&self, query: &str) -> Result<(), String> {
+        let query_set = parse_query_cls(query)?;
+        let own = get_all_sources(&query_set)?;
+        resolve_entries(&query_set, &self.sources(&query_set, &own))?;
+        Ok(())
+    }
+
+    /// Compiles a query to run through Multicall3, as `compile::compile_multicall`.
+    pub fn compile_multicall(&self, query: &str) -> Result<Multicall, String> {
+        let query_set = parse_query_cls(query)?;
+        let own = get_all_sources(&query_set)?;
+        compile_multicall_with_sources(&query_set, &self.sources(&query_set, &own))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::compile;
+    use crate::session::Session;
+
+    const COMET: &str = r#"REGISTER CONTRACT comet AT 0xc3d688B66703497DAA19211EEdff47f25384cdc3 WITH INTERFACE [
+        "function totalSupply() view returns (uint256)",
+        "error Paused()"
+    ];"#;
+
+    #[test]
+    fn compile_against_registered_contracts() {
+        let mut session = Session::new();
+        session.register(COMET).unwrap();
+        session
+            .register(
+                r#"REGISTER CONTRACT usdc AT 0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48 AS ERC20"#,
+            )
+            .unwrap();
+        assert_eq!(session.source_names(), vec!["comet", "usdc"]);
+
+        let query = "SELECT comet.totalSupply FROM comet";
+        let compiled = session.compile(query).unwrap();
+        let expected = compile(&format!("{}\n{}", COMET, query)).unwrap();
+        assert_eq!(compiled.bytecode, expected.bytecode);
+        assert_eq!(compiled.tuple, expected.tuple);
+        assert_eq!(compiled.errors, vec![String::from("error Paused()")]);
+        assert_eq!(compiled.columns[0].span, Some((7, 24)));
+        assert!(session.compile("SELECT usdc.decimals FROM usdc").is_ok());
+        assert!(session.compile_multicall(query).is_ok());
+        assert_eq!(session.check(query), Ok(()));
+        assert_eq!(
+            Session::new().check(query),
+            Err(String::from(
+                "No such relation \"comet\" referenced in FROM clause"
+            ))
+        );
+    }
+
+    #[test]
+    fn register_contracts() {
+        let mut session = Session::new();
+        session.register(COMET).unwrap();
+        // Registering a name again replaces it, and a query's own registration hides it.
+        session
+            .register(r#"REGISTER CONTRACT comet AT 0xc3d688B66703497DAA19211EEdff47f25384cdc3 WITH INTERFACE ["function name() view returns (string)"]"#)
+            .unwrap();
+        assert_eq!(session.source_names(), vec!["comet"]);
+        assert!(session
+            .check("SELECT comet.totalSupply FROM comet")
+            .is_err());
+        assert!(session.check("SELECT comet.name FROM comet").is_ok());
+        assert!(session
+            .check(&format!("{} SELECT comet.totalSupply FROM comet", COMET))
+            .is_ok());
+
+        assert_eq!(
+            session.register("SELECT 5"),
+            Err(String::from(
+                "A session can only register contracts, with REGISTER CONTRACT"
+            ))
+        );
+        assert!(session
+            .register(r#"REGISTER CONTRACT cat AT 0x01 WITH INTERFACE []"#)
+            .is_err());
+        assert_eq!(session.source_names(), vec!["comet"]);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::abi::parse_type;
+use crate::compile::{Compiled, CompiledQuery};
+use ethers::abi::{self, Address, ParamType, Token};
+use ethers::utils::{get_contract_address, hex, id};
+
+/// The account which deploys each version of `Sleuth.sol`.
+pub const SLEUTH_DEPLOYER: &str = "0x84C3e20985d9E7aEc46F80d2EB52b731D8CC40F8";
+
+/// Where `Sleuth.sol` is deployed: version `n` is the deployer's `n`th contract.
+pub fn sleuth_address(deployer: Address, version: u64) -> Result<Address, String> {
+    if version == 0 {
+        return Err(String::from("Sleuth versions start at 1"));
+    }
+    Ok(get_contract_address(deployer, version - 1))
+}
+
+fn hex_bytes(value: &str) -> Result<Vec<u8>, String> {
+    hex::decode(value.trim_start_matches("0x")).map_err(|e| format!("Invalid hex {}: {}", value, e))
+}
+
+/// The compiled query's function with the given name.
+pub fn find_query<'a>(compiled: &'a Compiled, name: &str) -> Result<&'a CompiledQuery, String> {
+    compiled
+        .queries
+        .iter()
+        .find(|q| q.name == name)
+        .ok_or_else(|| format!("Query does not define {}", name))
+}
+
+/// Calldata calling one of the compiled contract's queries, with an address for each of its
+/// parameters.
+pub fn encode_call(query: &CompiledQuery, args: &[Address]) -> Result<Vec<u8>, String> {
+    if args.len() != query.params.len() {
+        return Err(format!(
+            "{} expects {} argument(s), got {}",
+            query.signature,
+            query.params.len(),
+            args.len()
+        ));
+    }
+    let args: Vec<Token> = args.iter().map(|arg| Token::Address(*arg)).collect();
+    Ok([hex_bytes(&query.selector)?, abi::encode(&args)].concat())
+}
+
+/// Calldata for `Sleuth.query(bytes)`, which deploys `bytecode` and calls its `query()`.
+pub fn encode_query(bytecode: &[u8]) -> Vec<u8> {
+    [
+        id("query(bytes)").to_vec(),
+        abi::encode(&[Token::Bytes(bytecode.to_vec())]),
+    ]
+    .concat()
+}
+
+/// Calldata for `Sleuth.query(bytes,bytes)`, which deploys `bytecode` and calls it with
+/// `calldata`.
+pub fn encode_query_with_calldata(bytecode: &[u8], calldata: &[u8]) -> Vec<u8> {
+    [
+        id("query(bytes,bytes)").to_vec(),
+        abi::encode(&[
+            Token::Bytes(bytecode.to_vec()),
+            Token::Bytes(calldata.to_vec()),
+        ]),
+    ]
+    .concat()
+}
+
+/// Calldata for `Sleuth.sol` which runs the named query of a compiled query with `args`.
+/// `query()` is run through the `query(bytes)` overload, and other queries with
+/// `query(bytes,bytes)`.
+pub fn encode_request(
+    compiled: &Compiled,
+    name: &str,
+    args: &[Address],
+) -> Result<Vec<u8>, String> {
+    let query = find_query(compiled, name)?;
+    let bytecode = hex_bytes(&compiled.bytecode)?;
+    let calldata = encode_call(query, args)?;
+    if query.signature == "query()" {
+        Ok(encode_query(&bytecode))
+    } else {
+        Ok(encode_query_with_calldata(&bytecode, &calldata))
+    }
+}
+
+/// Decodes what `Sleuth.query` returned for the named query into its columns.
+pub fn decode_response(
+    compiled: &Compiled,
+    name: &str,
+    return_data: &[u8],
+) -> Result<Vec<Token>, String> {
+    let query = find_query(compiled, name)?;
+    let result = match abi::decode(&[ParamType::Bytes], return_data) {
+        Ok(tokens) => match &tokens[..] {
+            [Token::Bytes(result)] => result.clone(),
+            _ => unreachable!(),
+        },
+        Err(e) => return Err(format!("Invalid Sleuth result: {}", e)),
+    };
+    decode_result(query, &result)
+}
+
+/// Decodes what a query's function returned into its columns.
+pub fn decode_result(query: &CompiledQuery, result: &[u8]) -> Result<Vec<Token>, String> {
+    let types = query
+        .columns
+        .iter()
+        .map(|column| parse_type(&column.ty))
+        .collect::<Result<Vec<ParamType>, String>>()?;
+    abi::decode(&types, result)
+        .map_err(|e| format!("Invalid result for {}: {}", query.signature, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compile::compile;
+    use crate::sleuth::{
+        decode_response, encode_query, encode_query_with_calldata, encode_request, sleuth_address,
+        SLEUTH_DEPLOYER,
+    };
+    use ethers::abi::{self, Address, Token};
+    use ethers::utils::hex;
+
+    #[test]
+    fn sleuth_addresses() {
+        let deployer: Address = SLEUTH_DEPLOYER.parse().unwrap();
+        assert_eq!(
+            sleuth_address(deployer, 1),
+            Ok(ethers::utils::get_contract_address(deployer, 0))
+        );
+        assert_ne!(sleuth_address(deployer, 1), sleuth_address(deployer, 2));
+        assert_eq!(
+            sleuth_address(deployer, 0),
+            Err(String::from("Sleuth versions start at 1"))
+        );
+    }
+
+    #[test]
+    fn encode_query_overloads() {
+        assert_eq!(
+            hex::encode(encode_query(&[0xaa])),
+            [
+                "ed815d83",
+                "0000000000000000000000000000000000000000000000000000000000000020",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "aa00000000000000000000000000000000000000000000000000000000000000",
+            ]
+            .concat()
+        );
+        assert_eq!(
+            hex::encode(encode_query_with_calldata(&[0xaa], &[0xbb, 0xcc])),
+            [
+                "34686eaf",
+                "0000000000000000000000000000000000000000000000000000000000000040",
+                "0000000000000000000000000000000000000000000000000000000000000080",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "aa00000000000000000000000000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "bbcc000000000000000000000000000000000000000000000000000000000000",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn request_and_response() {
+        let compiled = compile("SELECT 5; QUERY account(:a) AS SELECT :a, BALANCE(:a)").unwrap();
+        let bytecode = hex::decode(&compiled.bytecode[2..]).unwrap();
+        let account = Address::repeat_byte(0x11);
+
+        assert_eq!(
+            encode_request(&compiled, "query", &[]),
+            Ok(encode_query(&bytecode))
+        );
+        let mut calldata = hex::decode(&compiled.queries[1].selector[2..]).unwrap();
+        calldata.extend(abi::encode(&[Token::Address(account)]));
+        assert_eq!(
+            encode_request(&compiled, "account", &[account]),
+            Ok(encode_query_with_calldata(&bytecode, &calldata))
+        );
+        assert_eq!(
+            encode_request(&compiled, "account", &[]),
+            Err(String::from(
+                "account(address) expects 1 argument(s), got 0"
+            ))
+        );
+        assert_eq!(
+            encode_request(&compiled, "markets", &[]),
+            Err(String::from("Query does not define markets"))
+        );
+
+        let tokens = vec![Token::Address(account), Token::Uint(7.into())];
+        let response = abi::encode(&[Token::Bytes(abi::encode(&tokens))]);
+        assert_eq!(decode_response(&compiled, "account", &response), Ok(tokens));
+        assert!(decode_response(&compiled, "account", &[]).is_err());
+    }
+}
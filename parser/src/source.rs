@@ -1,39 +1,141 @@
+use crate::abi::field_param;
+use crate::interfaces::{builtin_interface, BUILTIN_INTERFACES};
 use crate::query;
-use ethers::abi::{self, struct_def::FieldType, Address, ParamType};
+use ethers::abi::{self, struct_def::FieldType, Address, ParamType, StateMutability};
 use ethers::types::{Bytes, H160};
 use ethers::utils::hex::FromHex;
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+/// An account a query reads: a fixed address, or one of a named query's parameters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Account {
+    Address(Address),
+    Param(usize),
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum DataSource {
     BlockNumber,
     Number(u64),
     String(String),
-    Call(Address, Bytes, abi::struct_def::FieldType),
+    Call(Address, Bytes, abi::struct_def::FieldType, StateMutability),
+    Balance(Account),
+    CodeSize(Account),
+    CodeHash(Account),
+    IsContract(Account),
+    /// A named query's parameter, which is always an address.
+    Param(usize),
+    SafeString(Box<DataSource>),
+    Try(Box<DataSource>),
+    Coalesce(Box<DataSource>, abi::Token),
 }
 
 impl DataSource {
     pub fn abi(&self) -> abi::struct_def::FieldType {
         match self {
-            DataSource::BlockNumber | DataSource::Number(_) => {
+            DataSource::BlockNumber
+            | DataSource::Number(_)
+            | DataSource::Balance(_)
+            | DataSource::CodeSize(_) => {
                 abi::struct_def::FieldType::Elementary(abi::ParamType::Uint(256))
             }
-            DataSource::String(_) => abi::struct_def::FieldType::Elementary(abi::ParamType::String),
-            DataSource::Call(_, _, abi) => abi.clone(),
+            DataSource::CodeHash(_) => {
+                abi::struct_def::FieldType::Elementary(abi::ParamType::FixedBytes(32))
+            }
+            DataSource::IsContract(_) => abi::struct_def::FieldType::Elementary(abi::ParamType::Bool),
+            DataSource::Param(_) => abi::struct_def::FieldType::Elementary(abi::ParamType::Address),
+            DataSource::String(_) | DataSource::SafeString(_) => {
+                abi::struct_def::FieldType::Elementary(abi::ParamType::String)
+            }
+            DataSource::Call(_, _, abi, _) => abi.clone(),
+            DataSource::Try(call) => FieldType::Elementary(ParamType::Tuple(vec![
+                ParamType::Bool,
+                field_param(&call.abi()).clone(),
+            ])),
+            DataSource::Coalesce(call, _) => call.abi(),
+        }
+    }
+
+    pub fn is_call(&self) -> bool {
+        matches!(
+            self,
+            DataSource::Call(..)
+                | DataSource::SafeString(_)
+                | DataSource::Try(_)
+                | DataSource::Coalesce(..)
+        )
+    }
+
+    /// Whether reading this value can never modify state, i.e. any contract call it makes
+    /// is to a `view` or `pure` function.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            DataSource::Call(_, _, _, mutability) => {
+                matches!(mutability, StateMutability::View | StateMutability::Pure)
+            }
+            DataSource::SafeString(call)
+            | DataSource::Try(call)
+            | DataSource::Coalesce(call, _) => call.is_read_only(),
+            _ => true,
         }
     }
 }
 
+/// The zero-argument functions an interface declares, by name, and the errors it declares.
+/// Contracts registered with the same interface share one of these.
+#[derive(Debug, Default, PartialEq)]
+pub struct Interface {
+    functions: HashMap<String, Function>,
+    errors: Vec<abi::ethabi::AbiError>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Function {
+    calldata: Bytes,
+    abi: FieldType,
+    mutability: StateMutability,
+}
+
+/// What a source's variables read: the current block, or a registered contract.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Relation {
+    Block,
+    Contract(Address, Rc<Interface>),
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Source {
-    pub name: String,
-    pub mappings: HashMap<String, DataSource>,
+    pub name: Rc<str>,
+    pub relation: Relation,
+}
+
+impl Source {
+    /// The names of the variables a query can select from this source.
+    pub fn variables(&self) -> Vec<&str> {
+        match &self.relation {
+            Relation::Block => vec!["number"],
+            Relation::Contract(_, interface) => {
+                interface.functions.keys().map(String::as_str).collect()
+            }
+        }
+    }
+
+    /// The errors declared by the source's interface.
+    pub fn errors(&self) -> &[abi::ethabi::AbiError] {
+        match &self.relation {
+            Relation::Block => &[],
+            Relation::Contract(_, interface) => &interface.errors,
+        }
+    }
 }
 
 fn block_source() -> Source {
     Source {
-        name: String::from("block"),
-        mappings: HashMap::from([(String::from("number"), DataSource::BlockNumber)]),
+        name: Rc::from("block"),
+        relation: Relation::Block,
     }
 }
 
@@ -41,10 +143,10 @@ fn builtin_sources() -> Vec<Source> {
     vec![block_source()]
 }
 
-fn get_address(s: &str) -> Result<Address, String> {
+pub fn get_address(s: &str) -> Result<Address, String> {
     let inner = s
         .strip_prefix("0x")
-        .ok_or(format!("Error: address should begin with 0x.."))?
+        .ok_or_else(|| String::from("Error: address should begin with 0x.."))?
         .to_string();
 
     let address_bytes =
@@ -57,65 +159,171 @@ fn function_outputs_to_abi(outputs: Vec<ethers::abi::Param>) -> abi::struct_def:
     FieldType::Elementary(ParamType::Tuple(params))
 }
 
-fn get_source_from_register(query: &query::RegisterQuery) -> Result<Source, String> {
-    let address = get_address(query.address)?;
-    let contract = ethers::abi::parse_abi(&query.interface)
-        .map_err(|e| format!("Error parsing interface for {}: {:?}", &query.source, e))?;
-    let mappings: HashMap<String, DataSource> = contract
+fn get_register_interface<'a>(query: &query::RegisterQuery<'a>) -> Result<Vec<&'a str>, String> {
+    let mut interface = match query.builtin {
+        Some(name) => builtin_interface(name).ok_or_else(|| {
+            format!(
+                "Unknown interface \"{}\" for {}. Builtin interfaces: {}",
+                name,
+                query.source,
+                BUILTIN_INTERFACES.join(",")
+            )
+        })?,
+        None => vec![],
+    };
+    interface.extend(query.interface.iter());
+    Ok(interface)
+}
+
+fn parse_interface(source: &str, declarations: &[&str]) -> Result<Interface, String> {
+    let contract = ethers::abi::parse_abi(declarations)
+        .map_err(|e| format!("Error parsing interface for {}: {:?}", source, e))?;
+    let errors = contract.errors().cloned().collect();
+    let functions = contract
         .functions
         .into_iter()
         .filter_map(
-            |(name, fs)| match fs.into_iter().find(|f| f.inputs.len() == 0) {
+            |(name, fs)| match fs.into_iter().find(|f| f.inputs.is_empty()) {
                 Some(f) => {
-                    let bytes = Bytes::from(f.encode_input(&vec![]).ok()?);
-                    Some((
-                        name,
-                        DataSource::Call(address, bytes, function_outputs_to_abi(f.outputs)),
-                    ))
+                    let function = Function {
+                        calldata: Bytes::from(f.encode_input(&[]).ok()?),
+                        abi: function_outputs_to_abi(f.outputs),
+                        mutability: f.state_mutability,
+                    };
+                    Some((name, function))
                 }
                 None => None,
             },
         )
         .collect();
-    // TODO: Set call data
+    Ok(Interface { functions, errors })
+}
+
+// An interface and the declarations it was parsed from.
+type Parsed = (Vec<String>, Rc<Interface>);
+
+/// The interfaces parsed and the source names registered so far, so that each interface is
+/// parsed once and each name is stored once, however many contracts are registered with them.
+#[derive(Clone, Debug, Default)]
+pub struct Interfaces {
+    // By a hash of their declarations, which are kept to tell apart any that collide.
+    parsed: HashMap<u64, Vec<Parsed>>,
+    names: HashSet<Rc<str>>,
+}
+
+impl Interfaces {
+    fn parse(&mut self, source: &str, declarations: &[&str]) -> Result<Rc<Interface>, String> {
+        let mut hasher = DefaultHasher::new();
+        declarations.hash(&mut hasher);
+        let parsed = self.parsed.entry(hasher.finish()).or_default();
+        let found = parsed.iter().find(|(known, _)| {
+            known
+                .iter()
+                .map(String::as_str)
+                .eq(declarations.iter().copied())
+        });
+        if let Some((_, interface)) = found {
+            return Ok(interface.clone());
+        }
+        let interface = Rc::new(parse_interface(source, declarations)?);
+        let known = declarations.iter().map(|d| d.to_string()).collect();
+        parsed.push((known, interface.clone()));
+        Ok(interface)
+    }
+
+    fn intern(&mut self, name: &str) -> Rc<str> {
+        match self.names.get(name) {
+            Some(name) => name.clone(),
+            None => {
+                let name: Rc<str> = Rc::from(name);
+                self.names.insert(name.clone());
+                name
+            }
+        }
+    }
+}
+
+pub fn get_source_from_register(
+    query: &query::RegisterQuery,
+    interfaces: &mut Interfaces,
+) -> Result<Source, String> {
+    let address = get_address(query.address)?;
+    let interface = interfaces.parse(query.source, &get_register_interface(query)?)?;
     Ok(Source {
-        name: query.source.to_string(),
-        mappings,
+        name: interfaces.intern(query.source),
+        relation: Relation::Contract(address, interface),
     })
 }
 
-pub fn find_source<'a, 'b>(name: &'a str, sources: &'b Vec<Source>) -> Option<&'b Source> {
-    sources.iter().find(|&source| source.name == name)
+/// Borrowed sources, indexed by name. When two share a name, the first one added is found.
+#[derive(Clone, Debug, Default)]
+pub struct SourceIndex<'a> {
+    sources: Vec<&'a Source>,
+    by_name: HashMap<&'a str, usize>,
 }
 
-pub fn find_data_source<'a, 'b>(name: &'a str, source: &'b Source) -> Option<&'b DataSource> {
-    source.mappings.get(name)
+impl<'a> SourceIndex<'a> {
+    pub fn new(sources: &'a [Source]) -> Self {
+        let mut index = SourceIndex::default();
+        for source in sources {
+            index.push(source);
+        }
+        index
+    }
+
+    pub fn push(&mut self, source: &'a Source) {
+        self.by_name
+            .entry(&*source.name)
+            .or_insert(self.sources.len());
+        self.sources.push(source);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&'a Source> {
+        self.by_name.get(name).map(|&i| self.sources[i])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'a Source> + '_ {
+        self.sources.iter().copied()
+    }
+}
+
+pub fn find_data_source(name: &str, source: &Source) -> Option<DataSource> {
+    match &source.relation {
+        Relation::Block => (name == "number").then_some(DataSource::BlockNumber),
+        Relation::Contract(address, interface) => interface.functions.get(name).map(|function| {
+            DataSource::Call(
+                *address,
+                function.calldata.clone(),
+                function.abi.clone(),
+                function.mutability,
+            )
+        }),
+    }
 }
 
-pub fn get_all_sources(query_set: &Vec<query::Query>) -> Result<Vec<Source>, String> {
+pub fn get_all_sources(query_set: &[query::Query]) -> Result<Vec<Source>, String> {
     let mut all_sources = builtin_sources();
+    let mut interfaces = Interfaces::default();
     for query in query_set {
-        match query {
-            query::Query::Register(register) => {
-                all_sources.push(get_source_from_register(register)?);
-            }
-            _ => (),
+        if let query::Query::Register(register) = query {
+            all_sources.push(get_source_from_register(register, &mut interfaces)?);
         }
     }
     Ok(all_sources)
 }
 
-pub fn sources_for_query(
+/// The sources a query selects from, in its `FROM` clause.
+pub fn sources_for_query<'a>(
     query: &query::Query,
-    all_sources: &Vec<Source>,
-) -> Result<Vec<Source>, String> {
-    let mut res: Vec<Source> = vec![];
+    all_sources: &SourceIndex<'a>,
+) -> Result<SourceIndex<'a>, String> {
+    let mut res = SourceIndex::default();
     match query {
-        query::Query::Select(select) => {
+        query::Query::Select(select) | query::Query::Named(query::NamedQuery { select, .. }) => {
             if let Some(name) = select.source {
-                match find_source(name, &all_sources) {
+                match all_sources.get(name) {
                     Some(source) => {
-                        res.push(source.clone());
+                        res.push(source);
                     }
                     None => Err(format!(
                         "No such relation \"{}\" referenced in FROM clause",
@@ -124,7 +332,7 @@ pub fn sources_for_query(
                 }
             }
         }
-        query::Query::Register(_) => (),
+        query::Query::Register(_) | query::Query::Set(_) => (),
     }
     Ok(res)
 }
@@ -133,11 +341,12 @@ pub fn sources_for_query(
 mod tests {
     use crate::query::{FullSelectVar, Query, RegisterQuery, SelectQuery, SelectVar, Selection};
     use crate::source::{
-        block_source, find_data_source, find_source, get_address, get_all_sources,
-        sources_for_query, DataSource, Source,
+        block_source, find_data_source, get_address, get_all_sources, sources_for_query,
+        DataSource, Function, Interface, Interfaces, Relation, Source, SourceIndex,
     };
     use ethers::types::H160;
     use std::collections::HashMap;
+    use std::rc::Rc;
     use ethers::abi;
     use ethers::types::Bytes;
 
@@ -148,6 +357,8 @@ mod tests {
                 variable: SelectVar::Var("number"),
             })],
             source: Some(source.unwrap_or("block")),
+            gas: None,
+            spans: vec![],
         })
     }
 
@@ -155,23 +366,31 @@ mod tests {
         Query::Register(RegisterQuery {
             source: "comet",
             address: "0xc3d688B66703497DAA19211EEdff47f25384cdc3",
-            interface: vec!["function totalSupply() returns (uint256)"],
+            builtin: None,
+            interface: vec!["function totalSupply() view returns (uint256)"],
         })
     }
 
     fn comet_source() -> Source {
+        let total_supply = Function {
+            calldata: Bytes::from([0x18, 0x16, 0x0d, 0xdd]),
+            abi: abi::struct_def::FieldType::Elementary(ethers::abi::param_type::ParamType::Tuple(
+                vec![abi::ParamType::Uint(256)],
+            )),
+            mutability: abi::StateMutability::View,
+        };
         Source {
-            name: String::from("comet"),
-            mappings: HashMap::from([(String::from("totalSupply"), DataSource::Call(
+            name: Rc::from("comet"),
+            relation: Relation::Contract(
                 ethers::types::H160([
-                    0xc3, 0xd6, 0x88, 0xB6, 0x67, 0x03, 0x49, 0x7D, 0xAA, 0x19, 0x21, 0x1E, 0xEd,
+                    0xc3, 0xd6, 0x88, 0xB6, 0x67, 0x03, 0x49, 0x7D, 0xAA, 0x19, 0x21, 0x1E, 0xED,
                     0xff, 0x47, 0xf2, 0x53, 0x84, 0xcd, 0xc3,
                 ]),
-                Bytes::from([0x18, 0x16, 0x0d, 0xdd]),
-                abi::struct_def::FieldType::Elementary(ethers::abi::param_type::ParamType::Tuple(
-                    vec![abi::ParamType::Uint(256)],
-                )),
-            ))]),
+                Rc::new(Interface {
+                    functions: HashMap::from([(String::from("totalSupply"), total_supply)]),
+                    errors: vec![],
+                }),
+            ),
         }
     }
 
@@ -180,7 +399,7 @@ mod tests {
         assert_eq!(
             get_address("0xc3d688B66703497DAA19211EEdff47f25384cdc3"),
             Ok(H160::from([
-                0xc3, 0xd6, 0x88, 0xB6, 0x67, 0x03, 0x49, 0x7D, 0xAA, 0x19, 0x21, 0x1E, 0xEd, 0xff,
+                0xc3, 0xd6, 0x88, 0xB6, 0x67, 0x03, 0x49, 0x7D, 0xAA, 0x19, 0x21, 0x1E, 0xED, 0xff,
                 0x47, 0xf2, 0x53, 0x84, 0xcd, 0xc3
             ]))
         );
@@ -188,33 +407,134 @@ mod tests {
 
     #[test]
     fn get_all_sources_empty() {
-        assert_eq!(get_all_sources(&vec![]), Ok(vec![block_source()]));
+        assert_eq!(get_all_sources(&[]), Ok(vec![block_source()]));
     }
 
     #[test]
     fn get_all_sources_register() {
         let r = register_query();
         assert_eq!(
-            get_all_sources(&vec![r]),
+            get_all_sources(&[r]),
             Ok(vec![block_source(), comet_source()])
         );
     }
 
     #[test]
-    fn sources_for_query_builtin_success() {
-        let q = select_query(None);
-        let all_sources = get_all_sources(&vec![register_query()]).unwrap();
+    fn get_all_sources_register_builtin_interface() {
+        let r = Query::Register(RegisterQuery {
+            source: "usdc",
+            address: "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+            builtin: Some("erc20"),
+            interface: vec!["function owner() view returns (address)"],
+        });
+        let all_sources = get_all_sources(&[r]).unwrap();
+        let usdc = SourceIndex::new(&all_sources).get("usdc").unwrap();
+        let mut names = usdc.variables();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["decimals", "name", "owner", "symbol", "totalSupply"]
+        );
+        assert_eq!(
+            find_data_source("decimals", usdc),
+            Some(DataSource::Call(
+                get_address("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap(),
+                Bytes::from([0x31, 0x3c, 0xe5, 0x67]),
+                abi::struct_def::FieldType::Elementary(abi::ParamType::Tuple(vec![
+                    abi::ParamType::Uint(8)
+                ])),
+                abi::StateMutability::View,
+            ))
+        );
+    }
+
+    #[test]
+    fn get_all_sources_parses_interfaces_once() {
+        let register = |source, address| {
+            Query::Register(RegisterQuery {
+                source,
+                address,
+                builtin: Some("ERC4626"),
+                interface: vec![],
+            })
+        };
+        let all_sources = get_all_sources(&[
+            register("a", "0x1111111111111111111111111111111111111111"),
+            register("b", "0x2222222222222222222222222222222222222222"),
+        ])
+        .unwrap();
+        let index = SourceIndex::new(&all_sources);
+        match (
+            &index.get("a").unwrap().relation,
+            &index.get("b").unwrap().relation,
+        ) {
+            (Relation::Contract(a, interface), Relation::Contract(b, other)) => {
+                assert_eq!(*a, H160::repeat_byte(0x11));
+                assert_eq!(*b, H160::repeat_byte(0x22));
+                assert!(Rc::ptr_eq(interface, other));
+            }
+            relations => panic!("Expected contracts, got {:?}", relations),
+        }
+        let asset = |name| find_data_source("asset", index.get(name).unwrap()).unwrap();
+        match (asset("a"), asset("b")) {
+            (DataSource::Call(a, calldata, ..), DataSource::Call(b, other, ..)) => {
+                assert_eq!(a, H160::repeat_byte(0x11));
+                assert_eq!(b, H160::repeat_byte(0x22));
+                assert_eq!(calldata, other);
+            }
+            calls => panic!("Expected calls, got {:?}", calls),
+        }
+    }
+
+    #[test]
+    fn interfaces_parse_and_intern_once() {
+        let mut interfaces = Interfaces::default();
+        let erc20 = ["function decimals() view returns (uint8)"];
+        let first = interfaces.parse("a", &erc20).unwrap();
+        assert!(Rc::ptr_eq(&first, &interfaces.parse("b", &erc20).unwrap()));
+        let other = interfaces
+            .parse("c", &["function name() view returns (string)"])
+            .unwrap();
+        assert!(!Rc::ptr_eq(&first, &other));
+        assert_eq!(interfaces.parsed.values().map(Vec::len).sum::<usize>(), 2);
+        assert!(interfaces.parse("d", &["function"]).is_err());
+
+        let name = interfaces.intern("comet");
+        assert!(Rc::ptr_eq(&name, &interfaces.intern("comet")));
+        assert_eq!(interfaces.names.len(), 1);
+    }
+
+    #[test]
+    fn get_all_sources_register_unknown_interface() {
+        let r = Query::Register(RegisterQuery {
+            source: "usdc",
+            address: "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+            builtin: Some("ERC42"),
+            interface: vec![],
+        });
         assert_eq!(
-            sources_for_query(&q, &all_sources),
-            Ok(vec![block_source()])
+            get_all_sources(&[r]),
+            Err(String::from("Unknown interface \"ERC42\" for usdc. Builtin interfaces: ERC20,ERC721,ERC721Enumerable,ERC1155,ERC4626,AggregatorV3Interface"))
         );
     }
 
+    fn from_sources(q: &Query, all_sources: &[Source]) -> Result<Vec<Source>, String> {
+        let index = SourceIndex::new(all_sources);
+        Ok(sources_for_query(q, &index)?.iter().cloned().collect())
+    }
+
+    #[test]
+    fn sources_for_query_builtin_success() {
+        let q = select_query(None);
+        let all_sources = get_all_sources(&[register_query()]).unwrap();
+        assert_eq!(from_sources(&q, &all_sources), Ok(vec![block_source()]));
+    }
+
     #[test]
     fn sources_for_query_registered_success() {
         let q = select_query(Some("comet"));
         assert_eq!(
-            sources_for_query(&q, &vec![comet_source()]),
+            from_sources(&q, &[comet_source()]),
             Ok(vec![comet_source()])
         );
     }
@@ -222,50 +542,49 @@ mod tests {
     #[test]
     fn sources_for_query_missing() {
         let q = select_query(Some("time"));
-        let all_sources = get_all_sources(&vec![register_query()]).unwrap();
+        let all_sources = get_all_sources(&[register_query()]).unwrap();
         assert_eq!(
-            sources_for_query(&q, &all_sources),
-            Err(format!(
+            from_sources(&q, &all_sources),
+            Err(String::from(
                 "No such relation \"time\" referenced in FROM clause"
             ))
         );
     }
 
     #[test]
-    fn find_source_success() {
-        let q = select_query(None);
-        let all_sources = get_all_sources(&vec![register_query()]).unwrap();
-        let sources = sources_for_query(&q, &all_sources).unwrap();
-        let source = find_source("block", &sources);
-        assert_eq!(source, Some(&block_source()));
+    fn source_index_get() {
+        let all_sources = get_all_sources(&[register_query()]).unwrap();
+        let index = SourceIndex::new(&all_sources);
+        assert_eq!(index.get("block"), Some(&block_source()));
+        assert_eq!(index.get("comet"), Some(&comet_source()));
+        assert_eq!(index.get("time"), None);
     }
 
     #[test]
-    fn find_source_missing() {
-        let q = select_query(None);
-        let all_sources = get_all_sources(&vec![register_query()]).unwrap();
-        let sources = sources_for_query(&q, &all_sources).unwrap();
-        let source = find_source("time", &sources);
-        assert_eq!(source, None);
+    fn source_index_keeps_first() {
+        let first = comet_source();
+        let second = Source {
+            relation: Relation::Block,
+            ..comet_source()
+        };
+        let mut index = SourceIndex::default();
+        index.push(&first);
+        index.push(&second);
+        assert_eq!(index.get("comet"), Some(&first));
+        assert_eq!(index.iter().count(), 2);
     }
 
     #[test]
     fn find_data_source_success() {
-        let q = select_query(None);
-        let all_sources = get_all_sources(&vec![register_query()]).unwrap();
-        let sources = sources_for_query(&q, &all_sources).unwrap();
-        let source = find_source("block", &sources).unwrap();
-        let data_source = find_data_source("number", source);
-        assert_eq!(data_source, Some(&DataSource::BlockNumber));
+        let source = block_source();
+        let data_source = find_data_source("number", &source);
+        assert_eq!(data_source, Some(DataSource::BlockNumber));
     }
 
     #[test]
     fn find_data_source_failure() {
-        let q = select_query(None);
-        let all_sources = get_all_sources(&vec![register_query()]).unwrap();
-        let sources = sources_for_query(&q, &all_sources).unwrap();
-        let source = find_source("block", &sources).unwrap();
-        let data_source = find_data_source("age", source);
+        let source = block_source();
+        let data_source = find_data_source("age", &source);
         assert_eq!(data_source, None);
     }
 }
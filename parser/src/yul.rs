@@ -1,124 +1,860 @@
-use ethers::utils::hex::ToHex;
-use crate::resolve::Resolution;
-use crate::source::DataSource;
-use ethers::utils::hex;
-
-const PREFIX: &str = r###"
-object "Query" {
-    code {
-        // Store the creator in slot zero.
-        sstore(0, caller())
-
-        // Deploy the contract
-        datacopy(0, dataoffset("runtime"), datasize("runtime"))
-        return(0, datasize("runtime"))
-    }
-    object "runtime" {
-        code {
-            // Dispatcher
-            switch selector()
-            case 0x2c46b205 /* "query()" */ {
-                "###;
-
-const SUFFIX: &str = r###"
-            }
-            default {
-                revert(0, 0)
-            }
+use crate::abi::{default_token, encode_value, head_size, min_encoded_size};
+use crate::ir::{Call, Expr, Failure, Plan, Value, ValueId};
+use crate::source::Account;
+use crate::yul_ast::{
+    assign, call, exec, hex, let_, num, var, Block, Case, Expression, Ident, Literal, Names,
+    Object, Statement,
+};
+use crate::yul_parse::parse_yul;
+use ethers::abi::{ParamType, Token};
+use ethers::types::{Address, U256};
+use std::collections::HashMap;
+use std::sync::OnceLock;
 
-            /* ---------- calldata encoding functions ---------- */
-            function returnUint(v) {
-                mstore(0, v)
-                return(0, 0x20)
-            }
-            function returnTrue() {
-                returnUint(1)
-            }
+/// The selector of `query()`, which runs the query.
+pub const QUERY_SELECTOR: u32 = 0x2c46b205;
 
-            /* ---------- calldata decoding functions ----------- */
-            function selector() -> s {
-                s := div(calldataload(0), 0x100000000000000000000000000000000000000000000000000000000)
-            }
+/// The name of the object holding the deployed query's code.
+pub const RUNTIME_OBJECT: &str = "runtime";
+
+const DEPLOY: &str = r###"
+// Store the creator in slot zero.
+sstore(0, caller())
+
+// Deploy the contract
+datacopy(0, dataoffset("runtime"), datasize("runtime"))
+return(0, datasize("runtime"))
+"###;
+
+const HELPERS: &str = r###"
+/* ---------- calldata encoding functions ---------- */
+function returnUint(v) {
+    mstore(0, v)
+    return(0, 0x20)
+}
+function returnTrue() {
+    returnUint(1)
+}
+
+/* ---------- call functions ---------- */
+// Copies `len` bytes of calldata from `src` in the code to `ptr`, then calls `addr`
+// with `staticcall` and reports whether it succeeded and returned at least `minSize`
+// bytes, leaving its returndata in place for the caller to copy. Running out of
+// `gasLimit` is just another failure.
+function tryCall(gasLimit, addr, ptr, src, len, minSize) -> ok {
+    datacopy(ptr, src, len)
+    ok := staticcall(gasLimit, addr, ptr, len, 0, 0)
+    if lt(returndatasize(), minSize) {
+        ok := 0
+    }
+}
+
+// As `tryCall`, but with a regular `call`, which may modify state. Only used for
+// non-view functions under `SET ALLOW_MUTATIONS`.
+function tryMutatingCall(gasLimit, addr, ptr, src, len, minSize) -> ok {
+    datacopy(ptr, src, len)
+    ok := call(gasLimit, addr, 0, ptr, len, 0, 0)
+    if lt(returndatasize(), minSize) {
+        ok := 0
+    }
+}
+
+// Reverts with `CallFailed(uint256 column, bytes data)`, wrapping the failed
+// call's revert data with the index of the column that selected it.
+function revertCallFailed(column) {
+    let size := returndatasize()
+    mstore(0, shl(224, 0x5c0dee5d))
+    mstore(0x04, column)
+    mstore(0x24, 0x40)
+    mstore(0x44, size)
+    mstore(add(0x64, and(size, not(31))), 0)
+    returndatacopy(0x64, 0, size)
+    revert(0, add(0x64, and(add(size, 31), not(31))))
+}
+
+// Caps a call's gas at what's left of the query's `SET GAS_BUDGET`.
+function withinBudget(budget, gasLimit) -> g {
+    g := gasLimit
+    if gt(g, budget) {
+        g := budget
+    }
+}
+
+// Deducts the gas spent since `start` from the budget, stopping at zero.
+function spend(budget, start) -> remaining {
+    let spent := sub(start, gas())
+    if lt(spent, budget) {
+        remaining := sub(budget, spent)
+    }
+}
+
+/* ---------- memory functions ---------- */
+// Copies `len` bytes, rounded up to whole words, from `src` to `dst`.
+function copyMemory(dst, src, len) {
+    for { let i := 0 } lt(i, len) { i := add(i, 0x20) } {
+        mstore(add(dst, i), mload(add(src, i)))
+    }
+}
+
+// A shared call's cache entry packs the size of its returndata above the
+// location of its first copy, and is zero until the call has succeeded.
+function cachedData(entry) -> ptr {
+    ptr := and(entry, sub(shl(128, 1), 1))
+}
+function cachedSize(entry) -> size {
+    size := shr(128, entry)
+}
+
+/* ---------- returndata decoding functions ---------- */
+// Calls `addr` with `len` bytes of calldata from `src` in the code, and writes the
+// result as a `string` (length, then padded contents) at `dst`, accepting either
+// `string` or legacy `bytes32` returndata. A revert or any other returndata shape
+// decodes as the empty string.
+function safeString(gasLimit, addr, src, len, dst) -> next {
+    datacopy(dst, src, len)
+    let ok := staticcall(gasLimit, addr, dst, len, 0, 0)
+    let size := returndatasize()
+    mstore(dst, 0)
+    next := add(dst, 0x20)
+    if iszero(ok) {
+        leave
+    }
+    if eq(size, 0x20) {
+        returndatacopy(0, 0, 0x20)
+        let word := mload(0)
+        let n := 0
+        for { } byte(n, word) { n := add(n, 1) } { }
+        if n {
+            mstore(dst, n)
+            mstore(next, and(word, not(shr(mul(8, n), not(0)))))
+            next := add(next, 0x20)
         }
+        leave
+    }
+    if lt(size, 0x40) {
+        leave
+    }
+    returndatacopy(0, 0, 0x20)
+    let offset := mload(0)
+    if gt(offset, sub(size, 0x20)) {
+        leave
+    }
+    returndatacopy(0, offset, 0x20)
+    let strLen := mload(0)
+    if gt(strLen, sub(size, add(offset, 0x20))) {
+        leave
     }
-}"###;
+    mstore(add(next, and(strLen, not(31))), 0)
+    returndatacopy(next, add(offset, 0x20), strLen)
+    mstore(dst, strLen)
+    next := add(next, and(add(strLen, 31), not(31)))
+}
+
+/* ---------- calldata decoding functions ----------- */
+function selector() -> s {
+    s := div(calldataload(0), 0x100000000000000000000000000000000000000000000000000000000)
+}
+"###;
 
 fn pad_zeroes(arr: &[u8]) -> [u8; 32] {
     let mut b = [0; 32];
-    b[..arr.len()].copy_from_slice(&arr);
+    b[..arr.len()].copy_from_slice(arr);
     b
 }
 
-fn copy_bytes(tokens: &mut Vec<String>, bytes: Vec<u8>, store_len: bool) -> (usize, usize) {
-    let bytes_len = bytes.len();
-    if store_len {
-        tokens.push(format!("mstore(free, {})", bytes_len));
-        tokens.push(format!("free := add(free, 0x20)"));
+fn address(addr: &Address) -> Expression {
+    hex(U256::from_big_endian(addr.as_bytes()))
+}
+
+// An account's address: a literal, or a parameter's word of calldata with the high bits
+// cleared.
+fn account(account: &Account) -> Expression {
+    match account {
+        Account::Address(addr) => address(addr),
+        Account::Param(i) => param(*i),
     }
-    let mut chunks = 0;
+}
+
+fn param(index: usize) -> Expression {
+    call(
+        "and",
+        vec![
+            call("calldataload", vec![hex(4 + 32 * index)]),
+            call("sub", vec![call("shl", vec![num(160), num(1)]), num(1)]),
+        ],
+    )
+}
+
+fn offset(ptr: &Expression, offset: usize) -> Expression {
+    match offset {
+        0 => ptr.clone(),
+        _ => call("add", vec![ptr.clone(), hex(offset)]),
+    }
+}
+
+/// Constants a query copies into memory, such as calldata and strings, which are kept in
+/// the `data` of its object rather than pushed a word at a time. Each is stored once, however
+/// many times it's used.
+#[derive(Debug, Default)]
+pub struct Data {
+    items: Vec<(String, Vec<u8>)>,
+    names: HashMap<Vec<u8>, usize>,
+}
+
+impl Data {
+    // The offset in the code of the data item holding `bytes`.
+    fn offset(&mut self, bytes: &[u8]) -> Expression {
+        let items = &mut self.items;
+        let index = *self.names.entry(bytes.to_vec()).or_insert_with(|| {
+            items.push((format!("data_{}", items.len()), bytes.to_vec()));
+            items.len() - 1
+        });
+        let name = self.items[index].0.as_bytes().to_vec();
+        call(
+            "dataoffset",
+            vec![Expression::Literal(Literal::String(name))],
+        )
+    }
+
+    /// The data items, by name, in the order they were first used.
+    pub fn into_items(self) -> Vec<(String, Vec<u8>)> {
+        self.items
+    }
+}
+
+// Writes `bytes`, which are whole words, to memory at `dst`. Words with few significant
+// bytes, such as lengths and small numbers, are pushed and stored, which takes less code
+// than keeping them in the data. Runs of other words, such as the contents of strings, are
+// copied from the data.
+fn store_bytes(tokens: &mut Vec<Statement>, data: &mut Data, dst: &Expression, bytes: &[u8]) {
+    let mut copy = |tokens: &mut Vec<Statement>, start: usize, end: usize| {
+        tokens.push(exec(
+            "datacopy",
+            vec![
+                offset(dst, start),
+                data.offset(&bytes[start..end]),
+                num(end - start),
+            ],
+        ));
+    };
+    let mut run = None;
     for (index, chunk) in (0..).zip(bytes.chunks(32)) {
-        tokens.push(format!(
-            "mstore(add(free,{}),0x{})",
-            index * 32,
-            hex::encode(pad_zeroes(chunk))
+        let word = U256::from_big_endian(&pad_zeroes(chunk));
+        if word.bits() > 192 {
+            run.get_or_insert(index * 32);
+            continue;
+        }
+        if let Some(start) = run.take() {
+            copy(tokens, start, index * 32);
+        }
+        tokens.push(exec("mstore", vec![offset(dst, index * 32), hex(word)]));
+    }
+    if let Some(start) = run {
+        copy(tokens, start, bytes.len());
+    }
+}
+
+// The query function's local variables, named so they can't clash with the helpers, and the
+// data it copies from.
+struct Locals<'a> {
+    res: Ident,
+    free: Ident,
+    ok: Ident,
+    budget: Ident,
+    start: Ident,
+    // Where the result starts in memory.
+    base: usize,
+    // Where the cache entries of calls used by more than one column are kept, by value.
+    slots: Vec<Option<usize>>,
+    // Shared calls which have been made.
+    made: Vec<ValueId>,
+    data: &'a mut Data,
+}
+
+impl<'a> Locals<'a> {
+    fn new(plan: &Plan, names: &mut Names, data: &'a mut Data) -> Locals<'a> {
+        let res = names.fresh("res");
+        let free = names.fresh("free");
+        let ok = names.fresh("ok");
+        let budget = names.fresh("budget");
+        let start = names.fresh("start");
+        let mut next = 0x80;
+        let slots = plan
+            .uses()
+            .into_iter()
+            .map(|uses| {
+                if uses > 1 {
+                    next += 32;
+                    Some(next - 32)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        Locals {
+            res,
+            free,
+            ok,
+            budget,
+            start,
+            base: result_offset(plan),
+            slots,
+            made: vec![],
+            data,
+        }
+    }
+
+    fn res(&self) -> Expression {
+        var(&self.res)
+    }
+
+    fn free(&self) -> Expression {
+        var(&self.free)
+    }
+
+    fn ok(&self) -> Expression {
+        var(&self.ok)
+    }
+
+    // The head offset of data appended at `free`.
+    fn tail_offset(&self) -> Expression {
+        call("sub", vec![self.free(), hex(self.base)])
+    }
+
+    fn advance_res(&self, tokens: &mut Vec<Statement>, size: usize) {
+        tokens.push(assign(&self.res, call("add", vec![self.res(), hex(size)])));
+    }
+
+    fn advance_free(&self, tokens: &mut Vec<Statement>, size: Expression) {
+        tokens.push(assign(&self.free, call("add", vec![self.free(), size])));
+    }
+}
+
+// Rounds a length up to whole words.
+fn padded(len: Expression) -> Expression {
+    call(
+        "and",
+        vec![call("add", vec![len, num(31)]), call("not", vec![num(31)])],
+    )
+}
+
+// Gas forwarded to a call: its `GAS` limit or everything left, capped by any budget.
+fn derive_gas(contract_call: &Call, plan: &Plan, locals: &Locals) -> Expression {
+    let limit = match contract_call.gas {
+        Some(gas) => num(gas),
+        None => call("gas", vec![]),
+    };
+    match plan.gas_budget {
+        Some(_) => call("withinBudget", vec![var(&locals.budget), limit]),
+        None => limit,
+    }
+}
+
+// Pushes a statement making a call, charging the gas it spends to the budget if there is one.
+fn push_metered(tokens: &mut Vec<Statement>, plan: &Plan, locals: &Locals, statement: Statement) {
+    if plan.gas_budget.is_some() {
+        tokens.push(assign(&locals.start, call("gas", vec![])));
+        tokens.push(statement);
+        tokens.push(assign(
+            &locals.budget,
+            call("spend", vec![var(&locals.budget), var(&locals.start)]),
         ));
-        chunks += 1;
+    } else {
+        tokens.push(statement);
     }
-    (bytes_len, chunks)
 }
 
-pub fn derive_yul_function(resolutions: Vec<Resolution>) -> Result<Vec<String>, String> {
-    let mut tokens: Vec<String> = vec![
-        String::from("let res := 0x80"),
-        format!("let free := add(0x80,mul({},0x20))", resolutions.len()),
-    ];
-    for resolution in resolutions {
-        match resolution.data_source {
-            DataSource::BlockNumber => {
-                tokens.push(String::from("mstore(res, number())"));
-                tokens.push(String::from("res := add(res, 0x20)"));
-            }
-            DataSource::Number(n) => {
-                tokens.push(format!("mstore(res, {})", n));
-                tokens.push(String::from("res := add(res, 0x20)"));
+fn derive_call(
+    tokens: &mut Vec<Statement>,
+    contract_call: &Call,
+    plan: &Plan,
+    locals: &mut Locals,
+) {
+    let calldata = locals.data.offset(&contract_call.calldata);
+    let helper = if contract_call.is_read_only() {
+        "tryCall"
+    } else {
+        "tryMutatingCall"
+    };
+    let statement = assign(
+        &locals.ok,
+        call(
+            helper,
+            vec![
+                derive_gas(contract_call, plan, locals),
+                address(&contract_call.address),
+                locals.free(),
+                calldata,
+                num(contract_call.calldata.len()),
+                num(min_encoded_size(&contract_call.returns)),
+            ],
+        ),
+    );
+    push_metered(tokens, plan, locals, statement);
+}
+
+/// Where a plan's result starts in memory: after the cache entries of its shared calls, one
+/// word each from 0x80, which are kept in memory so that any number of calls can be shared.
+pub fn result_offset(plan: &Plan) -> usize {
+    0x80 + 32 * plan.uses().into_iter().filter(|uses| *uses > 1).count()
+}
+
+// A shared call's cache entry, at `slot` in memory.
+fn cache_entry(slot: usize) -> Expression {
+    call("mload", vec![hex(slot)])
+}
+
+// Where a successful call's result is copied from.
+enum Returned {
+    // The returndata of the call just made, which is cached in the entry at the given slot
+    // if the call is shared.
+    Returndata(Option<usize>),
+    // The copy of a shared call's returndata made by its first use. On failure, the call is
+    // repeated with the given statements so its revert data can be reported.
+    Cached(usize, Vec<Statement>),
+}
+
+impl Returned {
+    fn copy(&self, dst: Expression, len: Expression) -> Statement {
+        match self {
+            Returned::Returndata(_) => exec("returndatacopy", vec![dst, num(0), len]),
+            Returned::Cached(slot, _) => exec(
+                "copyMemory",
+                vec![dst, call("cachedData", vec![cache_entry(*slot)]), len],
+            ),
+        }
+    }
+
+    fn size(&self) -> Expression {
+        match self {
+            Returned::Returndata(_) => call("returndatasize", vec![]),
+            Returned::Cached(slot, _) => call("cachedSize", vec![cache_entry(*slot)]),
+        }
+    }
+}
+
+// Writes the result of the preceding call, handling failure as requested. Static
+// values are written in place at `dst`, dynamic ones are appended at `free`.
+fn derive_call_value(
+    tokens: &mut Vec<Statement>,
+    locals: &mut Locals,
+    param: &ParamType,
+    failure: &Failure,
+    column: usize,
+    dst: Expression,
+    returned: &Returned,
+) {
+    let (ptr, len) = if param.is_dynamic() {
+        (locals.free(), returned.size())
+    } else {
+        (dst.clone(), num(head_size(param)))
+    };
+    if let Returned::Returndata(Some(slot)) = returned {
+        let packed = call(
+            "or",
+            vec![
+                call("shl", vec![num(128), call("returndatasize", vec![])]),
+                ptr.clone(),
+            ],
+        );
+        tokens.push(Statement::If(
+            locals.ok(),
+            Block(vec![exec("mstore", vec![hex(*slot), packed])]),
+        ));
+    }
+    let mut on_success = vec![returned.copy(ptr, len.clone())];
+    if param.is_dynamic() {
+        locals.advance_free(&mut on_success, padded(len));
+    }
+    let fallback = match failure {
+        Failure::Default(token) => encode_value(token),
+        Failure::Zero => encode_value(&default_token(param)),
+        Failure::Revert => {
+            let mut on_failure = match returned {
+                Returned::Cached(_, repeat) => repeat.clone(),
+                Returned::Returndata(_) => vec![],
+            };
+            on_failure.push(exec("revertCallFailed", vec![num(column)]));
+            tokens.push(Statement::If(
+                call("iszero", vec![locals.ok()]),
+                Block(on_failure),
+            ));
+            tokens.extend(on_success);
+            return;
+        }
+    };
+    let mut on_failure = vec![];
+    if param.is_dynamic() {
+        store_bytes(&mut on_failure, locals.data, &locals.free(), &fallback);
+        locals.advance_free(&mut on_failure, num(fallback.len()));
+    } else if fallback.iter().all(|b| *b == 0) {
+        // The head is untouched until now, so it's already zeroed on failure.
+        tokens.push(Statement::If(locals.ok(), Block(on_success)));
+        return;
+    } else {
+        store_bytes(&mut on_failure, locals.data, &dst, &fallback);
+    }
+    tokens.push(Statement::Switch(
+        locals.ok(),
+        vec![Case {
+            value: Literal::Decimal(0.into()),
+            body: Block(on_failure),
+        }],
+        Some(Block(on_success)),
+    ));
+}
+
+fn derive_call_column(
+    tokens: &mut Vec<Statement>,
+    plan: &Plan,
+    locals: &mut Locals,
+    column: usize,
+    value: ValueId,
+    failure: &Failure,
+    with_status: bool,
+) -> Result<(), String> {
+    let contract_call = match &plan.values[value] {
+        Value::Call(contract_call) => contract_call,
+        v => Err(format!("Expected a contract call, got {:?}", v))?,
+    };
+    let param = &contract_call.returns;
+    let returned = match locals.slots[value] {
+        Some(slot) if locals.made.contains(&value) => {
+            let mut repeat = vec![];
+            derive_call(&mut repeat, contract_call, plan, locals);
+            tokens.push(assign(
+                &locals.ok,
+                call("iszero", vec![call("iszero", vec![cache_entry(slot)])]),
+            ));
+            Returned::Cached(slot, repeat)
+        }
+        slot => {
+            derive_call(tokens, contract_call, plan, locals);
+            if slot.is_some() {
+                locals.made.push(value);
             }
-            DataSource::String(s) => {
-                let (_bytes_len, chunks) = copy_bytes(&mut tokens, s.into_bytes(), true);
-                tokens.push(String::from("mstore(res, sub(free,add(0x80,0x20)))"));
-                tokens.push(format!("free := add(free, {})", chunks * 32));
-                tokens.push(String::from("res := add(res, 0x20)"));
+            Returned::Returndata(slot)
+        }
+    };
+    match (with_status, param.is_dynamic()) {
+        (false, true) => {
+            tokens.push(exec("mstore", vec![locals.res(), locals.tail_offset()]));
+            let dst = locals.res();
+            derive_call_value(tokens, locals, param, failure, column, dst, &returned);
+            locals.advance_res(tokens, 32);
+        }
+        (false, false) => {
+            let dst = locals.res();
+            derive_call_value(tokens, locals, param, failure, column, dst, &returned);
+            locals.advance_res(tokens, head_size(param));
+        }
+        (true, true) => {
+            // (bool ok, T value) with a dynamic T: the tuple lives in the tail, with
+            // the value's offset relative to the start of the tuple.
+            tokens.push(exec("mstore", vec![locals.res(), locals.tail_offset()]));
+            tokens.push(exec("mstore", vec![locals.free(), locals.ok()]));
+            tokens.push(exec(
+                "mstore",
+                vec![offset(&locals.free(), 0x20), hex(0x40)],
+            ));
+            locals.advance_free(tokens, hex(0x40));
+            let dst = locals.free();
+            derive_call_value(tokens, locals, param, failure, column, dst, &returned);
+            locals.advance_res(tokens, 32);
+        }
+        (true, false) => {
+            tokens.push(exec("mstore", vec![locals.res(), locals.ok()]));
+            let dst = offset(&locals.res(), 0x20);
+            derive_call_value(tokens, locals, param, failure, column, dst, &returned);
+            locals.advance_res(tokens, 32 + head_size(param));
+        }
+    }
+    Ok(())
+}
+
+fn derive_const(tokens: &mut Vec<Statement>, token: &Token, locals: &mut Locals) {
+    if let Token::Uint(n) = token {
+        tokens.push(exec("mstore", vec![locals.res(), num(*n)]));
+        locals.advance_res(tokens, 32);
+        return;
+    }
+    let encoded = encode_value(token);
+    if token.is_dynamic() {
+        tokens.push(exec("mstore", vec![locals.res(), locals.tail_offset()]));
+        store_bytes(tokens, locals.data, &locals.free(), &encoded);
+        locals.advance_free(tokens, num(encoded.len()));
+        locals.advance_res(tokens, 32);
+    } else {
+        store_bytes(tokens, locals.data, &locals.res(), &encoded);
+        locals.advance_res(tokens, encoded.len());
+    }
+}
+
+fn derive_value(
+    tokens: &mut Vec<Statement>,
+    value: &Value,
+    plan: &Plan,
+    locals: &mut Locals,
+) -> Result<(), String> {
+    let word = match value {
+        Value::BlockNumber => call("number", vec![]),
+        Value::Balance(addr) => call("balance", vec![account(addr)]),
+        Value::CodeSize(addr) => call("extcodesize", vec![account(addr)]),
+        Value::CodeHash(addr) => call("extcodehash", vec![account(addr)]),
+        Value::IsContract(addr) => call(
+            "iszero",
+            vec![call(
+                "iszero",
+                vec![call("extcodesize", vec![account(addr)])],
+            )],
+        ),
+        Value::Param(i) => param(*i),
+        Value::SafeString(contract_call) => {
+            tokens.push(exec("mstore", vec![locals.res(), locals.tail_offset()]));
+            let statement = assign(
+                &locals.free,
+                call(
+                    "safeString",
+                    vec![
+                        derive_gas(contract_call, plan, locals),
+                        address(&contract_call.address),
+                        locals.data.offset(&contract_call.calldata),
+                        num(contract_call.calldata.len()),
+                        locals.free(),
+                    ],
+                ),
+            );
+            push_metered(tokens, plan, locals, statement);
+            locals.advance_res(tokens, 32);
+            return Ok(());
+        }
+        Value::Call(_) => Err(String::from(
+            "A contract call must be read through its result",
+        ))?,
+    };
+    tokens.push(exec("mstore", vec![locals.res(), word]));
+    locals.advance_res(tokens, 32);
+    Ok(())
+}
+
+// The helper functions every query's code ends with, and their names, which generated
+// variables must avoid.
+struct Helpers {
+    statements: Vec<Statement>,
+    names: Vec<Ident>,
+}
+
+// Parses the helpers the first time they're needed, since they're the same for every query.
+fn helpers() -> Result<&'static Helpers, String> {
+    static PARSED: OnceLock<Result<Helpers, String>> = OnceLock::new();
+    PARSED
+        .get_or_init(|| {
+            let statements =
+                parse_yul(HELPERS).map_err(|e| format!("Invalid Yul helpers: {}", e))?;
+            let names = statements
+                .iter()
+                .filter_map(|statement| match statement {
+                    Statement::Function(function) => Some(function.name.clone()),
+                    _ => None,
+                })
+                .collect();
+            Ok(Helpers { statements, names })
+        })
+        .as_ref()
+        .map_err(String::clone)
+}
+
+/// Lowers a query plan to the body of the `query()` function, which calls `helpers`, adding
+/// the constants it copies to `data`.
+fn derive_yul_plan(
+    plan: &Plan,
+    data: &mut Data,
+    helpers: &Helpers,
+) -> Result<Vec<Statement>, String> {
+    let mut names = Names::new(&helpers.names);
+    let mut locals = Locals::new(plan, &mut names, data);
+    let head_words: usize = plan.columns.iter().map(|c| head_size(&c.ty) / 32).sum();
+    let mut tokens = vec![
+        let_(&locals.res, hex(locals.base)),
+        let_(&locals.free, hex(locals.base + head_words * 32)),
+    ];
+    let has_calls = plan.values.iter().any(|v| matches!(v, Value::Call(_)));
+    if has_calls {
+        tokens.push(let_(&locals.ok, num(0)));
+    }
+    if let Some(budget) = plan.gas_budget {
+        let metered = plan
+            .values
+            .iter()
+            .any(|v| matches!(v, Value::Call(_) | Value::SafeString(_)));
+        if metered {
+            tokens.push(let_(&locals.budget, num(budget)));
+            tokens.push(let_(&locals.start, num(0)));
+        }
+    }
+    let mut span = None;
+    for (index, column) in plan.columns.iter().enumerate() {
+        if column.span != span {
+            span = column.span;
+            tokens.push(Statement::Source(span));
+        }
+        match &column.expr {
+            Expr::Const(token) => derive_const(&mut tokens, token, &mut locals),
+            Expr::Value(value) => {
+                derive_value(&mut tokens, &plan.values[*value], plan, &mut locals)?
             }
-            DataSource::Call(addr, bytes, _abi) => {
-                let (bytes_len, _chunks) = copy_bytes(&mut tokens, bytes.to_vec(), false);
-                tokens.push(format!(
-                    "pop(call(gas(), 0x{}, 0, free, {}, free, 0))",
-                    addr.encode_hex::<String>(), bytes_len
-                ));
-                tokens.push(format!("returndatacopy(free, 0, returndatasize())"));
-                tokens.push(String::from("mstore(res, free)"));
-                tokens.push(format!("free := add(free, returndatasize())"));
-                tokens.push(String::from("res := add(res, 0x20)"));
+            Expr::Try(value) => derive_call_column(
+                &mut tokens,
+                plan,
+                &mut locals,
+                index,
+                *value,
+                &Failure::Zero,
+                true,
+            )?,
+            expr => {
+                let (value, failure) = expr
+                    .call_result()
+                    .ok_or_else(|| format!("Cannot compile column expression {:?}", expr))?;
+                derive_call_column(
+                    &mut tokens,
+                    plan,
+                    &mut locals,
+                    index,
+                    value,
+                    &failure,
+                    false,
+                )?
             }
         }
     }
-    tokens.push(String::from("return(0x80,sub(free,0x80))"));
+    if span.is_some() {
+        tokens.push(Statement::Source(None));
+    }
+    tokens.push(exec("return", vec![hex(locals.base), locals.tail_offset()]));
     Ok(tokens)
 }
 
-pub fn derive_yul(resolutions: Vec<Resolution>) -> Result<String, String> {
-    let tokens = derive_yul_function(resolutions)?;
-    let inner = tokens.join("\n                ");
-    Ok(format!("{}{}{}", PREFIX, inner, SUFFIX))
+/// Builds the Yul object which deploys a contract whose `query()` runs the plan.
+pub fn derive_object(plan: &Plan) -> Result<Object, String> {
+    derive_dispatcher_object(&[(QUERY_SELECTOR, plan)])
+}
+
+/// Builds the Yul object which deploys a contract running each plan when called with its
+/// selector.
+pub fn derive_dispatcher_object(entries: &[(u32, &Plan)]) -> Result<Object, String> {
+    let helpers = helpers()?;
+    let mut data = Data::default();
+    let mut cases = vec![];
+    for (selector, plan) in entries {
+        cases.push(Case {
+            value: Literal::Hex((*selector).into()),
+            body: Block(derive_yul_plan(plan, &mut data, helpers)?),
+        });
+    }
+    let mut runtime = vec![Statement::Switch(
+        call("selector", vec![]),
+        cases,
+        Some(Block(vec![exec("revert", vec![num(0), num(0)])])),
+    )];
+    runtime.extend(helpers.statements.iter().cloned());
+    Ok(Object {
+        name: String::from("Query"),
+        code: Block(parse_yul(DEPLOY)?),
+        objects: vec![Object {
+            name: String::from(RUNTIME_OBJECT),
+            code: Block(runtime),
+            objects: vec![],
+            data: data.into_items(),
+        }],
+        data: vec![],
+    })
+}
+
+/// Builds a Yul object whose init code runs the plan and returns its result, so that a
+/// contract creation, such as an `eth_call` with no `to`, answers the query without anything
+/// being deployed first. The result is returned as the created contract's code, so it's
+/// subject to the same limits: it must be at most `MAX_CODE_SIZE` bytes and can't start with
+/// `0xef`.
+pub fn derive_initcode_object(plan: &Plan) -> Result<Object, String> {
+    if plan.values.iter().any(|v| matches!(v, Value::Param(_))) {
+        return Err(String::from("Init code can't read a named query's parameters"));
+    }
+    let helpers = helpers()?;
+    let mut data = Data::default();
+    let mut code = derive_yul_plan(plan, &mut data, helpers)?;
+    code.extend(helpers.statements.iter().cloned());
+    Ok(Object {
+        name: String::from("Query"),
+        code: Block(code),
+        objects: vec![],
+        data: data.into_items(),
+    })
+}
+
+pub fn derive_yul(plan: &Plan) -> Result<String, String> {
+    Ok(derive_object(plan)?.to_string())
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::resolve::Resolution;
-    use crate::source::{DataSource, DataSource::Call};
-    use crate::yul;
+    use crate::evm::assemble;
+    use crate::executor::CallResult;
+    use crate::ir::{lower, Column, Expr, Plan};
+    use crate::local::LocalChain;
+    use crate::passes::PassManager;
+    use crate::resolve::{Resolution, Settings};
+    use crate::source::{Account, DataSource, DataSource::Call};
+    use crate::yul::{self, Data};
+    use crate::yul_ast::{print_statements, Statement};
     use ethers::abi;
     use ethers::types::Bytes;
 
+    fn derive_yul_function(
+        resolutions: Vec<Resolution>,
+        settings: &Settings,
+    ) -> Result<Vec<String>, String> {
+        let plan = lower(resolutions, settings)?;
+        yul::derive_yul_plan(&plan, &mut Data::default(), yul::helpers().unwrap()).map(lines)
+    }
+
+    // Runs the optimised plan's `query()` in a local EVM, decoding its result as uint256 words.
+    fn run_query(
+        chain: &LocalChain,
+        resolutions: Vec<Resolution>,
+        words: usize,
+    ) -> Result<Vec<abi::Token>, String> {
+        let plan = PassManager::default().run(lower(resolutions, &Settings::default())?)?;
+        let bytecode = assemble(&yul::derive_object(&plan)?)?.bytecode;
+        match chain.run(&bytecode, &yul::QUERY_SELECTOR.to_be_bytes())? {
+            CallResult::Success(data) => {
+                abi::decode(&vec![abi::ParamType::Uint(256); words], &data)
+                    .map_err(|e| e.to_string())
+            }
+            CallResult::Revert(data) => Err(format!("Reverted with {:?}", data)),
+        }
+    }
+
+    // A contract at 0x1111…1111 which returns 42 to any call.
+    fn answer_chain() -> LocalChain {
+        LocalChain::from_state_dump(
+            r#"{
+                "0x1111111111111111111111111111111111111111": {
+                    "balance": "1000",
+                    "code": "0x602a60005260206000f3"
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn lines(statements: Vec<Statement>) -> Vec<String> {
+        print_statements(&statements)
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
     #[test]
     fn pad_zeroes() {
         assert_eq!(
@@ -134,52 +870,651 @@ mod tests {
     fn simple_derive_yul() {
         let resolutions = vec![Resolution {
             name: Some(String::from("block")),
+            source: None,
             abi: abi::struct_def::FieldType::Elementary(abi::ParamType::Uint(256)),
             data_source: DataSource::BlockNumber,
+            gas: None,
+            span: None,
         }];
 
         assert_eq!(
-            yul::derive_yul_function(resolutions),
+            derive_yul_function(resolutions, &Settings::default()),
             Ok(vec![
                 String::from("let res := 0x80"),
-                String::from("let free := add(0x80,mul(1,0x20))"),
+                String::from("let free := 0xa0"),
                 String::from("mstore(res, number())"),
                 String::from("res := add(res, 0x20)"),
-                String::from("return(0x80,sub(free,0x80))"),
+                String::from("return(0x80, sub(free, 0x80))"),
+            ])
+        )
+    }
+
+    #[test]
+    fn derive_yul_account_builtins() {
+        let address = Account::Address(ethers::types::H160([
+            0xc3, 0xd6, 0x88, 0xB6, 0x67, 0x03, 0x49, 0x7D, 0xAA, 0x19, 0x21, 0x1E, 0xED, 0xff,
+            0x47, 0xf2, 0x53, 0x84, 0xcd, 0xc3,
+        ]));
+        let resolutions = vec![
+            DataSource::Balance(address),
+            DataSource::CodeSize(address),
+            DataSource::CodeHash(address),
+            DataSource::IsContract(address),
+        ]
+        .into_iter()
+        .map(|data_source| Resolution {
+            name: None,
+            source: None,
+            abi: data_source.abi(),
+            data_source,
+            gas: None,
+            span: None,
+        })
+        .collect();
+
+        assert_eq!(
+            derive_yul_function(resolutions, &Settings::default()),
+            Ok(vec![
+                String::from("let res := 0x80"),
+                String::from("let free := 0x100"),
+                String::from("mstore(res, balance(0xc3d688b66703497daa19211eedff47f25384cdc3))"),
+                String::from("res := add(res, 0x20)"),
+                String::from("mstore(res, extcodesize(0xc3d688b66703497daa19211eedff47f25384cdc3))"),
+                String::from("res := add(res, 0x20)"),
+                String::from("mstore(res, extcodehash(0xc3d688b66703497daa19211eedff47f25384cdc3))"),
+                String::from("res := add(res, 0x20)"),
+                String::from("mstore(res, iszero(iszero(extcodesize(0xc3d688b66703497daa19211eedff47f25384cdc3))))"),
+                String::from("res := add(res, 0x20)"),
+                String::from("return(0x80, sub(free, 0x80))"),
             ])
         )
     }
 
+    #[test]
+    fn run_account_builtins() {
+        let address = Account::Address(ethers::types::H160::repeat_byte(0x11));
+        let resolutions = vec![
+            DataSource::Balance(address),
+            DataSource::CodeSize(address),
+            DataSource::CodeHash(address),
+            DataSource::IsContract(address),
+        ]
+        .into_iter()
+        .map(resolution)
+        .collect();
+        let code_hash =
+            ethers::utils::keccak256([0x60, 0x2a, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3]);
+        assert_eq!(
+            run_query(&answer_chain(), resolutions, 4),
+            Ok(vec![
+                abi::Token::Uint(1000.into()),
+                abi::Token::Uint(10.into()),
+                abi::Token::Uint(code_hash.into()),
+                abi::Token::Uint(1.into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn derive_yul_safe_string() {
+        let call = Call(
+            ethers::types::H160([
+                0x9f, 0x8f, 0x72, 0xaa, 0x93, 0x04, 0xc8, 0xb5, 0x93, 0xd5, 0x55, 0xf1, 0x2e, 0xf6,
+                0x58, 0x9c, 0xc3, 0xa5, 0x79, 0xa2,
+            ]),
+            Bytes::from([0x95, 0xd8, 0x9b, 0x41]),
+            abi::struct_def::FieldType::Elementary(abi::ParamType::Tuple(vec![
+                abi::ParamType::String,
+            ])),
+            abi::StateMutability::View,
+        );
+        let resolutions = vec![Resolution {
+            name: Some(String::from("symbol")),
+            source: None,
+            abi: abi::struct_def::FieldType::Elementary(abi::ParamType::String),
+            data_source: DataSource::SafeString(Box::new(call)),
+            gas: None,
+            span: None,
+        }];
+
+        assert_eq!(
+            derive_yul_function(resolutions, &Settings::default()),
+            Ok(vec![
+                String::from("let res := 0x80"),
+                String::from("let free := 0xa0"),
+                String::from("mstore(res, sub(free, 0x80))"),
+                String::from("free := safeString(gas(), 0x9f8f72aa9304c8b593d555f12ef6589cc3a579a2, dataoffset(\"data_0\"), 4, free)"),
+                String::from("res := add(res, 0x20)"),
+                String::from("return(0x80, sub(free, 0x80))"),
+            ])
+        );
+    }
+
+    #[test]
+    fn derive_yul_safe_string_requires_call() {
+        let resolutions = vec![Resolution {
+            name: None,
+            source: None,
+            abi: abi::struct_def::FieldType::Elementary(abi::ParamType::String),
+            data_source: DataSource::SafeString(Box::new(DataSource::BlockNumber)),
+            gas: None,
+            span: None,
+        }];
+
+        assert_eq!(
+            derive_yul_function(resolutions, &Settings::default()),
+            Err(String::from(
+                "SAFE_STRING requires a contract call, got BlockNumber"
+            ))
+        );
+    }
+
     #[test]
     fn derive_yul_call() {
         let resolutions = vec![Resolution {
             name: Some(String::from("comet")),
+            source: None,
             abi: abi::struct_def::FieldType::Elementary(abi::ParamType::Uint(256)),
             data_source: Call(
                 ethers::types::H160([
-                    0xc3, 0xd6, 0x88, 0xB6, 0x67, 0x03, 0x49, 0x7D, 0xAA, 0x19, 0x21, 0x1E, 0xEd,
+                    0xc3, 0xd6, 0x88, 0xB6, 0x67, 0x03, 0x49, 0x7D, 0xAA, 0x19, 0x21, 0x1E, 0xED,
                     0xff, 0x47, 0xf2, 0x53, 0x84, 0xcd, 0xc3,
                 ]),
                 Bytes::from([0x18, 0x16, 0x0d, 0xdd]),
                 abi::struct_def::FieldType::Elementary(ethers::abi::param_type::ParamType::Tuple(
                     vec![abi::ParamType::Uint(256)],
                 )),
+                abi::StateMutability::View,
             ),
+            gas: None,
+            span: None,
         }];
 
         assert_eq!(
-            yul::derive_yul_function(resolutions),
+            derive_yul_function(resolutions, &Settings::default()),
+            Ok(vec![
+                String::from("let res := 0x80"),
+                String::from("let free := 0xa0"),
+                String::from("let ok := 0"),
+                String::from("ok := tryCall(gas(), 0xc3d688b66703497daa19211eedff47f25384cdc3, free, dataoffset(\"data_0\"), 4, 32)"),
+                String::from("if ok {"),
+                String::from("    returndatacopy(res, 0, 32)"),
+                String::from("}"),
+                String::from("res := add(res, 0x20)"),
+                String::from("return(0x80, sub(free, 0x80))")
+            ])
+        )
+    }
+
+    fn symbol_call() -> DataSource {
+        Call(
+            ethers::types::H160::repeat_byte(0x11),
+            Bytes::from([0x95, 0xd8, 0x9b, 0x41]),
+            abi::struct_def::FieldType::Elementary(abi::ParamType::Tuple(vec![
+                abi::ParamType::String,
+            ])),
+            abi::StateMutability::View,
+        )
+    }
+
+    fn total_supply_call() -> DataSource {
+        Call(
+            ethers::types::H160::repeat_byte(0x11),
+            Bytes::from([0x18, 0x16, 0x0d, 0xdd]),
+            abi::struct_def::FieldType::Elementary(abi::ParamType::Tuple(vec![
+                abi::ParamType::Uint(256),
+            ])),
+            abi::StateMutability::View,
+        )
+    }
+
+    fn resolution(data_source: DataSource) -> Resolution {
+        Resolution {
+            name: None,
+            source: None,
+            abi: data_source.abi(),
+            data_source,
+            gas: None,
+            span: None,
+        }
+    }
+
+    #[test]
+    fn derive_yul_call_dynamic() {
+        assert_eq!(
+            derive_yul_function(vec![resolution(symbol_call())], &Settings::default()),
+            Ok(vec![
+                String::from("let res := 0x80"),
+                String::from("let free := 0xa0"),
+                String::from("let ok := 0"),
+                String::from("ok := tryCall(gas(), 0x1111111111111111111111111111111111111111, free, dataoffset(\"data_0\"), 4, 32)"),
+                String::from("mstore(res, sub(free, 0x80))"),
+                String::from("switch ok"),
+                String::from("case 0 {"),
+                String::from("    mstore(free, 0x20)"),
+                String::from("    mstore(add(free, 0x20), 0x0)"),
+                String::from("    free := add(free, 64)"),
+                String::from("}"),
+                String::from("default {"),
+                String::from("    returndatacopy(free, 0, returndatasize())"),
+                String::from("    free := add(free, and(add(returndatasize(), 31), not(31)))"),
+                String::from("}"),
+                String::from("res := add(res, 0x20)"),
+                String::from("return(0x80, sub(free, 0x80))"),
+            ])
+        )
+    }
+
+    #[test]
+    fn derive_yul_try() {
+        assert_eq!(
+            derive_yul_function(
+                vec![
+                    resolution(DataSource::Try(Box::new(total_supply_call()))),
+                    resolution(DataSource::Try(Box::new(symbol_call()))),
+                ],
+                &Settings::default()
+            ),
+            Ok(vec![
+                String::from("let res := 0x80"),
+                String::from("let free := 0xe0"),
+                String::from("let ok := 0"),
+                String::from("ok := tryCall(gas(), 0x1111111111111111111111111111111111111111, free, dataoffset(\"data_0\"), 4, 32)"),
+                String::from("mstore(res, ok)"),
+                String::from("if ok {"),
+                String::from("    returndatacopy(add(res, 0x20), 0, 32)"),
+                String::from("}"),
+                String::from("res := add(res, 0x40)"),
+                String::from("ok := tryCall(gas(), 0x1111111111111111111111111111111111111111, free, dataoffset(\"data_1\"), 4, 32)"),
+                String::from("mstore(res, sub(free, 0x80))"),
+                String::from("mstore(free, ok)"),
+                String::from("mstore(add(free, 0x20), 0x40)"),
+                String::from("free := add(free, 0x40)"),
+                String::from("switch ok"),
+                String::from("case 0 {"),
+                String::from("    mstore(free, 0x20)"),
+                String::from("    mstore(add(free, 0x20), 0x0)"),
+                String::from("    free := add(free, 64)"),
+                String::from("}"),
+                String::from("default {"),
+                String::from("    returndatacopy(free, 0, returndatasize())"),
+                String::from("    free := add(free, and(add(returndatasize(), 31), not(31)))"),
+                String::from("}"),
+                String::from("res := add(res, 0x20)"),
+                String::from("return(0x80, sub(free, 0x80))"),
+            ])
+        )
+    }
+
+    #[test]
+    fn derive_yul_coalesce() {
+        let fallback = abi::Token::Tuple(vec![abi::Token::Uint(ethers::types::U256::from(7))]);
+        assert_eq!(
+            derive_yul_function(
+                vec![resolution(DataSource::Coalesce(
+                    Box::new(total_supply_call()),
+                    fallback
+                ))],
+                &Settings::default()
+            ),
             Ok(vec![
                 String::from("let res := 0x80"),
-                String::from("let free := add(0x80,mul(1,0x20))"),
-                String::from("mstore(add(free,0),0x18160ddd00000000000000000000000000000000000000000000000000000000)"),
-                String::from("pop(call(gas(), 0xc3d688b66703497daa19211eedff47f25384cdc3, 0, free, 4, free, 0))"),
+                String::from("let free := 0xa0"),
+                String::from("let ok := 0"),
+                String::from("ok := tryCall(gas(), 0x1111111111111111111111111111111111111111, free, dataoffset(\"data_0\"), 4, 32)"),
+                String::from("switch ok"),
+                String::from("case 0 {"),
+                String::from("    mstore(res, 0x7)"),
+                String::from("}"),
+                String::from("default {"),
+                String::from("    returndatacopy(res, 0, 32)"),
+                String::from("}"),
+                String::from("res := add(res, 0x20)"),
+                String::from("return(0x80, sub(free, 0x80))"),
+            ])
+        )
+    }
+
+    #[test]
+    fn run_try_and_coalesce() {
+        // Calls to an account without code succeed, but return too little to decode.
+        let missing = match total_supply_call() {
+            Call(_, data, abi, mutability) => Call(
+                ethers::types::H160::repeat_byte(0x33),
+                data,
+                abi,
+                mutability,
+            ),
+            _ => unreachable!(),
+        };
+        let fallback = abi::Token::Tuple(vec![abi::Token::Uint(ethers::types::U256::from(7))]);
+        let resolutions = vec![
+            resolution(DataSource::Try(Box::new(total_supply_call()))),
+            resolution(DataSource::Try(Box::new(missing.clone()))),
+            resolution(DataSource::Coalesce(Box::new(missing), fallback)),
+        ];
+        assert_eq!(
+            run_query(&answer_chain(), resolutions, 5),
+            Ok(vec![
+                abi::Token::Uint(1.into()),
+                abi::Token::Uint(42.into()),
+                abi::Token::Uint(0.into()),
+                abi::Token::Uint(0.into()),
+                abi::Token::Uint(7.into()),
+            ])
+        );
+    }
+
+    #[test]
+    fn derive_yul_strict() {
+        let settings = Settings {
+            strict: true,
+            ..Settings::default()
+        };
+        assert_eq!(
+            derive_yul_function(
+                vec![
+                    resolution(total_supply_call()),
+                    resolution(symbol_call()),
+                    resolution(DataSource::Try(Box::new(total_supply_call()))),
+                ],
+                &settings
+            ),
+            Ok(vec![
+                String::from("let res := 0x80"),
+                String::from("let free := 0x100"),
+                String::from("let ok := 0"),
+                String::from("ok := tryCall(gas(), 0x1111111111111111111111111111111111111111, free, dataoffset(\"data_0\"), 4, 32)"),
+                String::from("if iszero(ok) {"),
+                String::from("    revertCallFailed(0)"),
+                String::from("}"),
+                String::from("returndatacopy(res, 0, 32)"),
+                String::from("res := add(res, 0x20)"),
+                String::from("ok := tryCall(gas(), 0x1111111111111111111111111111111111111111, free, dataoffset(\"data_1\"), 4, 32)"),
+                String::from("mstore(res, sub(free, 0x80))"),
+                String::from("if iszero(ok) {"),
+                String::from("    revertCallFailed(1)"),
+                String::from("}"),
                 String::from("returndatacopy(free, 0, returndatasize())"),
-                String::from("mstore(res, free)"),
-                String::from("free := add(free, returndatasize())"),
+                String::from("free := add(free, and(add(returndatasize(), 31), not(31)))"),
+                String::from("res := add(res, 0x20)"),
+                String::from("ok := tryCall(gas(), 0x1111111111111111111111111111111111111111, free, dataoffset(\"data_0\"), 4, 32)"),
+                String::from("mstore(res, ok)"),
+                String::from("if ok {"),
+                String::from("    returndatacopy(add(res, 0x20), 0, 32)"),
+                String::from("}"),
+                String::from("res := add(res, 0x40)"),
+                String::from("return(0x80, sub(free, 0x80))"),
+            ])
+        );
+    }
+
+    #[test]
+    fn derive_yul_mutating_call() {
+        let call = match total_supply_call() {
+            Call(addr, bytes, abi, _) => Call(addr, bytes, abi, abi::StateMutability::NonPayable),
+            ds => ds,
+        };
+        let tokens = derive_yul_function(vec![resolution(call)], &Settings::default()).unwrap();
+        assert!(tokens.contains(&String::from(
+            "ok := tryMutatingCall(gas(), 0x1111111111111111111111111111111111111111, free, dataoffset(\"data_0\"), 4, 32)"
+        )));
+    }
+
+    #[test]
+    fn derive_yul_gas_budget() {
+        let settings = Settings {
+            gas_budget: Some(1000000),
+            ..Settings::default()
+        };
+        let resolutions = vec![
+            Resolution {
+                gas: Some(50000),
+                ..resolution(total_supply_call())
+            },
+            resolution(DataSource::SafeString(Box::new(symbol_call()))),
+        ];
+        assert_eq!(
+            derive_yul_function(resolutions, &settings),
+            Ok(vec![
+                String::from("let res := 0x80"),
+                String::from("let free := 0xc0"),
+                String::from("let ok := 0"),
+                String::from("let budget := 1000000"),
+                String::from("let start := 0"),
+                String::from("start := gas()"),
+                String::from("ok := tryCall(withinBudget(budget, 50000), 0x1111111111111111111111111111111111111111, free, dataoffset(\"data_0\"), 4, 32)"),
+                String::from("budget := spend(budget, start)"),
+                String::from("if ok {"),
+                String::from("    returndatacopy(res, 0, 32)"),
+                String::from("}"),
+                String::from("res := add(res, 0x20)"),
+                String::from("mstore(res, sub(free, 0x80))"),
+                String::from("start := gas()"),
+                String::from("free := safeString(withinBudget(budget, gas()), 0x1111111111111111111111111111111111111111, dataoffset(\"data_1\"), 4, free)"),
+                String::from("budget := spend(budget, start)"),
                 String::from("res := add(res, 0x20)"),
-                String::from("return(0x80,sub(free,0x80))")
+                String::from("return(0x80, sub(free, 0x80))"),
             ])
+        );
+    }
+
+    #[test]
+    fn derive_yul_shared_calls() {
+        let settings = Settings {
+            strict: true,
+            ..Settings::default()
+        };
+        let plan = lower(
+            vec![
+                resolution(DataSource::Try(Box::new(symbol_call()))),
+                resolution(symbol_call()),
+            ],
+            &settings,
         )
+        .unwrap();
+        let plan = PassManager::default().run(plan).unwrap();
+        assert_eq!(
+            yul::derive_yul_plan(&plan, &mut Data::default(), yul::helpers().unwrap()).map(lines),
+            Ok(vec![
+                String::from("let res := 0xa0"),
+                String::from("let free := 0xe0"),
+                String::from("let ok := 0"),
+                String::from("ok := tryCall(gas(), 0x1111111111111111111111111111111111111111, free, dataoffset(\"data_0\"), 4, 32)"),
+                String::from("mstore(res, sub(free, 0xa0))"),
+                String::from("mstore(free, ok)"),
+                String::from("mstore(add(free, 0x20), 0x40)"),
+                String::from("free := add(free, 0x40)"),
+                String::from("if ok {"),
+                String::from("    mstore(0x80, or(shl(128, returndatasize()), free))"),
+                String::from("}"),
+                String::from("switch ok"),
+                String::from("case 0 {"),
+                String::from("    mstore(free, 0x20)"),
+                String::from("    mstore(add(free, 0x20), 0x0)"),
+                String::from("    free := add(free, 64)"),
+                String::from("}"),
+                String::from("default {"),
+                String::from("    returndatacopy(free, 0, returndatasize())"),
+                String::from("    free := add(free, and(add(returndatasize(), 31), not(31)))"),
+                String::from("}"),
+                String::from("res := add(res, 0x20)"),
+                String::from("ok := iszero(iszero(mload(0x80)))"),
+                String::from("mstore(res, sub(free, 0xa0))"),
+                String::from("if iszero(ok) {"),
+                String::from("    ok := tryCall(gas(), 0x1111111111111111111111111111111111111111, free, dataoffset(\"data_0\"), 4, 32)"),
+                String::from("    revertCallFailed(1)"),
+                String::from("}"),
+                String::from("copyMemory(free, cachedData(mload(0x80)), cachedSize(mload(0x80)))"),
+                String::from("free := add(free, and(add(cachedSize(mload(0x80)), 31), not(31)))"),
+                String::from("res := add(res, 0x20)"),
+                String::from("return(0xa0, sub(free, 0xa0))"),
+            ])
+        );
+    }
+
+    #[test]
+    fn derive_yul_many_shared_calls() {
+        // More shared calls than the EVM can reach on the stack, which only need the usual
+        // locals since their cache entries are in memory.
+        let resolutions = (0..17u8)
+            .flat_map(|i| {
+                let call = Call(
+                    ethers::types::H160::repeat_byte(0x11),
+                    Bytes::from([0, 0, 0, i]),
+                    abi::struct_def::FieldType::Elementary(abi::ParamType::Tuple(vec![
+                        abi::ParamType::Uint(256),
+                    ])),
+                    abi::StateMutability::View,
+                );
+                vec![
+                    resolution(call.clone()),
+                    resolution(DataSource::Try(Box::new(call))),
+                ]
+            })
+            .collect();
+        let plan = lower(resolutions, &Settings::default()).unwrap();
+        let plan = PassManager::default().run(plan).unwrap();
+        let lines = yul::derive_yul_plan(&plan, &mut Data::default(), yul::helpers().unwrap())
+            .map(lines)
+            .unwrap();
+        assert_eq!(
+            lines.iter().filter(|line| line.starts_with("let ")).collect::<Vec<_>>(),
+            vec!["let res := 0x2a0", "let free := 0x900", "let ok := 0"]
+        );
+        assert!(lines.contains(&String::from(
+            "    mstore(0x280, or(shl(128, returndatasize()), res))"
+        )));
+        assert!(lines.contains(&String::from("ok := iszero(iszero(mload(0x280)))")));
+    }
+
+    #[test]
+    fn run_many_shared_calls() {
+        // More shared calls than the EVM can reach on the stack.
+        let resolutions = (0..17u8)
+            .flat_map(|i| {
+                let call = Call(
+                    ethers::types::H160::repeat_byte(0x11),
+                    Bytes::from([0, 0, 0, i]),
+                    abi::struct_def::FieldType::Elementary(abi::ParamType::Tuple(vec![
+                        abi::ParamType::Uint(256),
+                    ])),
+                    abi::StateMutability::View,
+                );
+                vec![
+                    resolution(call.clone()),
+                    resolution(DataSource::Try(Box::new(call))),
+                ]
+            })
+            .collect();
+        let answers = [42, 1, 42].map(|word| abi::Token::Uint(word.into()));
+        assert_eq!(
+            run_query(&answer_chain(), resolutions, 17 * 3),
+            Ok(answers.iter().cloned().cycle().take(17 * 3).collect())
+        );
+    }
+
+    #[test]
+    fn derive_yul_constants() {
+        let token = abi::Token::Tuple(vec![
+            abi::Token::Bool(true),
+            abi::Token::Tuple(vec![abi::Token::Uint(5.into())]),
+        ]);
+        let plan = Plan {
+            columns: vec![Column {
+                name: None,
+                source: None,
+                ty: abi::ParamType::Tuple(vec![
+                    abi::ParamType::Bool,
+                    abi::ParamType::Tuple(vec![abi::ParamType::Uint(256)]),
+                ]),
+                expr: Expr::Const(token),
+                span: None,
+            }],
+            values: vec![],
+            gas_budget: None,
+        };
+        assert_eq!(
+            yul::derive_yul_plan(&plan, &mut Data::default(), yul::helpers().unwrap()).map(lines),
+            Ok(vec![
+                String::from("let res := 0x80"),
+                String::from("let free := 0xc0"),
+                String::from("mstore(res, 0x1)"),
+                String::from("mstore(add(res, 0x20), 0x5)"),
+                String::from("res := add(res, 0x40)"),
+                String::from("return(0x80, sub(free, 0x80))"),
+            ])
+        );
+    }
+
+    #[test]
+    fn derive_yul_strings() {
+        let fox = "The quick brown fox jumps over the lazy dog";
+        let plan = lower(
+            vec![
+                resolution(DataSource::String(String::from(fox))),
+                resolution(DataSource::String(String::from(fox))),
+            ],
+            &Settings::default(),
+        )
+        .unwrap();
+        let mut data = Data::default();
+        assert_eq!(
+            yul::derive_yul_plan(&plan, &mut data, yul::helpers().unwrap()).map(lines),
+            Ok(vec![
+                String::from("let res := 0x80"),
+                String::from("let free := 0xc0"),
+                String::from("mstore(res, sub(free, 0x80))"),
+                String::from("mstore(free, 0x2b)"),
+                String::from("datacopy(add(free, 0x20), dataoffset(\"data_0\"), 64)"),
+                String::from("free := add(free, 96)"),
+                String::from("res := add(res, 0x20)"),
+                String::from("mstore(res, sub(free, 0x80))"),
+                String::from("mstore(free, 0x2b)"),
+                String::from("datacopy(add(free, 0x20), dataoffset(\"data_0\"), 64)"),
+                String::from("free := add(free, 96)"),
+                String::from("res := add(res, 0x20)"),
+                String::from("return(0x80, sub(free, 0x80))"),
+            ])
+        );
+        // The string is stored once, and copied for both columns.
+        assert_eq!(data.into_items().len(), 1);
+        let bytecode = assemble(&yul::derive_object(&plan).unwrap())
+            .unwrap()
+            .bytecode;
+        let result = LocalChain::default()
+            .run(&bytecode, &yul::QUERY_SELECTOR.to_be_bytes())
+            .unwrap();
+        let string = abi::Token::String(String::from(fox));
+        assert_eq!(
+            result,
+            CallResult::Success(abi::encode(&[string.clone(), string]))
+        );
+    }
+
+    #[test]
+    fn derive_yul_object() {
+        let plan = lower(vec![resolution(total_supply_call())], &Settings::default()).unwrap();
+        let yul = yul::derive_yul(&plan).unwrap();
+        assert!(yul.starts_with("object \"Query\" {\n    code {\n        sstore(0, caller())"));
+        assert!(yul.contains("\n    object \"runtime\" {\n        code {\n            switch selector()\n            case 0x2c46b205 {\n                let res := 0x80\n"));
+        assert!(
+            yul.contains("\n            default {\n                revert(0, 0)\n            }\n")
+        );
+        assert!(yul.contains(
+            "\n            function tryCall(gasLimit, addr, ptr, src, len, minSize) -> ok {\n"
+        ));
+        // The calldata is kept in the runtime object's data, after its code.
+        assert!(yul.ends_with("\n        data \"data_0\" hex\"18160ddd\"\n    }\n}"));
+    }
+
+    #[test]
+    fn call_failed_selector() {
+        assert!(
+            print_statements(&yul::helpers().unwrap().statements).contains(&format!(
+                "shl(224, 0x{})",
+                ethers::utils::hex::encode(ethers::utils::id("CallFailed(uint256,bytes)"))
+            ))
+        );
     }
 }
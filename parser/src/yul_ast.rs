@@ -0,0 +1,560 @@
+use crate::query::Span;
+use ethers::types::U256;
+use std::collections::HashSet;
+use std::fmt;
+
+#[rustfmt::skip]
+const KEYWORDS: &[&str] = &[
+    "function", "let", "if", "switch", "case", "default", "for", "break", "continue", "leave",
+    "true", "false", "object", "code", "data",
+];
+
+/// EVM dialect builtins, which can be called but not shadowed.
+#[rustfmt::skip]
+pub const BUILTINS: &[&str] = &[
+    "stop", "add", "sub", "mul", "div", "sdiv", "mod", "smod", "exp", "not", "lt", "gt", "slt",
+    "sgt", "eq", "iszero", "and", "or", "xor", "byte", "shl", "shr", "sar", "addmod", "mulmod",
+    "signextend", "keccak256", "pc", "pop", "mload", "mstore", "mstore8", "sload", "sstore",
+    "tload", "tstore", "msize", "gas", "address", "balance", "selfbalance", "caller",
+    "callvalue", "calldataload", "calldatasize", "calldatacopy", "codesize", "codecopy",
+    "extcodesize", "extcodecopy", "returndatasize", "returndatacopy", "mcopy", "extcodehash",
+    "create", "create2", "call", "callcode", "delegatecall", "staticcall", "return", "revert",
+    "selfdestruct", "invalid", "log0", "log1", "log2", "log3", "log4", "chainid", "basefee",
+    "blobbasefee", "origin", "gasprice", "blockhash", "blobhash", "coinbase", "timestamp",
+    "number", "difficulty", "prevrandao", "gaslimit", "datasize", "dataoffset", "datacopy",
+    "setimmutable", "loadimmutable", "linkersymbol", "memoryguard",
+];
+
+/// A Yul identifier. Only valid identifiers can be constructed, so anything printed in an
+/// identifier's position is an identifier.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Ident(String);
+
+impl Ident {
+    pub fn new(name: &str) -> Result<Ident, String> {
+        let mut chars = name.chars();
+        let valid_start =
+            matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '$');
+        let valid_rest = chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '$' | '.'));
+        if !valid_start || !valid_rest || KEYWORDS.contains(&name) {
+            return Err(format!("Invalid Yul identifier: {:?}", name));
+        }
+        Ok(Ident(String::from(name)))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Ident {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    Decimal(U256),
+    Hex(U256),
+    Bool(bool),
+    /// A string literal's bytes, which are escaped as needed when printed.
+    String(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expression {
+    Literal(Literal),
+    Identifier(Ident),
+    Call(Ident, Vec<Expression>),
+}
+
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Block(pub Vec<Statement>);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub name: Ident,
+    pub params: Vec<Ident>,
+    pub returns: Vec<Ident>,
+    pub body: Block,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Case {
+    pub value: Literal,
+    pub body: Block,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Statement {
+    Block(Block),
+    Function(Function),
+    Let(Vec<Ident>, Option<Expression>),
+    Assign(Vec<Ident>, Expression),
+    If(Expression, Block),
+    Switch(Expression, Vec<Case>, Option<Block>),
+    For(Block, Expression, Block, Block),
+    Break,
+    Continue,
+    Leave,
+    Expression(Expression),
+    /// Marks the statements up to the next marker as generated from this part of the query, or
+    /// from none of it. Printed as a solc `@src` comment.
+    Source(Option<Span>),
+}
+
+/// A Yul object: its code, the objects it deploys and the data its code copies from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Object {
+    pub name: String,
+    pub code: Block,
+    pub objects: Vec<Object>,
+    pub data: Vec<(String, Vec<u8>)>,
+}
+
+/// Panics on an invalid name, so it's only for names fixed in the compiler itself.
+pub fn ident(name: &str) -> Ident {
+    Ident::new(name).unwrap()
+}
+
+pub fn call(name: &str, args: Vec<Expression>) -> Expression {
+    Expression::Call(ident(name), args)
+}
+
+pub fn var(ident: &Ident) -> Expression {
+    Expression::Identifier(ident.clone())
+}
+
+pub fn num<T: Into<U256>>(n: T) -> Expression {
+    Expression::Literal(Literal::Decimal(n.into()))
+}
+
+pub fn hex<T: Into<U256>>(n: T) -> Expression {
+    Expression::Literal(Literal::Hex(n.into()))
+}
+
+pub fn let_(name: &Ident, value: Expression) -> Statement {
+    Statement::Let(vec![name.clone()], Some(value))
+}
+
+pub fn assign(name: &Ident, value: Expression) -> Statement {
+    Statement::Assign(vec![name.clone()], value)
+}
+
+pub fn exec(name: &str, args: Vec<Expression>) -> Statement {
+    Statement::Expression(call(name, args))
+}
+
+/// Hands out identifiers which don't clash with builtins, reserved names or each other.
+pub struct Names {
+    used: HashSet<String>,
+}
+
+impl Names {
+    pub fn new<'a, I: IntoIterator<Item = &'a Ident>>(reserved: I) -> Names {
+        let mut used: HashSet<String> = BUILTINS.iter().map(|b| String::from(*b)).collect();
+        used.extend(reserved.into_iter().map(|r| r.0.clone()));
+        Names { used }
+    }
+
+    /// A fresh identifier based on `hint`, which may be any string.
+    pub fn fresh(&mut self, hint: &str) -> Ident {
+        let mut base: String = hint
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        if !base.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            base.insert(0, '_');
+        }
+        let mut name = base.clone();
+        let mut n = 1;
+        while self.used.contains(&name) || KEYWORDS.contains(&name.as_str()) {
+            name = format!("{}_{}", base, n);
+            n += 1;
+        }
+        self.used.insert(name.clone());
+        Ident(name)
+    }
+}
+
+const INDENT: &str = "    ";
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Literal::Decimal(n) => write!(f, "{}", n),
+            Literal::Hex(n) => write!(f, "{:#x}", n),
+            Literal::Bool(b) => write!(f, "{}", b),
+            Literal::String(bytes) => {
+                f.write_str("\"")?;
+                for b in bytes {
+                    match b {
+                        b'"' => f.write_str("\\\"")?,
+                        b'\\' => f.write_str("\\\\")?,
+                        b'\n' => f.write_str("\\n")?,
+                        b'\r' => f.write_str("\\r")?,
+                        b'\t' => f.write_str("\\t")?,
+                        0x20..=0x7e => write!(f, "{}", *b as char)?,
+                        _ => write!(f, "\\x{:02x}", b)?,
+                    }
+                }
+                f.write_str("\"")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expression::Literal(literal) => write!(f, "{}", literal),
+            Expression::Identifier(ident) => write!(f, "{}", ident),
+            Expression::Call(name, args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+fn join(idents: &[Ident]) -> String {
+    idents
+        .iter()
+        .map(|i| i.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+// Prints a block on one line, as used for the init and post blocks of a `for` loop.
+fn inline(block: &Block) -> String {
+    // Source comments would swallow the rest of the line, so they're dropped.
+    let lines: Vec<String> = block
+        .0
+        .iter()
+        .flat_map(|s| {
+            s.to_string()
+                .lines()
+                .map(|line| String::from(line.trim()))
+                .filter(|line| !line.starts_with("///"))
+                .collect::<Vec<String>>()
+        })
+        .collect();
+    if lines.is_empty() {
+        return String::from("{ }");
+    }
+    format!("{{ {} }}", lines.join(" "))
+}
+
+struct Printer {
+    out: String,
+    depth: usize,
+}
+
+impl Printer {
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.depth {
+            self.out.push_str(INDENT);
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    // Prints `head {`, the block's statements one level deeper, then `}`.
+    fn block(&mut self, head: &str, block: &Block) {
+        if block.0.is_empty() {
+            self.line(&format!("{}{{ }}", head));
+            return;
+        }
+        self.line(&format!("{}{{", head));
+        self.depth += 1;
+        self.statements(&block.0);
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        for (i, statement) in statements.iter().enumerate() {
+            if i > 0 && matches!(statement, Statement::Function(_)) {
+                self.out.push('\n');
+            }
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::Block(block) => self.block("", block),
+            Statement::Function(function) => {
+                let returns = if function.returns.is_empty() {
+                    String::new()
+                } else {
+                    format!(" -> {}", join(&function.returns))
+                };
+                let head = format!(
+                    "function {}({}){} ",
+                    function.name,
+                    join(&function.params),
+                    returns
+                );
+                self.block(&head, &function.body);
+            }
+            Statement::Let(names, None) => self.line(&format!("let {}", join(names))),
+            Statement::Let(names, Some(value)) => {
+                self.line(&format!("let {} := {}", join(names), value))
+            }
+            Statement::Assign(names, value) => self.line(&format!("{} := {}", join(names), value)),
+            Statement::If(condition, body) => self.block(&format!("if {} ", condition), body),
+            Statement::Switch(value, cases, default) => {
+                self.line(&format!("switch {}", value));
+                for case in cases {
+                    self.block(&format!("case {} ", case.value), &case.body);
+                }
+                if let Some(default) = default {
+                    self.block("default ", default);
+                }
+            }
+            Statement::For(init, condition, post, body) => {
+                let head = format!("for {} {} {} ", inline(init), condition, inline(post));
+                self.block(&head, body);
+            }
+            Statement::Break => self.line("break"),
+            Statement::Continue => self.line("continue"),
+            Statement::Leave => self.line("leave"),
+            Statement::Expression(expression) => self.line(&expression.to_string()),
+            Statement::Source(Some(span)) => {
+                self.line(&format!("/// @src 0:{}:{}", span.start, span.end))
+            }
+            Statement::Source(None) => self.line("/// @src -1:-1:-1"),
+        }
+    }
+
+    fn object(&mut self, object: &Object) {
+        self.line(&format!(
+            "object {} {{",
+            Literal::String(object.name.as_bytes().to_vec())
+        ));
+        self.depth += 1;
+        self.block("code ", &object.code);
+        for inner in &object.objects {
+            self.object(inner);
+        }
+        for (name, bytes) in &object.data {
+            self.line(&format!(
+                "data {} hex\"{}\"",
+                Literal::String(name.as_bytes().to_vec()),
+                ethers::utils::hex::encode(bytes)
+            ));
+        }
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn finish(mut self) -> String {
+        if self.out.ends_with('\n') {
+            self.out.pop();
+        }
+        self.out
+    }
+}
+
+/// Prints statements one per line, with nested blocks indented by four spaces.
+pub fn print_statements(statements: &[Statement]) -> String {
+    let mut printer = Printer {
+        out: String::new(),
+        depth: 0,
+    };
+    printer.statements(statements);
+    printer.finish()
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&print_statements(std::slice::from_ref(self)))
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut printer = Printer {
+            out: String::new(),
+            depth: 0,
+        };
+        printer.object(self);
+        f.write_str(&printer.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::yul_ast::*;
+
+    #[test]
+    fn identifiers() {
+        assert!(Ident::new("tryCall").is_ok());
+        assert!(Ident::new("$x.y_1").is_ok());
+        assert_eq!(
+            Ident::new("let"),
+            Err(String::from("Invalid Yul identifier: \"let\""))
+        );
+        assert!(Ident::new("1x").is_err());
+        assert!(Ident::new("x)}").is_err());
+        assert!(Ident::new("").is_err());
+    }
+
+    #[test]
+    fn fresh_names() {
+        let mut names = Names::new(&[ident("tryCall")]);
+        assert_eq!(names.fresh("res"), ident("res"));
+        assert_eq!(names.fresh("res"), ident("res_1"));
+        assert_eq!(names.fresh("gas"), ident("gas_1"));
+        assert_eq!(names.fresh("tryCall"), ident("tryCall_1"));
+        assert_eq!(names.fresh("let"), ident("let_1"));
+        assert_eq!(
+            names.fresh("comet.total supply"),
+            ident("comet_total_supply")
+        );
+        assert_eq!(names.fresh("0x}"), ident("_0x_"));
+    }
+
+    #[test]
+    fn print_literals() {
+        assert_eq!(num(32).to_string(), "32");
+        assert_eq!(hex(32).to_string(), "0x20");
+        assert_eq!(hex(0).to_string(), "0x0");
+        assert_eq!(
+            Literal::String(b"a\"b\\c\n}\x7f".to_vec()).to_string(),
+            "\"a\\\"b\\\\c\\n}\\x7f\""
+        );
+        assert_eq!(
+            call(
+                "mstore",
+                vec![call("add", vec![var(&ident("res")), num(0)]), hex(0x20)]
+            )
+            .to_string(),
+            "mstore(add(res, 0), 0x20)"
+        );
+    }
+
+    #[test]
+    fn print_statements_indented() {
+        let i = ident("i");
+        let statements = vec![
+            let_(&i, num(0)),
+            Statement::Switch(
+                var(&i),
+                vec![Case {
+                    value: Literal::Decimal(0.into()),
+                    body: Block(vec![Statement::If(
+                        call("iszero", vec![var(&i)]),
+                        Block(vec![Statement::Leave]),
+                    )]),
+                }],
+                Some(Block::default()),
+            ),
+            Statement::For(
+                Block(vec![let_(&ident("j"), num(0))]),
+                call("lt", vec![var(&ident("j")), num(2)]),
+                Block(vec![assign(
+                    &ident("j"),
+                    call("add", vec![var(&ident("j")), num(1)]),
+                )]),
+                Block(vec![exec("pop", vec![var(&ident("j"))])]),
+            ),
+            Statement::Function(Function {
+                name: ident("f"),
+                params: vec![ident("a"), ident("b")],
+                returns: vec![ident("c")],
+                body: Block(vec![assign(&ident("c"), var(&ident("a")))]),
+            }),
+        ];
+        assert_eq!(
+            print_statements(&statements),
+            [
+                "let i := 0",
+                "switch i",
+                "case 0 {",
+                "    if iszero(i) {",
+                "        leave",
+                "    }",
+                "}",
+                "default { }",
+                "for { let j := 0 } lt(j, 2) { j := add(j, 1) } {",
+                "    pop(j)",
+                "}",
+                "",
+                "function f(a, b) -> c {",
+                "    c := a",
+                "}",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn print_object() {
+        let object = Object {
+            name: String::from("Query"),
+            code: Block(vec![exec("stop", vec![])]),
+            objects: vec![Object {
+                name: String::from("run\"time"),
+                code: Block::default(),
+                objects: vec![],
+                data: vec![(String::from("data_0"), vec![0x18, 0x16, 0x0d, 0xdd])],
+            }],
+            data: vec![],
+        };
+        assert_eq!(
+            object.to_string(),
+            [
+                "object \"Query\" {",
+                "    code {",
+                "        stop()",
+                "    }",
+                "    object \"run\\\"time\" {",
+                "        code { }",
+                "        data \"data_0\" hex\"18160ddd\"",
+                "    }",
+                "}",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn print_source_locations() {
+        let statements = vec![
+            Statement::Source(Some(Span { start: 7, end: 24 })),
+            exec("pop", vec![num(1)]),
+            Statement::Source(None),
+            Statement::For(
+                Block(vec![Statement::Source(None)]),
+                num(0),
+                Block::default(),
+                Block::default(),
+            ),
+        ];
+        assert_eq!(
+            print_statements(&statements),
+            [
+                "/// @src 0:7:24",
+                "pop(1)",
+                "/// @src -1:-1:-1",
+                "for { } 0 { } { }",
+            ]
+            .join("\n")
+        );
+    }
+}
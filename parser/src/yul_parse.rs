@@ -0,0 +1,286 @@
+use crate::yul_ast::{Block, Case, Expression, Function, Ident, Literal, Statement};
+use ethers::types::U256;
+use pest::iterators::Pair;
+use pest::Parser;
+
+#[derive(Parser)]
+#[grammar = "yul.pest"]
+struct YulParser;
+
+// A pair's children, without the keywords which introduce statements.
+fn inner(pair: Pair<Rule>) -> impl Iterator<Item = Pair<Rule>> {
+    pair.into_inner().filter(|pair| {
+        !matches!(
+            pair.as_rule(),
+            Rule::function_kw
+                | Rule::let_kw
+                | Rule::if_kw
+                | Rule::switch_kw
+                | Rule::case_kw
+                | Rule::default_kw
+                | Rule::for_kw
+        )
+    })
+}
+
+fn parse_identifier(pair: Pair<Rule>) -> Result<Ident, String> {
+    Ident::new(pair.as_str())
+}
+
+fn parse_identifiers(pair: Pair<Rule>) -> Result<Vec<Ident>, String> {
+    pair.into_inner().map(parse_identifier).collect()
+}
+
+fn parse_string(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some(c @ ('"' | '\'' | '\\')) => bytes.push(c as u8),
+            Some('x') => {
+                let digits: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&digits, 16)
+                    .map_err(|_| format!("Invalid escape in Yul string: \\x{}", digits))?;
+                bytes.push(byte);
+            }
+            c => {
+                return Err(format!(
+                    "Invalid escape in Yul string: \\{}",
+                    c.unwrap_or(' ')
+                ))
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+fn parse_literal(pair: Pair<Rule>) -> Result<Literal, String> {
+    let pair = pair.into_inner().next().unwrap();
+    match pair.as_rule() {
+        Rule::yul_hex_number => U256::from_str_radix(&pair.as_str()[2..], 16)
+            .map(Literal::Hex)
+            .map_err(|e| format!("Invalid Yul number {}: {}", pair.as_str(), e)),
+        Rule::yul_decimal_number => U256::from_dec_str(pair.as_str())
+            .map(Literal::Decimal)
+            .map_err(|e| format!("Invalid Yul number {}: {:?}", pair.as_str(), e)),
+        Rule::yul_bool => Ok(Literal::Bool(pair.as_str() == "true")),
+        Rule::yul_string => Ok(Literal::String(parse_string(
+            pair.into_inner().next().unwrap().as_str(),
+        )?)),
+        r => Err(format!("parse_literal::unmatched: {:?}", r)),
+    }
+}
+
+fn parse_expression(pair: Pair<Rule>) -> Result<Expression, String> {
+    let pair = pair.into_inner().next().unwrap();
+    match pair.as_rule() {
+        Rule::yul_call => {
+            let mut pairs = pair.into_inner();
+            let name = parse_identifier(pairs.next().unwrap())?;
+            let args = pairs.map(parse_expression).collect::<Result<_, _>>()?;
+            Ok(Expression::Call(name, args))
+        }
+        Rule::yul_literal => Ok(Expression::Literal(parse_literal(pair)?)),
+        Rule::yul_identifier => Ok(Expression::Identifier(parse_identifier(pair)?)),
+        r => Err(format!("parse_expression::unmatched: {:?}", r)),
+    }
+}
+
+fn parse_block(pair: Pair<Rule>) -> Result<Block, String> {
+    Ok(Block(
+        pair.into_inner()
+            .map(parse_statement)
+            .collect::<Result<_, _>>()?,
+    ))
+}
+
+fn parse_function(pair: Pair<Rule>) -> Result<Function, String> {
+    let mut function = Function {
+        name: Ident::new("_")?,
+        params: vec![],
+        returns: vec![],
+        body: Block::default(),
+    };
+    for pair in inner(pair) {
+        match pair.as_rule() {
+            Rule::yul_identifier => function.name = parse_identifier(pair)?,
+            Rule::yul_identifiers => function.params = parse_identifiers(pair)?,
+            Rule::yul_returns => function.returns = parse_identifiers(inner(pair).next().unwrap())?,
+            Rule::yul_block => function.body = parse_block(pair)?,
+            r => return Err(format!("parse_function::unmatched: {:?}", r)),
+        }
+    }
+    Ok(function)
+}
+
+fn parse_switch(pair: Pair<Rule>) -> Result<Statement, String> {
+    let mut pairs = inner(pair);
+    let value = parse_expression(pairs.next().unwrap())?;
+    let mut cases = vec![];
+    let mut default = None;
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::yul_case => {
+                let mut case = inner(pair);
+                cases.push(Case {
+                    value: parse_literal(case.next().unwrap())?,
+                    body: parse_block(case.next().unwrap())?,
+                });
+            }
+            Rule::yul_default => default = Some(parse_block(inner(pair).next().unwrap())?),
+            r => return Err(format!("parse_switch::unmatched: {:?}", r)),
+        }
+    }
+    Ok(Statement::Switch(value, cases, default))
+}
+
+fn parse_statement(pair: Pair<Rule>) -> Result<Statement, String> {
+    match pair.as_rule() {
+        Rule::yul_block => Ok(Statement::Block(parse_block(pair)?)),
+        Rule::yul_function => Ok(Statement::Function(parse_function(pair)?)),
+        Rule::yul_let => {
+            let mut pairs = inner(pair);
+            let names = parse_identifiers(pairs.next().unwrap())?;
+            let value = pairs.next().map(parse_expression).transpose()?;
+            Ok(Statement::Let(names, value))
+        }
+        Rule::yul_assignment => {
+            let mut pairs = inner(pair);
+            let names = parse_identifiers(pairs.next().unwrap())?;
+            Ok(Statement::Assign(
+                names,
+                parse_expression(pairs.next().unwrap())?,
+            ))
+        }
+        Rule::yul_if => {
+            let mut pairs = inner(pair);
+            let condition = parse_expression(pairs.next().unwrap())?;
+            Ok(Statement::If(
+                condition,
+                parse_block(pairs.next().unwrap())?,
+            ))
+        }
+        Rule::yul_switch => parse_switch(pair),
+        Rule::yul_for => {
+            let mut pairs = inner(pair);
+            let init = parse_block(pairs.next().unwrap())?;
+            let condition = parse_expression(pairs.next().unwrap())?;
+            let post = parse_block(pairs.next().unwrap())?;
+            let body = parse_block(pairs.next().unwrap())?;
+            Ok(Statement::For(init, condition, post, body))
+        }
+        Rule::yul_break => Ok(Statement::Break),
+        Rule::yul_continue => Ok(Statement::Continue),
+        Rule::yul_leave => Ok(Statement::Leave),
+        Rule::yul_expression => Ok(Statement::Expression(parse_expression(pair)?)),
+        r => Err(format!("parse_statement::unmatched: {:?}", r)),
+    }
+}
+
+/// Parses a sequence of Yul statements, e.g. the helper functions a query calls.
+pub fn parse_yul(code: &str) -> Result<Vec<Statement>, String> {
+    let pairs = YulParser::parse(Rule::yul, code).map_err(|e| format!("{}", e))?;
+    pairs
+        .into_iter()
+        .next()
+        .unwrap()
+        .into_inner()
+        .filter(|pair| pair.as_rule() != Rule::EOI)
+        .map(parse_statement)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::yul_ast::*;
+    use crate::yul_parse::parse_yul;
+
+    #[test]
+    fn parse_statements() {
+        let statements = parse_yul(
+            r#"
+            // A comment
+            function f(a, b) -> c {
+                c := add(a, 0x20) /* another */
+                if iszero(c) { leave }
+            }
+            let x, y := f(1, "a\"\x41")
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            vec![
+                Statement::Function(Function {
+                    name: ident("f"),
+                    params: vec![ident("a"), ident("b")],
+                    returns: vec![ident("c")],
+                    body: Block(vec![
+                        assign(&ident("c"), call("add", vec![var(&ident("a")), hex(0x20)])),
+                        Statement::If(
+                            call("iszero", vec![var(&ident("c"))]),
+                            Block(vec![Statement::Leave])
+                        ),
+                    ]),
+                }),
+                Statement::Let(
+                    vec![ident("x"), ident("y")],
+                    Some(call(
+                        "f",
+                        vec![
+                            num(1),
+                            Expression::Literal(Literal::String(b"a\"A".to_vec()))
+                        ]
+                    ))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_keywords_and_identifiers() {
+        let statements = parse_yul("let iffy := leaver for { } true { } { break }").unwrap();
+        assert_eq!(
+            statements,
+            vec![
+                let_(&ident("iffy"), var(&ident("leaver"))),
+                Statement::For(
+                    Block::default(),
+                    Expression::Literal(Literal::Bool(true)),
+                    Block::default(),
+                    Block(vec![Statement::Break]),
+                ),
+            ]
+        );
+        assert!(parse_yul("let let := 1").is_err());
+        assert!(parse_yul("f(").is_err());
+    }
+
+    #[test]
+    fn print_round_trip() {
+        let code = [
+            "function g(x) -> y {",
+            "    switch x",
+            "    case 0x01 {",
+            "        y := \"\\x00\\\\\"",
+            "    }",
+            "    default {",
+            "        for { let i := 0 } lt(i, x) { i := add(i, 1) } { }",
+            "    }",
+            "}",
+        ]
+        .join("\n");
+        let statements = parse_yul(&code).unwrap();
+        let printed = print_statements(&statements);
+        assert_eq!(printed, code.replace("0x01", "0x1"));
+        assert_eq!(parse_yul(&printed).unwrap(), statements);
+    }
+}
@@ -0,0 +1,74 @@
+//! Runs the `sleuth` binary on query files.
+
+#![cfg(not(target_arch = "wasm32"))]
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn write(name: &str, query: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sleuth-cli-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::write(&path, query).unwrap();
+    path
+}
+
+fn sleuth(args: &[&str], files: &[&PathBuf]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_sleuth"))
+        .args(args)
+        .args(files)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn check_files() {
+    let good = write("good.sleuth", "SELECT block.number FROM block;\n");
+    let bad = write("bad.sleuth", "SELECT comet.totalSupply FROM comet");
+
+    assert!(sleuth(&["check"], &[&good]).status.success());
+    let output = sleuth(&["check"], &[&good, &bad]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("bad.sleuth: No such relation \"comet\" referenced in FROM clause"));
+    assert!(stderr.contains("1 of 2 file(s) failed"));
+}
+
+#[test]
+fn format_files() {
+    let formatted = write("formatted.sleuth", "SELECT block.number FROM block;\n");
+    let unformatted = write("unformatted.sleuth", "select block.number from block");
+
+    assert!(sleuth(&["fmt", "--check"], &[&formatted]).status.success());
+    assert_eq!(
+        sleuth(&["fmt", "--check"], &[&unformatted]).status.code(),
+        Some(1)
+    );
+    let output = sleuth(&["fmt"], &[&unformatted]);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "SELECT block.number FROM block;\n"
+    );
+}
+
+#[test]
+fn compile_and_explain_files() {
+    let query = write("query.sleuth", "SELECT block.number FROM block");
+    let output = sleuth(&["compile"], &[&query]);
+    let compiled: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(compiled["tuple"], "tuple(uint256 number)");
+    assert!(compiled["bytecode"].as_str().unwrap().starts_with("0x"));
+
+    let output = sleuth(&["explain"], &[&query]);
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("query() [0x2c46b205] returns tuple(uint256 number)\n"));
+}
+
+#[test]
+fn usage() {
+    assert_eq!(sleuth(&[], &[]).status.code(), Some(2));
+    assert_eq!(sleuth(&["bogus"], &[]).status.code(), Some(2));
+    assert_eq!(sleuth(&["check", "--fix"], &[]).status.code(), Some(2));
+}